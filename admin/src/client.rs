@@ -0,0 +1,42 @@
+use engine::streams::{self, ADMIN_STREAM};
+use redis::Client;
+use std::error::Error;
+use std::time::Duration;
+
+use engine::types::{AdminCommand, MessageToApi};
+
+pub type ClientError = Box<dyn Error + Send + Sync>;
+
+pub struct AdminClient {
+    client: Client,
+    timeout: Duration,
+}
+
+impl AdminClient {
+    pub fn new(redis_url: &str, timeout: Duration) -> Result<Self, ClientError> {
+        Ok(Self {
+            client: Client::open(redis_url)?,
+            timeout,
+        })
+    }
+
+    pub async fn send(&self, command: AdminCommand) -> Result<MessageToApi, ClientError> {
+        let client_id = get_random_client_id();
+        let payload = serde_json::to_string(&(client_id.clone(), command))?;
+        // The API only ever writes to `messages`, so access to the admin stream is
+        // what makes a client an admin
+        let mut connection = self.client.get_async_connection().await?;
+        streams::push(&mut connection, ADMIN_STREAM, &payload).await?;
+
+        let response = streams::take_reply(&mut connection, &client_id, self.timeout)
+            .await?
+            .ok_or("Timed out waiting for the engine")?;
+        Ok(serde_json::from_str(&response)?)
+    }
+}
+
+fn get_random_client_id() -> String {
+    use rand::Rng;
+    let mut rng = rand::thread_rng();
+    format!("admin-{:x}{:x}", rng.gen::<u64>(), rng.gen::<u64>())
+}
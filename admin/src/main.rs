@@ -0,0 +1,381 @@
+mod client;
+
+use clap::{Parser, Subcommand, ValueEnum};
+use client::AdminClient;
+use engine::types::{
+    AdjustBalancePayload, AdminCommand, CancelOrderPayload, CircuitBreakerConfig, GetBookPayload,
+    GetUserPayload, MarginConfig, MarginEnabledPayload, MarketStatus, MessageToApi, Order,
+    PriceProtectionPayload, RiskLimits, RiskLimitsPayload, SetMarketStatusPayload,
+};
+use rust_decimal::Decimal;
+use std::process::ExitCode;
+use std::time::Duration;
+
+/// Inspects and operates the matching engine over its privileged admin queue.
+#[derive(Parser)]
+#[command(name = "exchange-admin")]
+struct Args {
+    #[arg(long, env = "REDIS_URL", default_value = "redis://127.0.0.1:6379")]
+    redis_url: String,
+    /// How long to wait for the engine to answer, in milliseconds
+    #[arg(long, default_value_t = 5000)]
+    timeout_ms: u64,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// List every market with its status
+    Markets,
+    /// Show a user's balances and open orders
+    User { user_id: String },
+    /// Show the resting orders at the top of a book
+    Book {
+        market: String,
+        /// Price levels per side
+        #[arg(long, default_value_t = 5)]
+        levels: usize,
+    },
+    /// Cancel an order regardless of the market's status
+    Cancel { market: String, order_id: String },
+    /// Add to a user's available balance
+    Credit {
+        user_id: String,
+        asset: String,
+        amount: String,
+        #[arg(long)]
+        reason: String,
+    },
+    /// Take from a user's available balance
+    Debit {
+        user_id: String,
+        asset: String,
+        amount: String,
+        #[arg(long)]
+        reason: String,
+    },
+    /// Stop all order entry and cancels on a market
+    Halt { market: String },
+    /// Return a market to continuous trading
+    Resume { market: String },
+    /// Put a market into any status; delisting cancels every resting order and is final
+    Status { market: String, status: Status },
+    /// Replace a market's price band and circuit breaker; options left out are disabled
+    Protect {
+        market: String,
+        /// Widest distance of a limit price from the reference price, in basis points
+        #[arg(long)]
+        band_bps: Option<u64>,
+        /// Fixed reference price for the band instead of the last trade
+        #[arg(long, requires = "band_bps")]
+        reference_price: Option<u64>,
+        /// Largest move within the window before the market halts, in basis points
+        #[arg(long, requires_all = ["window_secs", "cooldown_secs"])]
+        max_move_bps: Option<u64>,
+        #[arg(long, requires = "max_move_bps")]
+        window_secs: Option<u64>,
+        #[arg(long, requires = "max_move_bps")]
+        cooldown_secs: Option<u64>,
+    },
+    /// Replace a user's pre-trade risk limits; limits left out are lifted
+    RiskLimits {
+        user_id: String,
+        /// Resting orders per market
+        #[arg(long)]
+        max_open_orders: Option<u64>,
+        /// Largest notional of a single order, in the quote asset
+        #[arg(long)]
+        max_order_notional: Option<Decimal>,
+        /// Largest notional across all resting orders, in the quote asset
+        #[arg(long)]
+        max_open_notional: Option<Decimal>,
+        #[arg(long)]
+        max_orders_per_second: Option<u64>,
+    },
+    /// Replace the exchange-wide margin settings; a leverage of 1 disables borrowing
+    MarginConfig {
+        /// Largest ratio of account assets to equity reachable by borrowing
+        #[arg(long)]
+        max_leverage: Decimal,
+        /// Accounts whose assets fall below this multiple of their debt are liquidated
+        #[arg(long)]
+        liquidation_margin_level: Decimal,
+        /// Charged on borrowed amounts, as a fraction of the debt per hour
+        #[arg(long)]
+        hourly_interest_rate: Decimal,
+    },
+    /// Let a user borrow on margin
+    EnableMargin { user_id: String },
+    /// Stop a user from borrowing; accounts that still owe stay enabled
+    DisableMargin { user_id: String },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Status {
+    Trading,
+    Auction,
+    Halted,
+    CancelOnly,
+    PostOnly,
+    Delisted,
+}
+
+impl From<Status> for MarketStatus {
+    fn from(status: Status) -> Self {
+        match status {
+            Status::Trading => MarketStatus::Trading,
+            Status::Auction => MarketStatus::Auction,
+            Status::Halted => MarketStatus::Halted,
+            Status::CancelOnly => MarketStatus::CancelOnly,
+            Status::PostOnly => MarketStatus::PostOnly,
+            Status::Delisted => MarketStatus::Delisted,
+        }
+    }
+}
+
+impl Command {
+    fn into_admin_command(self) -> AdminCommand {
+        match self {
+            Command::Markets => AdminCommand::ListMarkets,
+            Command::User { user_id } => AdminCommand::GetUser(GetUserPayload { user_id }),
+            Command::Book { market, levels } => {
+                AdminCommand::GetBook(GetBookPayload { market, levels })
+            }
+            Command::Cancel { market, order_id } => {
+                AdminCommand::ForceCancel(CancelOrderPayload { order_id, market })
+            }
+            Command::Credit {
+                user_id,
+                asset,
+                amount,
+                reason,
+            } => AdminCommand::AdjustBalance(AdjustBalancePayload {
+                user_id,
+                asset,
+                amount,
+                reason,
+            }),
+            Command::Debit {
+                user_id,
+                asset,
+                amount,
+                reason,
+            } => AdminCommand::AdjustBalance(AdjustBalancePayload {
+                user_id,
+                asset,
+                amount: format!("-{}", amount.trim_start_matches('-')),
+                reason,
+            }),
+            Command::Halt { market } => AdminCommand::SetMarketStatus(SetMarketStatusPayload {
+                market,
+                status: MarketStatus::Halted,
+            }),
+            Command::Resume { market } => AdminCommand::SetMarketStatus(SetMarketStatusPayload {
+                market,
+                status: MarketStatus::Trading,
+            }),
+            Command::Status { market, status } => {
+                AdminCommand::SetMarketStatus(SetMarketStatusPayload {
+                    market,
+                    status: status.into(),
+                })
+            }
+            Command::Protect {
+                market,
+                band_bps,
+                reference_price,
+                max_move_bps,
+                window_secs,
+                cooldown_secs,
+            } => AdminCommand::SetPriceProtection(PriceProtectionPayload {
+                market,
+                band_bps,
+                reference_price,
+                circuit_breaker: max_move_bps.map(|max_move_bps| CircuitBreakerConfig {
+                    max_move_bps,
+                    window_secs: window_secs.unwrap_or_default(),
+                    cooldown_secs: cooldown_secs.unwrap_or_default(),
+                }),
+            }),
+            Command::RiskLimits {
+                user_id,
+                max_open_orders,
+                max_order_notional,
+                max_open_notional,
+                max_orders_per_second,
+            } => AdminCommand::SetRiskLimits(RiskLimitsPayload {
+                user_id,
+                limits: RiskLimits {
+                    max_open_orders,
+                    max_order_notional,
+                    max_open_notional,
+                    max_orders_per_second,
+                },
+            }),
+            Command::MarginConfig {
+                max_leverage,
+                liquidation_margin_level,
+                hourly_interest_rate,
+            } => AdminCommand::SetMarginConfig(MarginConfig {
+                max_leverage,
+                liquidation_margin_level,
+                hourly_interest_rate,
+            }),
+            Command::EnableMargin { user_id } => {
+                AdminCommand::SetMarginEnabled(MarginEnabledPayload {
+                    user_id,
+                    enabled: true,
+                })
+            }
+            Command::DisableMargin { user_id } => {
+                AdminCommand::SetMarginEnabled(MarginEnabledPayload {
+                    user_id,
+                    enabled: false,
+                })
+            }
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    dotenv::dotenv().ok();
+    let args = Args::parse();
+
+    let client = match AdminClient::new(&args.redis_url, Duration::from_millis(args.timeout_ms)) {
+        Ok(client) => client,
+        Err(e) => {
+            eprintln!("Failed to connect to Redis: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    match client.send(args.command.into_admin_command()).await {
+        Ok(reply) => print_reply(reply),
+        Err(e) => {
+            eprintln!("Request failed: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn print_reply(reply: MessageToApi) -> ExitCode {
+    match reply {
+        MessageToApi::Markets(markets) => {
+            println!("{:<16} {:<10} {:<10} STATUS", "MARKET", "KIND", "QUOTE");
+            for market in markets {
+                println!(
+                    "{:<16} {:<10} {:<10} {:?}",
+                    market.market,
+                    format!("{:?}", market.kind),
+                    market.quote_asset,
+                    market.status
+                );
+            }
+        }
+        MessageToApi::UserState(state) => {
+            println!("User {}", state.user_id);
+            println!(
+                "{:<8} {:>16} {:>16} {:>16} {:>16}",
+                "ASSET", "AVAILABLE", "LOCKED", "BORROWED", "INTEREST"
+            );
+            for balance in state.balances {
+                println!(
+                    "{:<8} {:>16} {:>16} {:>16} {:>16}",
+                    balance.asset,
+                    balance.available,
+                    balance.locked,
+                    balance.borrowed,
+                    balance.interest
+                );
+            }
+            for market_orders in state.open_orders {
+                println!();
+                println!("Open orders on {}", market_orders.market);
+                print_orders(&market_orders.orders);
+            }
+        }
+        MessageToApi::BookTop(book) => {
+            println!(
+                "{} {:?}, last price {}",
+                book.market, book.status, book.last_price
+            );
+            println!();
+            println!("Asks");
+            print_orders(&book.asks.into_iter().rev().collect::<Vec<_>>());
+            println!();
+            println!("Bids");
+            print_orders(&book.bids);
+        }
+        MessageToApi::OrderCancelled(cancelled) => println!(
+            "Cancelled {} with {} executed and {} remaining",
+            cancelled.order_id, cancelled.executed_qty, cancelled.remaining_qty
+        ),
+        MessageToApi::BalanceAdjusted(adjustment) => println!(
+            "Adjusted {} {} for {} ({}), adjustment id {}",
+            adjustment.amount,
+            adjustment.asset,
+            adjustment.user_id,
+            adjustment.reason,
+            adjustment.id
+        ),
+        MessageToApi::MarketStatus(status) => {
+            println!("{} is now {:?}", status.market, status.status)
+        }
+        MessageToApi::PriceProtection(protection) => {
+            println!(
+                "{} band {:?} bps around {:?}, circuit breaker {:?}",
+                protection.market,
+                protection.band_bps,
+                protection.reference_price,
+                protection.circuit_breaker
+            )
+        }
+        MessageToApi::RiskLimits(risk_limits) => {
+            println!("Risk limits for {}", risk_limits.user_id);
+            println!("{:#?}", risk_limits.limits)
+        }
+        MessageToApi::MarginConfig(config) => println!(
+            "Margin: max leverage {}, liquidation at margin level {}, hourly interest {}",
+            config.max_leverage, config.liquidation_margin_level, config.hourly_interest_rate
+        ),
+        MessageToApi::MarginAccount(account) => println!(
+            "Margin for {} is {}, assets {} against liabilities {}",
+            account.user_id,
+            if account.enabled {
+                "enabled"
+            } else {
+                "disabled"
+            },
+            account.assets_value,
+            account.liabilities_value
+        ),
+        MessageToApi::AdminRejected(rejected) => {
+            eprintln!("Rejected: {:?}", rejected.reason);
+            return ExitCode::FAILURE;
+        }
+        other => {
+            eprintln!("Unexpected reply: {:?}", other);
+            return ExitCode::FAILURE;
+        }
+    }
+    ExitCode::SUCCESS
+}
+
+fn print_orders(orders: &[Order]) {
+    println!(
+        "{:<24} {:<12} {:<5} {:>12} {:>12} {:>12}",
+        "ORDER", "USER", "SIDE", "PRICE", "QUANTITY", "FILLED"
+    );
+    for order in orders {
+        println!(
+            "{:<24} {:<12} {:<5} {:>12} {:>12} {:>12}",
+            order.order_id,
+            order.user_id,
+            format!("{:?}", order.side),
+            order.price,
+            order.quantity,
+            order.filled
+        );
+    }
+}
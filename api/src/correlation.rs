@@ -0,0 +1,75 @@
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::{HeaderName, HeaderValue},
+    middleware::Next,
+    Error,
+};
+use std::time::Instant;
+use tracing::{info, info_span, Instrument};
+
+const CORRELATION_ID_HEADER: &str = "x-request-id";
+const MAX_CORRELATION_ID_LEN: usize = 64;
+
+tokio::task_local! {
+    static CORRELATION_ID: String;
+}
+
+// Correlation id of the HTTP request being handled on this task
+pub fn current() -> Option<String> {
+    CORRELATION_ID.try_with(Clone::clone).ok()
+}
+
+// Reuses the caller's X-Request-Id when it looks like an id and makes one up
+// otherwise. The id is echoed back on the response and sent to the engine with
+// every message the request causes.
+pub async fn trace_request(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let correlation_id = req
+        .headers()
+        .get(CORRELATION_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| is_valid(id))
+        .map(str::to_string)
+        .unwrap_or_else(new_correlation_id);
+    let span = info_span!(
+        "http_request",
+        %correlation_id,
+        method = %req.method(),
+        path = %req.path()
+    );
+    let started = Instant::now();
+
+    let mut response = CORRELATION_ID
+        .scope(correlation_id.clone(), next.call(req))
+        .instrument(span.clone())
+        .await?;
+    span.in_scope(|| {
+        info!(
+            status = response.status().as_u16(),
+            elapsed_ms = started.elapsed().as_secs_f64() * 1000.0,
+            "Request handled"
+        )
+    });
+    if let Ok(value) = HeaderValue::from_str(&correlation_id) {
+        response
+            .headers_mut()
+            .insert(HeaderName::from_static(CORRELATION_ID_HEADER), value);
+    }
+    Ok(response)
+}
+
+fn is_valid(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_CORRELATION_ID_LEN
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+fn new_correlation_id() -> String {
+    use rand::Rng;
+    format!("{:016x}", rand::thread_rng().gen::<u64>())
+}
@@ -0,0 +1,36 @@
+use actix_web::{web, HttpResponse, Responder};
+
+use crate::{
+    redis_manager::RedisManager,
+    types::{GetSubAccountsRequest, MessageToEngine, SubAccountRequest, TransferRequest},
+};
+
+pub async fn create_sub_account(data: web::Json<SubAccountRequest>) -> impl Responder {
+    let message_to_engine = MessageToEngine::CreateSubAccount(data.into_inner());
+
+    let redis_manager = RedisManager::get_instance().await;
+    match redis_manager.send_and_await(message_to_engine).await {
+        Ok(response) => HttpResponse::Ok().json(response),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error: {}", e)),
+    }
+}
+
+pub async fn get_sub_accounts(data: web::Query<GetSubAccountsRequest>) -> impl Responder {
+    let message_to_engine = MessageToEngine::GetSubAccounts(data.into_inner());
+
+    let redis_manager = RedisManager::get_instance().await;
+    match redis_manager.send_and_await(message_to_engine).await {
+        Ok(response) => HttpResponse::Ok().json(response),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error: {}", e)),
+    }
+}
+
+pub async fn transfer(data: web::Json<TransferRequest>) -> impl Responder {
+    let message_to_engine = MessageToEngine::Transfer(data.into_inner());
+
+    let redis_manager = RedisManager::get_instance().await;
+    match redis_manager.send_and_await(message_to_engine).await {
+        Ok(response) => HttpResponse::Ok().json(response),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error: {}", e)),
+    }
+}
@@ -2,12 +2,28 @@ use actix_web::{web, HttpResponse, Responder};
 
 use crate::{
     redis_manager::RedisManager,
-    types::{GetDepthRequest, MessageToEngine},
+    types::{DepthQuery, GetDepthRequest, GetL3SnapshotRequest, L3Query, MessageToEngine},
 };
 
-pub async fn get_depth(symbol: web::Query<String>) -> impl Responder {
-    let symbol = symbol.into_inner();
-    let message_to_engine = MessageToEngine::GetDepth(GetDepthRequest { market: symbol });
+pub async fn get_depth(query: web::Query<DepthQuery>) -> impl Responder {
+    let query = query.into_inner();
+    let message_to_engine = MessageToEngine::GetDepth(GetDepthRequest {
+        market: query.symbol,
+        limit: query.limit,
+        precision: query.precision,
+    });
+    let redis_manager = RedisManager::get_instance().await;
+
+    match redis_manager.send_and_await(message_to_engine).await {
+        Ok(response) => HttpResponse::Ok().json(response),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error: {}", e)),
+    }
+}
+
+pub async fn get_l3_snapshot(query: web::Query<L3Query>) -> impl Responder {
+    let message_to_engine = MessageToEngine::GetL3Snapshot(GetL3SnapshotRequest {
+        market: query.into_inner().symbol,
+    });
     let redis_manager = RedisManager::get_instance().await;
 
     match redis_manager.send_and_await(message_to_engine).await {
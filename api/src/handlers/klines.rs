@@ -2,6 +2,7 @@ use crate::types::{Kline, KlinesQuery};
 use actix_web::{web, HttpResponse, Responder};
 use chrono::{DateTime, Utc};
 use sqlx::{PgPool, Row};
+use tracing::error;
 
 pub async fn get_klines(data: web::Query<KlinesQuery>, pool: web::Data<PgPool>) -> impl Responder {
     let query = data.into_inner();
@@ -9,7 +10,7 @@ pub async fn get_klines(data: web::Query<KlinesQuery>, pool: web::Data<PgPool>)
     match get_klines_from_db(&query, &pool).await {
         Ok(klines) => HttpResponse::Ok().json(klines),
         Err(e) => {
-            eprintln!("Failed to get klines: {}", e);
+            error!("Failed to get klines: {}", e);
             HttpResponse::InternalServerError().json("Failed to get klines")
         }
     }
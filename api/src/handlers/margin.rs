@@ -0,0 +1,26 @@
+use actix_web::{web, HttpResponse, Responder};
+
+use crate::{
+    redis_manager::RedisManager,
+    types::{GetMarginAccountRequest, MessageToEngine, RepayMarginRequest},
+};
+
+pub async fn repay_margin(data: web::Json<RepayMarginRequest>) -> impl Responder {
+    let message_to_engine = MessageToEngine::RepayMargin(data.into_inner());
+
+    let redis_manager = RedisManager::get_instance().await;
+    match redis_manager.send_and_await(message_to_engine).await {
+        Ok(response) => HttpResponse::Ok().json(response),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error: {}", e)),
+    }
+}
+
+pub async fn get_margin_account(data: web::Query<GetMarginAccountRequest>) -> impl Responder {
+    let message_to_engine = MessageToEngine::GetMarginAccount(data.into_inner());
+
+    let redis_manager = RedisManager::get_instance().await;
+    match redis_manager.send_and_await(message_to_engine).await {
+        Ok(response) => HttpResponse::Ok().json(response),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error: {}", e)),
+    }
+}
@@ -0,0 +1,14 @@
+use actix_web::{HttpResponse, Responder};
+
+use crate::{redis_manager::RedisManager, types::MessageToEngine};
+
+pub async fn get_markets() -> impl Responder {
+    let redis_manager = RedisManager::get_instance().await;
+    match redis_manager
+        .send_and_await(MessageToEngine::GetMarkets)
+        .await
+    {
+        Ok(response) => HttpResponse::Ok().json(response),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error: {}", e)),
+    }
+}
@@ -0,0 +1,8 @@
+use actix_web::{web, HttpResponse, Responder};
+use engine::telemetry::PrometheusHandle;
+
+pub async fn get_metrics(handle: web::Data<PrometheusHandle>) -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(handle.render())
+}
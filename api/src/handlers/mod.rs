@@ -1,11 +1,23 @@
+pub mod account;
 pub mod depth;
 pub mod klines;
+pub mod margin;
+pub mod market;
+pub mod metrics;
 pub mod order;
-pub mod trades;
+pub mod positions;
+pub mod risk;
 pub mod tickers;
+pub mod trades;
 
+pub use account::*;
 pub use depth::*;
 pub use klines::*;
+pub use margin::*;
+pub use market::*;
+pub use metrics::*;
 pub use order::*;
-pub use trades::*;
+pub use positions::*;
+pub use risk::*;
 pub use tickers::*;
+pub use trades::*;
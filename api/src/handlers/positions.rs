@@ -0,0 +1,16 @@
+use actix_web::{web, HttpResponse, Responder};
+
+use crate::{
+    redis_manager::RedisManager,
+    types::{GetPositionsRequest, MessageToEngine},
+};
+
+pub async fn get_positions(data: web::Query<GetPositionsRequest>) -> impl Responder {
+    let message_to_engine = MessageToEngine::GetPositions(data.into_inner());
+
+    let redis_manager = RedisManager::get_instance().await;
+    match redis_manager.send_and_await(message_to_engine).await {
+        Ok(response) => HttpResponse::Ok().json(response),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error: {}", e)),
+    }
+}
@@ -1,5 +1,15 @@
 use actix_web::{HttpResponse, Responder};
 
+use crate::{redis_manager::RedisManager, types::MessageToEngine};
+
 pub async fn get_tickers() -> impl Responder {
-    HttpResponse::Ok().json({})
+    let redis_manager = RedisManager::get_instance().await;
+
+    match redis_manager
+        .send_and_await(MessageToEngine::GetTickers)
+        .await
+    {
+        Ok(response) => HttpResponse::Ok().json(response),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error: {}", e)),
+    }
 }
@@ -3,6 +3,7 @@ use actix_web::{web, HttpResponse, Responder};
 use engine::types::TradeAdd as Trade;
 use rust_decimal::Decimal;
 use sqlx::{PgPool, Row};
+use tracing::error;
 
 pub async fn get_trades(data: web::Query<TradeQuery>, pool: web::Data<PgPool>) -> impl Responder {
     let query_params = data.into_inner();
@@ -41,7 +42,7 @@ pub async fn get_trades(data: web::Query<TradeQuery>, pool: web::Data<PgPool>) -
             HttpResponse::Ok().json(trades)
         }
         Err(e) => {
-            eprintln!("Database error fetching trades: {}", e);
+            error!("Database error fetching trades: {}", e);
             HttpResponse::InternalServerError().json(serde_json::json!({
                 "error": "Failed to fetch trades"
             }))
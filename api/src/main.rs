@@ -1,26 +1,38 @@
 use actix_cors::Cors;
-use actix_web::{web, App, HttpServer};
+use actix_web::{middleware::from_fn, web, App, HttpServer};
 use sqlx::PgPool;
 use std::env;
+use tracing::info;
 
+mod correlation;
 mod handlers;
+mod metrics;
 mod redis_manager;
 mod types;
 use handlers::*;
 
+// On SIGTERM or Ctrl-C actix stops accepting connections and waits this long for
+// in-flight requests, including ones still waiting on an engine reply.
+const SHUTDOWN_TIMEOUT_SECS: u64 = 30;
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     dotenv::dotenv().ok();
+    engine::telemetry::init_tracing();
 
     let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
     let pool = PgPool::connect(&database_url)
         .await
         .expect("Failed to connect to database");
 
-    println!("Connected to database pool");
+    info!("Connected to database pool");
+
+    let metrics_handle = engine::telemetry::install_metrics_recorder();
 
     HttpServer::new(move || {
-        App::new()
+        let mut app = App::new()
+            .wrap(from_fn(metrics::record_request))
+            .wrap(from_fn(correlation::trace_request))
             .wrap(Cors::permissive())
             .app_data(web::JsonConfig::default())
             .app_data(web::Data::new(pool.clone()))
@@ -28,14 +40,33 @@ async fn main() -> std::io::Result<()> {
                 web::scope("/api/v1")
                     .route("/order", web::post().to(create_order))
                     .route("/order", web::delete().to(cancel_order))
+                    .route("/order/all", web::delete().to(cancel_all_orders))
                     .route("/order/open", web::get().to(get_open_orders))
                     .route("/depth", web::get().to(get_depth))
                     .route("/klines", web::get().to(get_klines))
+                    .route("/l3", web::get().to(get_l3_snapshot))
+                    .route("/margin/account", web::get().to(get_margin_account))
+                    .route("/margin/repay", web::post().to(repay_margin))
+                    .route("/markets", web::get().to(get_markets))
+                    .route("/positions", web::get().to(get_positions))
+                    .route("/user/risk-limits", web::get().to(get_risk_limits))
+                    .route("/subaccounts", web::get().to(get_sub_accounts))
+                    .route("/subaccounts", web::post().to(create_sub_account))
                     .route("/tickers", web::get().to(get_tickers))
-                    .route("/trades", web::get().to(get_trades)),
-            )
+                    .route("/trades", web::get().to(get_trades))
+                    .route("/transfer", web::post().to(transfer)),
+            );
+        if let Some(handle) = &metrics_handle {
+            app = app
+                .app_data(web::Data::new(handle.clone()))
+                .route("/metrics", web::get().to(get_metrics));
+        }
+        app
     })
     .bind(("127.0.0.1", 8000))?
+    .shutdown_timeout(SHUTDOWN_TIMEOUT_SECS)
     .run()
-    .await
+    .await?;
+    info!("API server stopped");
+    Ok(())
 }
@@ -0,0 +1,32 @@
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    middleware::Next,
+    Error,
+};
+use metrics::{counter, histogram};
+use std::time::Instant;
+
+// Counts and times every request by its route pattern, so ids in paths or
+// query strings do not create new series.
+pub async fn record_request(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let started = Instant::now();
+    let method = req.method().to_string();
+    let path = req
+        .match_pattern()
+        .unwrap_or_else(|| "unmatched".to_string());
+    let response = next.call(req).await?;
+    counter!(
+        "api_requests_total",
+        "method" => method.clone(),
+        "path" => path.clone(),
+        "status" => response.status().as_u16().to_string()
+    )
+    .increment(1);
+    histogram!("api_request_duration_seconds", "method" => method, "path" => path)
+        .record(started.elapsed().as_secs_f64());
+    Ok(response)
+}
@@ -1,17 +1,24 @@
-use futures_util::StreamExt;
-use redis::{
-    aio::{Connection, PubSub},
-    AsyncCommands, Client,
-};
+use engine::streams::{self, API_STREAM};
+use metrics::{counter, histogram};
+use redis::{aio::Connection, Client};
 use std::env;
 use std::error::Error;
+use std::time::{Duration, Instant};
 use tokio::sync::{Mutex, OnceCell};
+use tracing::debug;
 
+use crate::correlation;
 use crate::types::{MessageFromOrderbook, MessageToEngine};
 
+// Covers an engine failover, which can take as long as the leader lock's TTL
+const REPLY_TIMEOUT: Duration = Duration::from_secs(30);
+
 pub struct RedisManager {
+    redis: Client,
     client: Mutex<Connection>,
-    pubsub: Mutex<PubSub>,
+    // Waiting for a reply blocks the connection, so each request in flight takes
+    // one from here and returns it afterwards
+    reply_connections: Mutex<Vec<Connection>>,
 }
 
 pub static REDIS_MANAGER: OnceCell<RedisManager> = OnceCell::const_new();
@@ -21,12 +28,11 @@ impl RedisManager {
         let redis_url = env::var("REDIS_URL")?;
         let client = Client::open(redis_url.clone())?;
         let client_conn = client.get_async_connection().await?;
-        let pubsub_client = Client::open(redis_url.clone())?;
-        let pubsub_conn = pubsub_client.get_async_connection().await?;
 
         Ok(RedisManager {
             client: Mutex::new(client_conn),
-            pubsub: Mutex::new(pubsub_conn.into_pubsub()),
+            reply_connections: Mutex::new(Vec::new()),
+            redis: client,
         })
     }
 
@@ -44,32 +50,37 @@ impl RedisManager {
         &self,
         message: MessageToEngine,
     ) -> Result<MessageFromOrderbook, Box<dyn Error>> {
-        // async redis connectors
+        let started = Instant::now();
+        let result = self.request(message).await;
+        histogram!("api_engine_request_duration_seconds").record(started.elapsed().as_secs_f64());
+        if result.is_err() {
+            counter!("api_engine_request_failures_total").increment(1);
+        }
+        result
+    }
 
+    async fn request(
+        &self,
+        message: MessageToEngine,
+    ) -> Result<MessageFromOrderbook, Box<dyn Error>> {
         let client_id = self.get_random_client_id();
+        let payload = serde_json::to_string(&(client_id.clone(), message, correlation::current()))?;
 
-        //pubsub setup
-        let mut pubsub = self.pubsub.lock().await;
-        pubsub.subscribe(&client_id).await?;
-        let mut pubsub_stream = pubsub.on_message();
-
-        let payload = serde_json::to_string(&(client_id.clone(), message))?;
-
-        //push to queue
         let mut client = self.client.lock().await;
-        let _: () = client.lpush("messages", payload).await?;
+        let id = streams::push(&mut client, API_STREAM, &payload).await?;
         drop(client);
+        debug!("Queued request {} for the engine", id);
 
-        // await the stream to get message and response
-        if let Some(msg) = pubsub_stream.next().await {
-            let response: String = msg.get_payload()?;
-            let result: MessageFromOrderbook = serde_json::from_str(&response)?;
-            drop(pubsub_stream); //droppin stream cos on_message takes mut ref
-            pubsub.unsubscribe(&client_id).await?;
-            Ok(result)
-        } else {
-            Err("No message received".into())
-        }
+        // The reply stays on its stream until read, so it cannot be missed
+        let pooled = self.reply_connections.lock().await.pop();
+        let mut connection = match pooled {
+            Some(connection) => connection,
+            None => self.redis.get_async_connection().await?,
+        };
+        let response = streams::take_reply(&mut connection, &client_id, REPLY_TIMEOUT).await?;
+        self.reply_connections.lock().await.push(connection);
+        let response = response.ok_or("Timed out waiting for the engine")?;
+        Ok(serde_json::from_str(&response)?)
     }
 
     pub fn get_random_client_id(&self) -> String {
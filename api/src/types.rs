@@ -1,9 +1,15 @@
 use serde::{Deserialize, Serialize};
 
 pub use engine::types::{
-    CancelOrderPayload as CancelOrderRequest, CreateOrderPayload as PlaceOrderRequest,
-    GetDepthPayload as GetDepthRequest, GetOpenOrdersPayload as GetOpenOrdersRequest,
+    CancelAllOrdersPayload as CancelAllOrdersRequest, CancelOrderPayload as CancelOrderRequest,
+    CreateOrderPayload as PlaceOrderRequest, GetDepthPayload as GetDepthRequest,
+    GetL3SnapshotPayload as GetL3SnapshotRequest,
+    GetMarginAccountPayload as GetMarginAccountRequest,
+    GetOpenOrdersPayload as GetOpenOrdersRequest, GetPositionsPayload as GetPositionsRequest,
+    GetRiskLimitsPayload as GetRiskLimitsRequest, GetSubAccountsPayload as GetSubAccountsRequest,
     MessageToApi as MessageFromOrderbook, OnRampPayload as OnRampRequest,
+    RepayMarginPayload as RepayMarginRequest, SubAccountPayload as SubAccountRequest,
+    TransferPayload as TransferRequest,
 };
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -12,9 +18,33 @@ pub use engine::types::{
 pub enum MessageToEngine {
     CreateOrder(PlaceOrderRequest),
     CancelOrder(CancelOrderRequest),
+    CancelAllOrders(CancelAllOrdersRequest),
     OnRamp(OnRampRequest),
     GetDepth(GetDepthRequest),
+    GetL3Snapshot(GetL3SnapshotRequest),
     GetOpenOrders(GetOpenOrdersRequest),
+    GetMarkets,
+    GetTickers,
+    GetRiskLimits(GetRiskLimitsRequest),
+    RepayMargin(RepayMarginRequest),
+    GetMarginAccount(GetMarginAccountRequest),
+    GetPositions(GetPositionsRequest),
+    CreateSubAccount(SubAccountRequest),
+    GetSubAccounts(GetSubAccountsRequest),
+    Transfer(TransferRequest),
+}
+
+//Depth route types
+#[derive(Deserialize)]
+pub struct DepthQuery {
+    pub symbol: String,
+    pub limit: Option<usize>,
+    pub precision: Option<u64>,
+}
+
+#[derive(Deserialize)]
+pub struct L3Query {
+    pub symbol: String,
 }
 
 //Kline route types
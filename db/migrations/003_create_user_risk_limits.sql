@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS user_risk_limits (
+    user_id TEXT PRIMARY KEY,
+    max_open_orders BIGINT,
+    max_order_notional DECIMAL,
+    max_open_notional DECIMAL,
+    max_orders_per_second BIGINT,
+    updated_at TIMESTAMPTZ NOT NULL
+);
@@ -0,0 +1,11 @@
+CREATE TABLE IF NOT EXISTS transfers (
+    id TEXT PRIMARY KEY,
+    from_account TEXT NOT NULL,
+    to_account TEXT NOT NULL,
+    asset TEXT NOT NULL,
+    amount DECIMAL NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_transfers_from_account ON transfers(from_account);
+CREATE INDEX IF NOT EXISTS idx_transfers_to_account ON transfers(to_account);
@@ -1,129 +1,698 @@
 use chrono::{DateTime, Utc};
 use dotenv::dotenv;
-use engine::types::{DbMessage, DbMessageData, DbMessageType};
-use redis::{AsyncCommands, Client};
+use engine::streams::{self, DB_DEAD_LETTER_STREAM, DB_GROUP, DB_STREAM, PAYLOAD};
+use engine::types::{DbMessage, DbMessageData, DbMessageType, Traced, TradeAdd};
+use metrics::{counter, histogram};
+use redis::streams::StreamId;
+use redis::Client;
 use rust_decimal::Decimal;
-use serde_json;
 use sqlx::{error::BoxDynError, Connection, PgConnection};
+use std::fmt;
+use std::time::{Duration, Instant};
+use tracing::{error, info, info_span, warn, Instrument};
+
+// How long a read blocks before the loop checks for a shutdown signal
+const POLL_TIMEOUT: Duration = Duration::from_secs(1);
+const BATCH_SIZE: usize = 64;
+// How often unacknowledged messages are retried, and how long one must have waited
+// to be retried. Other instances in the group get that long to finish their own.
+const RECLAIM_INTERVAL: Duration = Duration::from_secs(30);
+// Pause before a failed message is tried again
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+// Deliveries after which a message the database keeps rejecting is dead-lettered
+const MAX_DELIVERIES: usize = 5;
+
+// Why a message was not stored
+#[derive(Debug)]
+enum StoreError {
+    Unparsable(String),
+    Database(sqlx::Error),
+    // The row for the message's id is already there with different content
+    Conflict { table: &'static str, id: String },
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StoreError::Unparsable(e) => write!(f, "unparsable message: {}", e),
+            StoreError::Database(e) => write!(f, "database error: {}", e),
+            StoreError::Conflict { table, id } => {
+                write!(f, "{} {} already stored with different content", table, id)
+            }
+        }
+    }
+}
+
+impl From<sqlx::Error> for StoreError {
+    fn from(e: sqlx::Error) -> Self {
+        StoreError::Database(e)
+    }
+}
+
+// What becomes of a message after an attempt to store it
+#[derive(Debug, PartialEq)]
+enum Outcome {
+    Ack,
+    Retry,
+    DeadLetter,
+}
 
 #[tokio::main]
 async fn main() {
     dotenv().ok();
+    engine::telemetry::init_tracing();
+    engine::telemetry::serve_metrics("0.0.0.0:9103");
+    // Instances sharing the `db` group split the stream between them; each needs a
+    // name that stays the same across restarts to pick up its own pending messages
+    let consumer = std::env::var("DB_CONSUMER").unwrap_or_else(|_| "db-processor".to_string());
 
     let (mut pg_conn, mut redis_conn) = match init().await {
         Ok((pg_conn, redis_conn)) => (pg_conn, redis_conn),
         Err(e) => {
-            println!("Error: {}", e);
+            error!("Error: {}", e);
             return;
         }
     };
-    loop {
-        match redis_conn
-            .brpop::<_, (String, String)>("db_processor", 0)
+    if let Err(e) = streams::create_group(&mut redis_conn, DB_STREAM, DB_GROUP).await {
+        error!("Failed to create consumer group: {}", e);
+        return;
+    }
+
+    let shutdown = engine::shutdown::listen();
+    let mut reclaimed_at = Instant::now();
+    // Whatever this consumer was storing when it last stopped comes first
+    let mut pending_first = true;
+    // The current batch is always stored before the loop checks for shutdown
+    while !shutdown.is_finished() {
+        let entries = if pending_first {
+            streams::read_pending(&mut redis_conn, &[DB_STREAM], DB_GROUP, &consumer)
+                .await
+                .map(|entries| entries.into_iter().map(|(_, entry)| entry).collect())
+        } else if reclaimed_at.elapsed() >= RECLAIM_INTERVAL {
+            reclaimed_at = Instant::now();
+            streams::claim_idle(
+                &mut redis_conn,
+                DB_STREAM,
+                DB_GROUP,
+                &consumer,
+                RECLAIM_INTERVAL,
+            )
+            .await
+        } else {
+            streams::read_group(
+                &mut redis_conn,
+                &[DB_STREAM],
+                DB_GROUP,
+                &consumer,
+                BATCH_SIZE,
+                POLL_TIMEOUT,
+            )
+            .await
+            .map(|entries| entries.into_iter().map(|(_, entry)| entry).collect())
+        };
+        let entries: Vec<StreamId> = match entries {
+            Ok(entries) => entries,
+            Err(e) => {
+                error!("Failed to get message {}", e);
+                continue;
+            }
+        };
+        // A message that failed holds back the rest of its batch, which stays
+        // pending, so no update is ever stored ahead of an earlier one
+        pending_first = false;
+        for entry in entries {
+            if handle(&mut pg_conn, &mut redis_conn, entry).await == Outcome::Retry {
+                pending_first = true;
+                tokio::time::sleep(RETRY_DELAY).await;
+                break;
+            }
+        }
+    }
+    if let Err(e) = pg_conn.close().await {
+        warn!("Failed to close database connection: {}", e);
+    }
+    info!("DB processor stopped");
+}
+
+// Stores the message, then acknowledges it, leaves it pending for a retry or
+// moves it to the dead-letter stream.
+async fn handle(
+    pg_conn: &mut PgConnection,
+    redis_conn: &mut redis::aio::Connection,
+    entry: StreamId,
+) -> Outcome {
+    let result = match parse(&entry) {
+        Ok(traced) => {
+            let db_message = traced.message;
+            let kind = message_kind(&db_message.db_message_type);
+            let span = info_span!(
+                "db_message",
+                correlation_id = traced.correlation_id.as_deref(),
+                message_id = entry.id,
+                kind
+            );
+            let started = Instant::now();
+            let result = store(pg_conn, db_message, kind).instrument(span).await;
+            counter!("db_messages_processed_total", "type" => kind).increment(1);
+            histogram!("db_insert_duration_seconds", "type" => kind)
+                .record(started.elapsed().as_secs_f64());
+            result
+        }
+        Err(e) => Err(e),
+    };
+    if let Err(StoreError::Unparsable(_)) = &result {
+        counter!("db_messages_unparsable_total").increment(1);
+    }
+    let deliveries = match &result {
+        Err(StoreError::Database(_)) => {
+            streams::delivery_count(redis_conn, DB_STREAM, DB_GROUP, &entry.id)
+                .await
+                .unwrap_or_else(|e| {
+                    error!("Failed to count deliveries of {}: {}", entry.id, e);
+                    0
+                })
+        }
+        _ => 0,
+    };
+    match outcome(&result, deliveries) {
+        Outcome::Ack => {
+            if let Err(e) = streams::ack(redis_conn, DB_STREAM, DB_GROUP, &entry.id).await {
+                error!("Failed to acknowledge message {}: {}", entry.id, e);
+            }
+            Outcome::Ack
+        }
+        Outcome::Retry => {
+            warn!("Message {} left pending for a retry", entry.id);
+            Outcome::Retry
+        }
+        Outcome::DeadLetter => {
+            let reason = result.err().map(|e| e.to_string()).unwrap_or_default();
+            error!("Dead-lettering message {}: {}", entry.id, reason);
+            if let Err(e) = streams::dead_letter(
+                redis_conn,
+                DB_STREAM,
+                DB_GROUP,
+                DB_DEAD_LETTER_STREAM,
+                &entry,
+                &reason,
+            )
             .await
-        {
-            Ok((_, msg)) => {
-                if let Ok(db_message) = serde_json::from_str::<DbMessage>(&msg) {
-                    match db_message.db_message_type {
-                        DbMessageType::TradeAdded => {
-                            if let DbMessageData::TradeAdd(trade) = db_message.data {
-                                println!(
-                                    "Trade added: id={}, price={}, quantity={}",
-                                    trade.id, trade.price, trade.quantity
-                                );
-
-                                let timestamp = trade
-                                    .timestamp
-                                    .parse::<DateTime<Utc>>()
-                                    .unwrap_or_else(|_| Utc::now());
-
-                                let price: Decimal = trade.price.parse().unwrap_or_default();
-                                let quantity: Decimal = trade.quantity.parse().unwrap_or_default();
-                                let quote_quantity: Decimal =
-                                    trade.quote_quantity.parse().unwrap_or_default();
-
-                                let query = "INSERT INTO trades (id, timestamp, market, price, quantity, quote_quantity, is_buyer_maker) VALUES ($1, $2, $3, $4, $5, $6, $7)";
-                                if let Err(e) = sqlx::query(query)
-                                    .bind(&trade.id)
-                                    .bind(timestamp)
-                                    .bind(&trade.market)
-                                    .bind(price)
-                                    .bind(quantity)
-                                    .bind(quote_quantity)
-                                    .bind(trade.is_buyer_maker)
-                                    .execute(&mut pg_conn)
-                                    .await
-                                {
-                                    eprintln!("Failed to insert trade into database: {}", e);
-                                }
-                            }
-                        }
-                        DbMessageType::OrderUpdate => {
-                            if let DbMessageData::OrderUpdate(order_update) = db_message.data {
-                                println!(
-                                    "Order updated: id={}, executed_qty={}",
-                                    order_update.order_id, order_update.executed_quantity
-                                );
-
-                                let timestamp = Utc::now();
-
-                                let executed_quantity: Decimal =
-                                    order_update.executed_quantity.into();
-                                let price = order_update
-                                    .price
-                                    .as_ref()
-                                    .and_then(|p| p.parse::<Decimal>().ok());
-                                let quantity = order_update
-                                    .quantity
-                                    .as_ref()
-                                    .and_then(|q| q.parse::<Decimal>().ok());
-                                let side = order_update.side.as_ref().map(|s| s.as_str());
-
-                                let query = r#"
-                                    INSERT INTO orders (order_id, executed_quantity, price, market, quantity, side, updated_at)
-                                    VALUES ($1, $2, $3, $4, $5, $6, $7)
-                                    ON CONFLICT (order_id) 
-                                    DO UPDATE SET 
-                                        executed_quantity = EXCLUDED.executed_quantity,
-                                        price = COALESCE(EXCLUDED.price, orders.price),
-                                        market = COALESCE(EXCLUDED.market, orders.market),
-                                        quantity = COALESCE(EXCLUDED.quantity, orders.quantity),
-                                        side = COALESCE(EXCLUDED.side, orders.side),
-                                        updated_at = EXCLUDED.updated_at
-                                "#;
-
-                                if let Err(e) = sqlx::query(query)
-                                    .bind(&order_update.order_id)
-                                    .bind(executed_quantity)
-                                    .bind(price)
-                                    .bind(order_update.market.as_deref())
-                                    .bind(quantity)
-                                    .bind(side)
-                                    .bind(timestamp)
-                                    .execute(&mut pg_conn)
-                                    .await
-                                {
-                                    eprintln!("Failed to insert/update order in database: {}", e);
-                                }
-                            }
-                        }
+            {
+                error!("Failed to dead-letter message {}: {}", entry.id, e);
+                return Outcome::Retry;
+            }
+            counter!("db_messages_dead_lettered_total").increment(1);
+            Outcome::DeadLetter
+        }
+    }
+}
+
+// Only a stored message is acknowledged. One that can never be stored goes to
+// the dead-letter stream straight away; one the database rejects goes there once
+// it has been delivered `MAX_DELIVERIES` times. Connection failures say nothing
+// about the message, so those are retried for as long as they last.
+fn outcome(result: &Result<(), StoreError>, deliveries: usize) -> Outcome {
+    match result {
+        Ok(()) => Outcome::Ack,
+        Err(StoreError::Unparsable(_) | StoreError::Conflict { .. }) => Outcome::DeadLetter,
+        Err(StoreError::Database(
+            sqlx::Error::Io(_)
+            | sqlx::Error::Tls(_)
+            | sqlx::Error::PoolTimedOut
+            | sqlx::Error::PoolClosed
+            | sqlx::Error::WorkerCrashed,
+        )) => Outcome::Retry,
+        Err(StoreError::Database(_)) if deliveries >= MAX_DELIVERIES => Outcome::DeadLetter,
+        Err(StoreError::Database(_)) => Outcome::Retry,
+    }
+}
+
+fn parse(entry: &StreamId) -> Result<Traced<DbMessage>, StoreError> {
+    let msg: String = entry
+        .get(PAYLOAD)
+        .ok_or_else(|| StoreError::Unparsable(format!("no {} field", PAYLOAD)))?;
+    serde_json::from_str(&msg).map_err(|e| StoreError::Unparsable(format!("{}: {}", e, msg)))
+}
+
+// The time a trade happened is part of its row, and of the check that a stored
+// row matches a redelivered message, so it is never made up.
+fn trade_timestamp(trade: &TradeAdd) -> Result<DateTime<Utc>, StoreError> {
+    trade.timestamp.parse().map_err(|e| {
+        StoreError::Unparsable(format!(
+            "trade {} timestamp {:?}: {}",
+            trade.id, trade.timestamp, e
+        ))
+    })
+}
+
+// Rows that are only ever inserted may already be there when a message is
+// delivered again. That is fine when the row holds what the message carries;
+// anything else means two different messages share an id.
+fn conflict(table: &'static str, id: &str, kind: &'static str) -> StoreError {
+    error!("{} {} is already stored with different content", table, id);
+    counter!("db_insert_conflicts_total", "type" => kind).increment(1);
+    StoreError::Conflict {
+        table,
+        id: id.to_string(),
+    }
+}
+
+async fn store(
+    pg_conn: &mut PgConnection,
+    db_message: DbMessage,
+    kind: &'static str,
+) -> Result<(), StoreError> {
+    match db_message.db_message_type {
+        DbMessageType::TradeAdded => {
+            if let DbMessageData::TradeAdd(trade) = db_message.data {
+                info!(
+                    "Trade added: id={}, price={}, quantity={}",
+                    trade.id, trade.price, trade.quantity
+                );
+
+                let timestamp = trade_timestamp(&trade)?;
+
+                let price: Decimal = trade.price.parse().unwrap_or_default();
+                let quantity: Decimal = trade.quantity.parse().unwrap_or_default();
+                let quote_quantity: Decimal = trade.quote_quantity.parse().unwrap_or_default();
+
+                // The outer query sees the table as it was before the insert
+                let query = r#"
+                    WITH inserted AS (
+                        INSERT INTO trades (id, timestamp, market, price, quantity, quote_quantity, is_buyer_maker)
+                        VALUES ($1, $2, $3, $4, $5, $6, $7)
+                        ON CONFLICT (id) DO NOTHING
+                        RETURNING id
+                    )
+                    SELECT EXISTS (SELECT 1 FROM inserted) OR EXISTS (
+                        SELECT 1 FROM trades
+                        WHERE id = $1 AND timestamp = $2 AND market = $3 AND price = $4
+                            AND quantity = $5 AND quote_quantity = $6 AND is_buyer_maker = $7
+                    )
+                "#;
+                match sqlx::query_scalar::<_, bool>(query)
+                    .bind(&trade.id)
+                    .bind(timestamp)
+                    .bind(&trade.market)
+                    .bind(price)
+                    .bind(quantity)
+                    .bind(quote_quantity)
+                    .bind(trade.is_buyer_maker)
+                    .fetch_one(&mut *pg_conn)
+                    .await
+                {
+                    Ok(true) => {}
+                    Ok(false) => return Err(conflict("trade", &trade.id, kind)),
+                    Err(e) => {
+                        error!("Failed to insert trade into database: {}", e);
+                        counter!("db_insert_failures_total", "type" => kind).increment(1);
+                        return Err(e.into());
                     }
                 }
             }
-            Err(e) => {
-                eprintln!("Failed to get message {}", e);
-                continue;
+        }
+        DbMessageType::OrderUpdate => {
+            if let DbMessageData::OrderUpdate(order_update) = db_message.data {
+                info!(
+                    "Order updated: id={}, executed_qty={}",
+                    order_update.order_id, order_update.executed_quantity
+                );
+
+                let timestamp = Utc::now();
+
+                let executed_quantity: Decimal = order_update.executed_quantity.into();
+                let price = order_update
+                    .price
+                    .as_ref()
+                    .and_then(|p| p.parse::<Decimal>().ok());
+                let quantity = order_update
+                    .quantity
+                    .as_ref()
+                    .and_then(|q| q.parse::<Decimal>().ok());
+                let side = order_update.side.as_ref().map(|s| s.as_str());
+
+                // Fills only add up, so an update that arrives late or twice never
+                // lowers the executed quantity
+                let query = r#"
+                    INSERT INTO orders (order_id, executed_quantity, price, market, quantity, side, updated_at)
+                    VALUES ($1, $2, $3, $4, $5, $6, $7)
+                    ON CONFLICT (order_id) 
+                    DO UPDATE SET 
+                        executed_quantity = GREATEST(orders.executed_quantity, EXCLUDED.executed_quantity),
+                        price = COALESCE(EXCLUDED.price, orders.price),
+                        market = COALESCE(EXCLUDED.market, orders.market),
+                        quantity = COALESCE(EXCLUDED.quantity, orders.quantity),
+                        side = COALESCE(EXCLUDED.side, orders.side),
+                        updated_at = EXCLUDED.updated_at
+                "#;
+
+                if let Err(e) = sqlx::query(query)
+                    .bind(&order_update.order_id)
+                    .bind(executed_quantity)
+                    .bind(price)
+                    .bind(order_update.market.as_deref())
+                    .bind(quantity)
+                    .bind(side)
+                    .bind(timestamp)
+                    .execute(&mut *pg_conn)
+                    .await
+                {
+                    error!("Failed to insert/update order in database: {}", e);
+                    counter!("db_insert_failures_total", "type" => kind).increment(1);
+                    return Err(e.into());
+                }
+            }
+        }
+        DbMessageType::OrderCancelled => {
+            if let DbMessageData::OrderCancelled(cancelled) = db_message.data {
+                info!(
+                    "Order cancelled: id={}, executed_qty={}, remaining_qty={}",
+                    cancelled.order_id, cancelled.executed_quantity, cancelled.remaining_quantity
+                );
+
+                let timestamp = cancelled
+                    .timestamp
+                    .parse::<DateTime<Utc>>()
+                    .unwrap_or_else(|_| Utc::now());
+                let executed_quantity: Decimal = cancelled.executed_quantity.into();
+
+                let query = r#"
+                    INSERT INTO orders (order_id, executed_quantity, market, status, cancelled_at, updated_at)
+                    VALUES ($1, $2, $3, 'cancelled', $4, $4)
+                    ON CONFLICT (order_id)
+                    DO UPDATE SET
+                        executed_quantity = GREATEST(orders.executed_quantity, EXCLUDED.executed_quantity),
+                        market = COALESCE(orders.market, EXCLUDED.market),
+                        status = EXCLUDED.status,
+                        cancelled_at = EXCLUDED.cancelled_at,
+                        updated_at = EXCLUDED.updated_at
+                "#;
+
+                if let Err(e) = sqlx::query(query)
+                    .bind(&cancelled.order_id)
+                    .bind(executed_quantity)
+                    .bind(&cancelled.market)
+                    .bind(timestamp)
+                    .execute(&mut *pg_conn)
+                    .await
+                {
+                    error!("Failed to mark order as cancelled in database: {}", e);
+                    counter!("db_insert_failures_total", "type" => kind).increment(1);
+                    return Err(e.into());
+                }
+            }
+        }
+        DbMessageType::RiskLimitsUpdated => {
+            if let DbMessageData::RiskLimits(risk_limits) = db_message.data {
+                info!("Risk limits updated: user_id={}", risk_limits.user_id);
+
+                let limits = risk_limits.limits;
+                let query = r#"
+                    INSERT INTO user_risk_limits (user_id, max_open_orders, max_order_notional, max_open_notional, max_orders_per_second, updated_at)
+                    VALUES ($1, $2, $3, $4, $5, $6)
+                    ON CONFLICT (user_id)
+                    DO UPDATE SET
+                        max_open_orders = EXCLUDED.max_open_orders,
+                        max_order_notional = EXCLUDED.max_order_notional,
+                        max_open_notional = EXCLUDED.max_open_notional,
+                        max_orders_per_second = EXCLUDED.max_orders_per_second,
+                        updated_at = EXCLUDED.updated_at
+                "#;
+
+                if let Err(e) = sqlx::query(query)
+                    .bind(&risk_limits.user_id)
+                    .bind(limits.max_open_orders.map(|v| v as i64))
+                    .bind(limits.max_order_notional)
+                    .bind(limits.max_open_notional)
+                    .bind(limits.max_orders_per_second.map(|v| v as i64))
+                    .bind(Utc::now())
+                    .execute(&mut *pg_conn)
+                    .await
+                {
+                    error!("Failed to store risk limits in database: {}", e);
+                    counter!("db_insert_failures_total", "type" => kind).increment(1);
+                    return Err(e.into());
+                }
+            }
+        }
+        DbMessageType::TransferCompleted => {
+            if let DbMessageData::Transfer(transfer) = db_message.data {
+                info!(
+                    "Transfer completed: {} {} from {} to {}",
+                    transfer.amount, transfer.asset, transfer.from_account, transfer.to_account
+                );
+
+                let query = r#"
+                    WITH inserted AS (
+                        INSERT INTO transfers (id, from_account, to_account, asset, amount, created_at)
+                        VALUES ($1, $2, $3, $4, $5, $6)
+                        ON CONFLICT (id) DO NOTHING
+                        RETURNING id
+                    )
+                    SELECT EXISTS (SELECT 1 FROM inserted) OR EXISTS (
+                        SELECT 1 FROM transfers
+                        WHERE id = $1 AND from_account = $2 AND to_account = $3
+                            AND asset = $4 AND amount = $5
+                    )
+                "#;
+
+                match sqlx::query_scalar::<_, bool>(query)
+                    .bind(&transfer.id)
+                    .bind(&transfer.from_account)
+                    .bind(&transfer.to_account)
+                    .bind(&transfer.asset)
+                    .bind(transfer.amount)
+                    .bind(Utc::now())
+                    .fetch_one(&mut *pg_conn)
+                    .await
+                {
+                    Ok(true) => {}
+                    Ok(false) => return Err(conflict("transfer", &transfer.id, kind)),
+                    Err(e) => {
+                        error!("Failed to store transfer in database: {}", e);
+                        counter!("db_insert_failures_total", "type" => kind).increment(1);
+                        return Err(e.into());
+                    }
+                }
+            }
+        }
+        DbMessageType::BalanceAdjusted => {
+            if let DbMessageData::BalanceAdjustment(adjustment) = db_message.data {
+                info!(
+                    "Balance adjusted: {} {} for {} ({})",
+                    adjustment.amount, adjustment.asset, adjustment.user_id, adjustment.reason
+                );
+
+                let query = r#"
+                    WITH inserted AS (
+                        INSERT INTO balance_adjustments (id, user_id, asset, amount, reason, created_at)
+                        VALUES ($1, $2, $3, $4, $5, $6)
+                        ON CONFLICT (id) DO NOTHING
+                        RETURNING id
+                    )
+                    SELECT EXISTS (SELECT 1 FROM inserted) OR EXISTS (
+                        SELECT 1 FROM balance_adjustments
+                        WHERE id = $1 AND user_id = $2 AND asset = $3 AND amount = $4 AND reason = $5
+                    )
+                "#;
+
+                match sqlx::query_scalar::<_, bool>(query)
+                    .bind(&adjustment.id)
+                    .bind(&adjustment.user_id)
+                    .bind(&adjustment.asset)
+                    .bind(adjustment.amount)
+                    .bind(&adjustment.reason)
+                    .bind(Utc::now())
+                    .fetch_one(&mut *pg_conn)
+                    .await
+                {
+                    Ok(true) => {}
+                    Ok(false) => {
+                        return Err(conflict("balance adjustment", &adjustment.id, kind));
+                    }
+                    Err(e) => {
+                        error!("Failed to store balance adjustment in database: {}", e);
+                        counter!("db_insert_failures_total", "type" => kind).increment(1);
+                        return Err(e.into());
+                    }
+                }
             }
         }
     }
+    Ok(())
+}
+
+// Label for the message in metrics
+fn message_kind(message_type: &DbMessageType) -> &'static str {
+    match message_type {
+        DbMessageType::TradeAdded => "trade_added",
+        DbMessageType::OrderUpdate => "order_update",
+        DbMessageType::OrderCancelled => "order_cancelled",
+        DbMessageType::RiskLimitsUpdated => "risk_limits_updated",
+        DbMessageType::TransferCompleted => "transfer_completed",
+        DbMessageType::BalanceAdjusted => "balance_adjusted",
+    }
 }
 
 async fn init() -> Result<(PgConnection, redis::aio::Connection), BoxDynError> {
     let pg_conn = PgConnection::connect(&std::env::var("DATABASE_URL")?).await?;
-    println!("Connected to PG database");
+    info!("Connected to PG database");
 
     let redis = Client::open(std::env::var("REDIS_URL").unwrap())?;
     let redis_conn = redis.get_async_connection().await?;
-    println!("Connected to redis");
+    info!("Connected to redis");
     Ok((pg_conn, redis_conn))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use engine::types::TransferRecord;
+    use redis::Value;
+    use std::collections::HashMap;
+
+    fn entry(payload: &str) -> StreamId {
+        StreamId {
+            id: "1-0".to_string(),
+            map: HashMap::from([(
+                PAYLOAD.to_string(),
+                Value::Data(payload.as_bytes().to_vec()),
+            )]),
+        }
+    }
+
+    fn published(message: DbMessage) -> String {
+        serde_json::to_string(&Traced {
+            message,
+            correlation_id: Some("req-1".to_string()),
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn only_stored_messages_are_acknowledged() {
+        assert_eq!(outcome(&Ok(()), 1), Outcome::Ack);
+        let rejected = || Err(StoreError::Database(sqlx::Error::RowNotFound));
+        assert_eq!(outcome(&rejected(), 1), Outcome::Retry);
+        assert_eq!(outcome(&rejected(), MAX_DELIVERIES - 1), Outcome::Retry);
+        assert_eq!(outcome(&rejected(), MAX_DELIVERIES), Outcome::DeadLetter);
+    }
+
+    #[test]
+    fn connection_failures_are_retried_past_the_delivery_cap() {
+        let io = std::io::Error::new(std::io::ErrorKind::ConnectionReset, "reset");
+        for e in [sqlx::Error::Io(io), sqlx::Error::PoolTimedOut] {
+            assert_eq!(
+                outcome(&Err(StoreError::Database(e)), MAX_DELIVERIES * 10),
+                Outcome::Retry
+            );
+        }
+    }
+
+    #[test]
+    fn messages_that_can_never_be_stored_are_dead_lettered_at_once() {
+        let conflict = StoreError::Conflict {
+            table: "trade",
+            id: "TATA_INR-1".to_string(),
+        };
+        assert_eq!(outcome(&Err(conflict), 1), Outcome::DeadLetter);
+        let unparsable = parse(&entry("not json")).map(|_| ());
+        assert!(matches!(unparsable, Err(StoreError::Unparsable(_))));
+        assert_eq!(outcome(&unparsable, 1), Outcome::DeadLetter);
+        let missing = StreamId {
+            id: "1-0".to_string(),
+            map: HashMap::new(),
+        };
+        assert_eq!(
+            outcome(&parse(&missing).map(|_| ()), 1),
+            Outcome::DeadLetter
+        );
+    }
+
+    #[test]
+    fn a_trade_without_a_readable_timestamp_is_dead_lettered() {
+        let trade = |timestamp: &str| TradeAdd {
+            id: "TATA_INR-7".to_string(),
+            is_buyer_maker: false,
+            price: "10".to_string(),
+            quantity: "1".to_string(),
+            quote_quantity: "10".to_string(),
+            timestamp: timestamp.to_string(),
+            market: "TATA_INR".to_string(),
+        };
+        // As the engine writes it
+        let at = DateTime::from_timestamp_millis(1_704_067_200_123).unwrap();
+        assert_eq!(trade_timestamp(&trade(&at.to_string())).unwrap(), at);
+        assert_eq!(
+            trade_timestamp(&trade("2024-01-01T00:00:00.123Z")).unwrap(),
+            at
+        );
+
+        let unreadable = trade_timestamp(&trade("yesterday")).map(|_| ());
+        assert!(matches!(unreadable, Err(StoreError::Unparsable(_))));
+        assert_eq!(outcome(&unreadable, 1), Outcome::DeadLetter);
+    }
+
+    #[test]
+    fn db_messages_round_trip_through_the_stream_payload() {
+        let trade = DbMessage {
+            db_message_type: DbMessageType::TradeAdded,
+            data: DbMessageData::TradeAdd(TradeAdd {
+                id: "TATA_INR-7".to_string(),
+                is_buyer_maker: true,
+                price: "101.5".to_string(),
+                quantity: "3".to_string(),
+                quote_quantity: "304.5".to_string(),
+                timestamp: "2024-01-01T00:00:00Z".to_string(),
+                market: "TATA_INR".to_string(),
+            }),
+        };
+        let traced = parse(&entry(&published(trade))).unwrap();
+        assert_eq!(traced.correlation_id.as_deref(), Some("req-1"));
+        assert!(matches!(
+            traced.message.db_message_type,
+            DbMessageType::TradeAdded
+        ));
+        let DbMessageData::TradeAdd(trade) = traced.message.data else {
+            panic!("expected a trade");
+        };
+        assert_eq!(trade.id, "TATA_INR-7");
+        assert!(trade.is_buyer_maker);
+        assert_eq!(
+            (
+                trade.price.as_str(),
+                trade.quantity.as_str(),
+                trade.quote_quantity.as_str()
+            ),
+            ("101.5", "3", "304.5")
+        );
+        assert_eq!(trade.timestamp, "2024-01-01T00:00:00Z");
+        assert_eq!(trade.market, "TATA_INR");
+
+        // Amounts keep their scale, so a redelivery compares equal to the stored row
+        let transfer = DbMessage {
+            db_message_type: DbMessageType::TransferCompleted,
+            data: DbMessageData::Transfer(TransferRecord {
+                id: "t-1".to_string(),
+                from_account: "0".to_string(),
+                to_account: "0:hedge".to_string(),
+                asset: "INR".to_string(),
+                amount: "0.10000000".parse().unwrap(),
+            }),
+        };
+        let DbMessageData::Transfer(transfer) =
+            parse(&entry(&published(transfer))).unwrap().message.data
+        else {
+            panic!("expected a transfer");
+        };
+        assert_eq!(transfer.amount.to_string(), "0.10000000");
+        assert_eq!(
+            (
+                transfer.id.as_str(),
+                transfer.from_account.as_str(),
+                transfer.to_account.as_str()
+            ),
+            ("t-1", "0", "0:hedge")
+        );
+    }
+}
@@ -0,0 +1,217 @@
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use engine::streams::ADMIN_STREAM;
+use engine::types::{AdminCommand, EngineRequest, MessageFromApi};
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+use tracing::{debug, error, warn};
+
+use crate::redis_manager::RedisManager;
+
+// How often the leader looks for requests stuck pending, and how long one must
+// have waited to count as stuck
+const RECLAIM_INTERVAL: Duration = Duration::from_secs(5);
+
+// Position of an entry in the input journal as assigned by Redis: the millisecond
+// the entry was appended and a counter within that millisecond.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(into = "String", try_from = "String")]
+pub struct JournalId {
+    pub millis: u64,
+    pub seq: u64,
+}
+
+impl fmt::Display for JournalId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.millis, self.seq)
+    }
+}
+
+impl FromStr for JournalId {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (millis, seq) = s
+            .split_once('-')
+            .ok_or_else(|| format!("Invalid journal id: {}", s))?;
+        Ok(JournalId {
+            millis: millis
+                .parse()
+                .map_err(|_| format!("Invalid journal id: {}", s))?,
+            seq: seq
+                .parse()
+                .map_err(|_| format!("Invalid journal id: {}", s))?,
+        })
+    }
+}
+
+impl From<JournalId> for String {
+    fn from(id: JournalId) -> Self {
+        id.to_string()
+    }
+}
+
+impl TryFrom<String> for JournalId {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+pub enum IncomingMessage {
+    Api(MessageFromApi),
+    Admin(AdminCommand),
+}
+
+// A request read from an input stream but not yet journaled. `id` is its id on
+// that stream.
+pub struct QueuedMessage {
+    pub queue: String,
+    pub id: String,
+    pub payload: String,
+}
+
+pub struct JournalEntry {
+    pub id: JournalId,
+    // Stream the request was taken from, which decides how its payload is read
+    pub queue: String,
+    pub payload: String,
+}
+
+impl JournalEntry {
+    pub fn parse(&self) -> Result<EngineRequest<IncomingMessage>, serde_json::Error> {
+        if self.queue == ADMIN_STREAM {
+            let request: EngineRequest<AdminCommand> = serde_json::from_str(&self.payload)?;
+            return Ok(EngineRequest {
+                client_id: request.client_id,
+                message: IncomingMessage::Admin(request.message),
+                correlation_id: request.correlation_id,
+            });
+        }
+        let request: EngineRequest<MessageFromApi> = serde_json::from_str(&self.payload)?;
+        Ok(EngineRequest {
+            client_id: request.client_id,
+            message: IncomingMessage::Api(request.message),
+            correlation_id: request.correlation_id,
+        })
+    }
+}
+
+// While this instance leads, moves requests from the input streams into the
+// journal, which fixes the one order every replica applies them in. A request
+// stays pending on its input stream until it is journaled, so one read by a leader
+// that died first is claimed again by its successor.
+pub fn spawn_sequencer(instance_id: String, mut leader: watch::Receiver<bool>) {
+    tokio::spawn(async move {
+        let redis = RedisManager::get_instance().await;
+        if let Err(e) = redis.create_request_groups().await {
+            error!("Failed to create consumer groups: {}", e);
+        }
+        let mut reclaimed_at: Option<Instant> = None;
+        loop {
+            if !*leader.borrow() {
+                reclaimed_at = None;
+                if leader.wait_for(|&leading| leading).await.is_err() {
+                    return;
+                }
+            }
+            // A new leader starts with everything its predecessor left pending, then
+            // keeps picking up requests that got stuck, such as ones it failed to journal
+            let requests = match reclaimed_at {
+                None => Some(Duration::ZERO),
+                Some(at) if at.elapsed() >= RECLAIM_INTERVAL => Some(RECLAIM_INTERVAL),
+                Some(_) => None,
+            };
+            let requests = match requests {
+                Some(min_idle) => {
+                    reclaimed_at = Some(Instant::now());
+                    redis.claim_requests(&instance_id, min_idle).await
+                }
+                None => redis.take_requests(&instance_id).await,
+            }
+            .map_err(|e| error!("Failed to read requests: {}", e));
+            let Ok(requests) = requests else {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            };
+            for request in requests {
+                match redis.append_to_journal(&instance_id, &request).await {
+                    Ok(Some(id)) => {
+                        debug!("Journaled {} from {} as {}", request.id, request.queue, id)
+                    }
+                    Ok(None) => {
+                        warn!("No longer the leader, leaving requests pending");
+                        break;
+                    }
+                    Err(e) => error!(
+                        "Failed to journal {} from {}: {}",
+                        request.id, request.queue, e
+                    ),
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use engine::streams::API_STREAM;
+
+    fn entry(queue: &str, message: &str) -> JournalEntry {
+        JournalEntry {
+            id: JournalId { millis: 1, seq: 0 },
+            queue: queue.to_string(),
+            payload: format!(r#"{{"client_id":"c","message":{}}}"#, message),
+        }
+    }
+
+    #[test]
+    fn requests_are_only_read_as_what_their_stream_carries() {
+        let halt = r#"{"type":"SET_MARKET_STATUS","data":{"market":"TATA_INR","status":"HALTED"}}"#;
+        let user = r#"{"type":"GET_USER","data":{"user_id":"1"}}"#;
+        let on_ramp = r#"{"type":"ON_RAMP","data":{"user_id":"1","amount":"100","asset":"INR","txn_id":"t"}}"#;
+
+        for command in [halt, user] {
+            let request = entry(ADMIN_STREAM, command).parse().unwrap();
+            assert!(matches!(request.message, IncomingMessage::Admin(_)));
+            assert_eq!(request.client_id, "c");
+            // Operator commands that reach the public stream are never applied
+            assert!(entry(API_STREAM, command).parse().is_err());
+        }
+        let request = entry(API_STREAM, on_ramp).parse().unwrap();
+        assert!(matches!(request.message, IncomingMessage::Api(_)));
+        assert!(entry(ADMIN_STREAM, on_ramp).parse().is_err());
+    }
+
+    #[test]
+    fn journal_ids_order_and_round_trip_as_redis_ids() {
+        let id: JournalId = "1700000000000-12".parse().unwrap();
+        assert_eq!(
+            id,
+            JournalId {
+                millis: 1_700_000_000_000,
+                seq: 12
+            }
+        );
+        assert_eq!(id.to_string(), "1700000000000-12");
+        assert!(
+            id < JournalId {
+                millis: 1_700_000_000_000,
+                seq: 13
+            }
+        );
+        assert!(
+            id < JournalId {
+                millis: 1_700_000_000_001,
+                seq: 0
+            }
+        );
+        for invalid in ["", "12", "a-1", "1-b", "1-2-3"] {
+            assert!(invalid.parse::<JournalId>().is_err(), "{}", invalid);
+        }
+    }
+}
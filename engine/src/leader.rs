@@ -0,0 +1,47 @@
+use std::time::Duration;
+
+use metrics::gauge;
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+use crate::redis_manager::RedisManager;
+
+// How long the leader key outlives a leader that stops renewing it, which bounds
+// how long the exchange goes without a leader after a crash
+const LEADER_TTL: Duration = Duration::from_secs(5);
+const RENEW_INTERVAL: Duration = Duration::from_secs(1);
+
+// Leadership is a Redis key holding the leader's instance id. The leader renews it
+// every second; once it stops, the key expires and the next instance to try takes
+// over. Failing to renew for any reason demotes the instance straight away, and the
+// publisher checks the key again with every write it makes.
+pub fn spawn_election(instance_id: String) -> watch::Receiver<bool> {
+    let (leader_tx, leader_rx) = watch::channel(false);
+    tokio::spawn(async move {
+        let redis = RedisManager::get_instance().await;
+        let mut interval = tokio::time::interval(RENEW_INTERVAL);
+        loop {
+            interval.tick().await;
+            let leading = *leader_tx.borrow();
+            let result = if leading {
+                redis.renew_leadership(&instance_id, LEADER_TTL).await
+            } else {
+                redis.try_acquire_leadership(&instance_id, LEADER_TTL).await
+            };
+            let now_leading = result.unwrap_or_else(|e| {
+                warn!("Leader election failed: {}", e);
+                false
+            });
+            if now_leading != leading {
+                if now_leading {
+                    info!("Instance {} is now the leader", instance_id);
+                } else {
+                    warn!("Instance {} lost leadership", instance_id);
+                }
+                leader_tx.send_replace(now_leading);
+            }
+            gauge!("engine_leader").set(if now_leading { 1.0 } else { 0.0 });
+        }
+    });
+    leader_rx
+}
@@ -1 +1,4 @@
+pub mod shutdown;
+pub mod streams;
+pub mod telemetry;
 pub mod types;
@@ -1,35 +1,199 @@
+use journal::{IncomingMessage, JournalId};
+use publisher::OutputSender;
 use redis_manager::RedisManager;
+use tracing::{debug, error, info};
 use trades::engine::{Engine, ProcessParams};
+use trades::snapshot::EngineSnapshot;
+mod journal;
+mod leader;
+mod metrics;
+mod publisher;
 mod redis_manager;
 mod trades;
 
+// Journal entries the leader processes between snapshots
+const SNAPSHOT_INTERVAL: u64 = 10_000;
+
 #[tokio::main]
 async fn main() {
     dotenv::dotenv().ok();
-    let mut engine = Engine::new();
+    engine::telemetry::init_tracing();
+    let instance_id =
+        std::env::var("ENGINE_ID").unwrap_or_else(|_| format!("{:016x}", rand::random::<u64>()));
+    engine::telemetry::serve_metrics("0.0.0.0:9101");
+    metrics::spawn_queue_monitor();
     let redis = RedisManager::get_instance().await;
+    let leader = leader::spawn_election(instance_id.clone());
+    let (outputs, publisher) = publisher::spawn_publisher(instance_id.clone(), leader.clone());
 
-    println!("Engine started");
-
-    loop {
-        println!("Waiting for messages...");
-        match redis.get_message().await {
-            Ok((client_id, message)) => {
-                match ProcessParams::from_api_message(message, client_id.clone()) {
-                    Ok(params) => {
-                        engine.process(params).await;
-                    }
-                    Err(e) => {
-                        eprintln!("Failed to parse message: {}", e);
-                        continue;
-                    }
-                }
-            }
+    let snapshot = redis.load_snapshot().await.and_then(|snapshot| {
+        snapshot
+            .map(|snapshot| serde_json::from_str::<EngineSnapshot>(&snapshot))
+            .transpose()
+            .map_err(Into::into)
+    });
+    let mut engine = match snapshot {
+        Ok(Some(snapshot)) => {
+            info!(
+                taken_at = snapshot.taken_at,
+                "Restoring state as of journal entry {:?}", snapshot.journal_id
+            );
+            Engine::restore(outputs.clone(), snapshot)
+        }
+        Ok(None) => Engine::new(outputs.clone()),
+        Err(e) => {
+            // Starting empty would silently drop every balance and resting order
+            error!("Failed to load snapshot: {}", e);
+            std::process::exit(1);
+        }
+    };
+    if std::env::var("ENGINE_AUDIT").is_ok_and(|v| v == "1" || v == "true") {
+        info!("Balance audit enabled");
+        engine.enable_audit();
+    }
+    journal::spawn_sequencer(instance_id.clone(), leader.clone());
+    let shutdown = engine::shutdown::listen();
+
+    info!(instance_id, "Engine started");
+
+    // Every instance applies the whole journal; the leader alone appends to it and
+    // publishes what comes out. Entries are processed to completion, so the loop
+    // only stops in between them.
+    let mut position = engine.journal_id().unwrap_or_default();
+    let mut since_snapshot = 0;
+    while !shutdown.is_finished() {
+        debug!("Waiting for journal entries after {}", position);
+        let entries = match redis.read_journal(position).await {
+            Ok(entries) => entries,
             Err(e) => {
-                eprintln!("Failed to get message: {}", e);
-                std::thread::sleep(std::time::Duration::from_secs(1));
+                error!("Failed to read journal: {}", e);
+                tokio::time::sleep(std::time::Duration::from_secs(1)).await;
                 continue;
             }
+        };
+        for entry in entries {
+            position = entry.id;
+            since_snapshot += 1;
+            let request = match entry.parse() {
+                Ok(request) => request,
+                Err(e) => {
+                    error!("Failed to parse journal entry {}: {}", entry.id, e);
+                    outputs.end_entry(entry.id);
+                    continue;
+                }
+            };
+            let client_id = request.client_id;
+            let params = match request.message {
+                IncomingMessage::Api(message) => {
+                    ProcessParams::from_api_message(message, client_id)
+                }
+                IncomingMessage::Admin(command) => {
+                    ProcessParams::from_admin_command(command, client_id)
+                }
+            };
+            match params {
+                Ok(params) => engine.process(
+                    params
+                        .with_correlation_id(request.correlation_id)
+                        .with_journal_id(entry.id),
+                ),
+                Err(e) => error!(
+                    correlation_id = request.correlation_id.as_deref(),
+                    "Failed to parse message: {}", e
+                ),
+            }
+            outputs.end_entry(entry.id);
+        }
+        if since_snapshot >= SNAPSHOT_INTERVAL && *leader.borrow() {
+            save_snapshot(&engine, &outputs, position);
+            since_snapshot = 0;
         }
     }
+
+    info!("Stopped reading the journal at {}", position);
+    let leading = *leader.borrow();
+    if leading && since_snapshot > 0 {
+        save_snapshot(&engine, &outputs, position);
+    }
+    // Dropping every sender closes the output channel so the publisher can finish
+    drop(engine);
+    drop(outputs);
+    publisher.flush().await;
+    if leading {
+        // Lets a standby take over now instead of when the lock expires
+        if let Err(e) = redis.release_leadership(&instance_id).await {
+            error!("Failed to release leadership: {}", e);
+        }
+    }
+    info!("Engine stopped");
+}
+
+// The publisher saves it once the outputs of every entry up to `position` are out.
+fn save_snapshot(engine: &Engine, outputs: &OutputSender, position: JournalId) {
+    let mut snapshot = engine.snapshot();
+    // Entries the engine skipped as unreadable changed nothing, so the snapshot
+    // covers them too
+    snapshot.journal_id = Some(position);
+    match serde_json::to_string(&snapshot) {
+        Ok(snapshot) => outputs.save_snapshot(position, snapshot),
+        Err(e) => error!("Failed to serialize snapshot: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use engine::types::{CreateOrderPayload, MessageFromApi, Side, Traced};
+    use publisher::Output;
+
+    #[test]
+    fn final_snapshot_follows_the_outputs_of_the_last_entry() {
+        let (outputs, mut outputs_rx) = OutputSender::channel();
+        let mut engine = Engine::new(outputs.clone());
+        let processed = JournalId {
+            millis: 1_700_000_000_000,
+            seq: 2,
+        };
+        let params = ProcessParams::from_api_message(
+            MessageFromApi::CreateOrder(CreateOrderPayload {
+                market: "TATA_INR".to_string(),
+                price: "10".to_string(),
+                quantity: "2".to_string(),
+                side: Side::Sell,
+                user_id: "1".to_string(),
+            }),
+            "client".to_string(),
+        )
+        .unwrap();
+        engine.process(params.with_journal_id(processed));
+        outputs.end_entry(processed);
+        // The next entry could not be read, so it changed nothing
+        let position = JournalId {
+            seq: 3,
+            ..processed
+        };
+        outputs.end_entry(position);
+        save_snapshot(&engine, &outputs, position);
+
+        let mut received = Vec::new();
+        while let Ok(Traced { message, .. }) = outputs_rx.try_recv() {
+            received.push(message);
+        }
+        let Some(Output::Snapshot(id, saved)) = received.pop() else {
+            panic!("the snapshot should come last");
+        };
+        assert_eq!(id, position);
+        assert!(matches!(received.last(), Some(Output::EntryDone(done)) if *done == position));
+
+        let saved: EngineSnapshot = serde_json::from_str(&saved).unwrap();
+        assert_eq!(saved.journal_id, Some(position));
+        let restored = Engine::restore(OutputSender::channel().0, saved);
+        assert_eq!(restored.journal_id(), Some(position));
+        let mut expected = engine.snapshot();
+        expected.journal_id = Some(position);
+        assert_eq!(
+            serde_json::to_value(restored.snapshot()).unwrap(),
+            serde_json::to_value(expected).unwrap()
+        );
+    }
 }
@@ -0,0 +1,32 @@
+use crate::redis_manager::{RedisManager, JOURNAL};
+use engine::streams::{ADMIN_STREAM, API_STREAM, DB_DEAD_LETTER_STREAM, DB_STREAM};
+use metrics::gauge;
+use std::time::Duration;
+use tracing::warn;
+
+const QUEUE_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+// Backlogs are sampled rather than tracked, since producers and consumers of
+// these streams live in different services. Entries are deleted once acknowledged,
+// so the input streams only hold what is still to be handled.
+pub fn spawn_queue_monitor() {
+    tokio::spawn(async {
+        let redis = RedisManager::get_instance().await;
+        let mut interval = tokio::time::interval(QUEUE_POLL_INTERVAL);
+        loop {
+            interval.tick().await;
+            for queue in [
+                API_STREAM,
+                ADMIN_STREAM,
+                DB_STREAM,
+                DB_DEAD_LETTER_STREAM,
+                JOURNAL,
+            ] {
+                match redis.stream_length(queue).await {
+                    Ok(length) => gauge!("redis_queue_length", "queue" => queue).set(length as f64),
+                    Err(e) => warn!("Failed to read length of {}: {}", queue, e),
+                }
+            }
+        }
+    });
+}
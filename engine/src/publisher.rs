@@ -0,0 +1,431 @@
+use crate::journal::JournalId;
+use crate::redis_manager::RedisManager;
+use engine::types::{DbMessage, MessageToApi, Traced, WsMessage};
+use metrics::{counter, gauge};
+use std::collections::VecDeque;
+use std::time::Duration;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tracing::{error, info, warn};
+
+// Upper bound on how many outputs are flushed in a single Redis round trip. Whole
+// journal entries always go together, so a batch can run over it by one entry.
+const MAX_BATCH_SIZE: usize = 512;
+// How often a standby checks how far the leader has published, and how soon a
+// leader retries after a failed write
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+pub enum Output {
+    Db(DbMessage),
+    Api(String, MessageToApi),
+    Ws(String, WsMessage),
+    // Follows the last output of a journal entry
+    EntryDone(JournalId),
+    // Engine state as of an entry, saved once the outputs up to it are out
+    Snapshot(JournalId, String),
+}
+
+// Handle the engine uses to emit outputs without waiting on Redis. Everything goes
+// through a single FIFO channel drained by one publisher task, so the relative
+// order of everything published for a market is preserved. Outputs are tagged
+// with the correlation id of the request being processed.
+#[derive(Clone)]
+pub struct OutputSender {
+    outputs: UnboundedSender<Traced<Output>>,
+    correlation_id: Option<String>,
+}
+
+impl OutputSender {
+    pub fn channel() -> (Self, UnboundedReceiver<Traced<Output>>) {
+        let (outputs, outputs_rx) = mpsc::unbounded_channel();
+        let sender = Self {
+            outputs,
+            correlation_id: None,
+        };
+        (sender, outputs_rx)
+    }
+
+    pub fn set_correlation_id(&mut self, correlation_id: Option<String>) {
+        self.correlation_id = correlation_id;
+    }
+
+    fn send(&self, output: Output) {
+        let traced = Traced {
+            message: output,
+            correlation_id: self.correlation_id.clone(),
+        };
+        if self.outputs.send(traced).is_err() {
+            warn!("Publisher has stopped, dropping output");
+        }
+    }
+
+    pub fn push_message(&self, message: DbMessage) {
+        self.send(Output::Db(message));
+    }
+
+    pub fn send_to_api(&self, client_id: String, message: MessageToApi) {
+        self.send(Output::Api(client_id, message));
+    }
+
+    pub fn publish_message(&self, channel: String, message: WsMessage) {
+        self.send(Output::Ws(channel, message));
+    }
+
+    pub fn end_entry(&self, id: JournalId) {
+        self.send(Output::EntryDone(id));
+    }
+
+    pub fn save_snapshot(&self, id: JournalId, snapshot: String) {
+        self.send(Output::Snapshot(id, snapshot));
+    }
+}
+
+// The publisher task runs until every OutputSender is dropped and the channel is
+// drained.
+pub struct Publisher {
+    task: JoinHandle<()>,
+}
+
+impl Publisher {
+    // Waits until everything already queued has been handed to Redis, if this
+    // instance still leads.
+    pub async fn flush(self) {
+        if let Err(e) = self.task.await {
+            error!("Publisher task failed: {:?}", e);
+        }
+    }
+}
+
+pub fn spawn_publisher(
+    instance_id: String,
+    leader: watch::Receiver<bool>,
+) -> (OutputSender, Publisher) {
+    let (sender, outputs_rx) = OutputSender::channel();
+    let task = tokio::spawn(run_publisher(outputs_rx, instance_id, leader));
+    (sender, Publisher { task })
+}
+
+// Outputs of one journal entry, or a snapshot taken after it.
+struct Entry {
+    id: JournalId,
+    outputs: Vec<Traced<Output>>,
+    snapshot: Option<String>,
+}
+
+// Every instance processes every entry, but only the leader publishes. A standby
+// holds on to its outputs until the leader's published mark passes them, so when it
+// takes over it publishes exactly what the old leader had not.
+async fn run_publisher(
+    mut rx: UnboundedReceiver<Traced<Output>>,
+    instance_id: String,
+    mut leader: watch::Receiver<bool>,
+) {
+    let redis = RedisManager::get_instance().await;
+    let mut in_progress: Vec<Traced<Output>> = Vec::new();
+    let mut ready: VecDeque<Entry> = VecDeque::new();
+    let mut published: Option<JournalId> = None;
+    let mut was_leading = false;
+    let mut open = true;
+    loop {
+        let leading = *leader.borrow_and_update();
+        if !leading || !was_leading {
+            match redis.published_up_to().await {
+                Ok(up_to) => published = up_to,
+                Err(e) => warn!("Failed to read the published mark: {}", e),
+            }
+        }
+        if leading && !was_leading {
+            info!("Publishing outputs after {:?}", published);
+        }
+        was_leading = leading;
+        // A leader still saves snapshots of entries it caught up on after taking over
+        ready.retain(|entry| {
+            published.is_none_or(|up_to| entry.id > up_to) || (leading && entry.snapshot.is_some())
+        });
+        if leading {
+            publish_ready(redis, &instance_id, &mut ready, &mut published).await;
+        }
+        gauge!("engine_unpublished_entries").set(ready.len() as f64);
+        if !open {
+            break;
+        }
+
+        tokio::select! {
+            received = rx.recv() => match received {
+                Some(traced) => {
+                    receive(traced, &mut in_progress, &mut ready);
+                    while let Ok(traced) = rx.try_recv() {
+                        receive(traced, &mut in_progress, &mut ready);
+                    }
+                }
+                None => open = false,
+            },
+            _ = leader.changed() => {}
+            _ = tokio::time::sleep(POLL_INTERVAL), if !leading || !ready.is_empty() => {}
+        }
+    }
+    if !ready.is_empty() {
+        warn!("{} journal entries left unpublished", ready.len());
+    }
+}
+
+fn receive(
+    traced: Traced<Output>,
+    in_progress: &mut Vec<Traced<Output>>,
+    ready: &mut VecDeque<Entry>,
+) {
+    match traced.message {
+        Output::EntryDone(id) => ready.push_back(Entry {
+            id,
+            outputs: std::mem::take(in_progress),
+            snapshot: None,
+        }),
+        Output::Snapshot(id, snapshot) => ready.push_back(Entry {
+            id,
+            outputs: Vec::new(),
+            snapshot: Some(snapshot),
+        }),
+        _ => in_progress.push(traced),
+    }
+}
+
+async fn publish_ready(
+    redis: &RedisManager,
+    instance_id: &str,
+    ready: &mut VecDeque<Entry>,
+    published: &mut Option<JournalId>,
+) {
+    while !ready.is_empty() {
+        let batch: Vec<Entry> = ready.drain(..batch_entries(ready)).collect();
+        // Never moves the published mark back, even for a snapshot of an older entry
+        let up_to = published.map_or(batch[batch.len() - 1].id, |published| {
+            published.max(batch[batch.len() - 1].id)
+        });
+        let payloads = serialize(&batch);
+        match redis
+            .publish_outputs(
+                instance_id,
+                up_to,
+                payloads.db,
+                payloads.replies,
+                payloads.messages,
+            )
+            .await
+        {
+            Ok(true) => *published = Some(up_to),
+            Ok(false) => {
+                warn!("No longer the leader, holding back outputs");
+                requeue(ready, batch);
+                return;
+            }
+            Err(e) => {
+                error!("Failed to publish outputs to Redis: {:?}", e);
+                counter!("engine_redis_publish_failures_total", "target" => "outputs").increment(1);
+                requeue(ready, batch);
+                return;
+            }
+        }
+        let snapshot = batch
+            .into_iter()
+            .find_map(|entry| Some((entry.id, entry.snapshot?)));
+        if let Some((id, snapshot)) = snapshot {
+            match redis.save_snapshot(instance_id, id, snapshot).await {
+                Ok(1) => info!("Saved snapshot at {}", id),
+                Ok(0) => warn!("No longer the leader, snapshot at {} skipped", id),
+                Ok(_) => info!("A newer snapshot is already saved, skipped {}", id),
+                Err(e) => error!("Failed to save snapshot at {}: {:?}", id, e),
+            }
+        }
+    }
+}
+
+// How many entries from the front of `ready` go out in the next batch.
+fn batch_entries(ready: &VecDeque<Entry>) -> usize {
+    let mut batch_len = 0;
+    let mut entries = 0;
+    for entry in ready.iter() {
+        if entries > 0 && batch_len >= MAX_BATCH_SIZE {
+            break;
+        }
+        batch_len += entry.outputs.len();
+        entries += 1;
+        // A snapshot is saved only once everything up to it is out
+        if entry.snapshot.is_some() {
+            break;
+        }
+    }
+    entries
+}
+
+fn requeue(ready: &mut VecDeque<Entry>, batch: Vec<Entry>) {
+    for entry in batch.into_iter().rev() {
+        ready.push_front(entry);
+    }
+}
+
+#[derive(Default)]
+struct Payloads {
+    db: Vec<String>,
+    // Client id and reply
+    replies: Vec<(String, String)>,
+    // Channel and message
+    messages: Vec<(String, String)>,
+}
+
+// DB messages and market data carry the correlation id for the services that log
+// them. API replies go out as they are, since the API already knows which request
+// they answer.
+fn serialize(batch: &[Entry]) -> Payloads {
+    let mut payloads = Payloads::default();
+    for traced in batch.iter().flat_map(|entry| &entry.outputs) {
+        let correlation_id = traced.correlation_id.clone();
+        let result = match &traced.message {
+            Output::Db(message) => serde_json::to_string(&Traced {
+                message,
+                correlation_id,
+            })
+            .map(|payload| payloads.db.push(payload)),
+            Output::Api(client_id, message) => serde_json::to_string(message)
+                .map(|payload| payloads.replies.push((client_id.clone(), payload))),
+            Output::Ws(channel, message) => serde_json::to_string(&Traced {
+                message,
+                correlation_id,
+            })
+            .map(|payload| payloads.messages.push((channel.clone(), payload))),
+            Output::EntryDone(_) | Output::Snapshot(..) => Ok(()),
+        };
+        if let Err(e) = result {
+            error!("Failed to serialize output: {:?}", e);
+        }
+    }
+    payloads
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use engine::types::{DbMessageData, DbMessageType, OrderCancelled};
+
+    fn id(millis: u64) -> JournalId {
+        JournalId { millis, seq: 0 }
+    }
+
+    fn traced(message: Output, correlation_id: &str) -> Traced<Output> {
+        Traced {
+            message,
+            correlation_id: Some(correlation_id.to_string()),
+        }
+    }
+
+    fn reply(client_id: &str) -> Output {
+        Output::Api(client_id.to_string(), MessageToApi::OpenOrders(vec![]))
+    }
+
+    fn entry(millis: u64, outputs: usize) -> Entry {
+        Entry {
+            id: id(millis),
+            outputs: (0..outputs)
+                .map(|i| traced(reply(&i.to_string()), "c"))
+                .collect(),
+            snapshot: None,
+        }
+    }
+
+    #[test]
+    fn outputs_are_grouped_by_the_entry_that_produced_them() {
+        let mut in_progress = Vec::new();
+        let mut ready = VecDeque::new();
+        for output in [
+            traced(reply("a"), "1"),
+            traced(reply("b"), "1"),
+            traced(Output::EntryDone(id(1)), "1"),
+            traced(Output::EntryDone(id(2)), "2"),
+            traced(Output::Snapshot(id(2), "{}".to_string()), "2"),
+            traced(reply("c"), "3"),
+        ] {
+            receive(output, &mut in_progress, &mut ready);
+        }
+
+        let entries: Vec<_> = ready
+            .iter()
+            .map(|entry| (entry.id, entry.outputs.len(), entry.snapshot.is_some()))
+            .collect();
+        assert_eq!(
+            entries,
+            [(id(1), 2, false), (id(2), 0, false), (id(2), 0, true)]
+        );
+        // Outputs of an entry still being processed are held back
+        assert_eq!(in_progress.len(), 1);
+    }
+
+    #[test]
+    fn batches_take_whole_entries_up_to_the_size_limit() {
+        let ready: VecDeque<Entry> = [entry(1, 300), entry(2, 300), entry(3, 1)].into();
+        assert_eq!(batch_entries(&ready), 2);
+
+        // An entry larger than the limit still goes out on its own
+        let ready: VecDeque<Entry> = [entry(1, MAX_BATCH_SIZE * 2), entry(2, 1)].into();
+        assert_eq!(batch_entries(&ready), 1);
+
+        let ready: VecDeque<Entry> = (1..=10).map(|millis| entry(millis, 1)).collect();
+        assert_eq!(batch_entries(&ready), 10);
+    }
+
+    #[test]
+    fn batches_end_at_a_snapshot() {
+        let mut snapshot = entry(2, 0);
+        snapshot.snapshot = Some("{}".to_string());
+        let ready: VecDeque<Entry> = [entry(1, 3), snapshot, entry(3, 3)].into();
+        assert_eq!(batch_entries(&ready), 2);
+    }
+
+    #[test]
+    fn requeued_batches_go_back_in_front_in_order() {
+        let mut ready: VecDeque<Entry> = (1..=5).map(|millis| entry(millis, 1)).collect();
+        let batch: Vec<Entry> = ready.drain(..3).collect();
+        requeue(&mut ready, batch);
+        let ids: Vec<JournalId> = ready.iter().map(|entry| entry.id).collect();
+        assert_eq!(ids, (1..=5).map(id).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn serialize_keeps_output_order_and_correlation_ids() {
+        let cancelled = DbMessage {
+            db_message_type: DbMessageType::OrderCancelled,
+            data: DbMessageData::OrderCancelled(OrderCancelled {
+                order_id: "o1".to_string(),
+                market: "TATA_INR".to_string(),
+                executed_quantity: 0,
+                remaining_quantity: 5,
+                timestamp: "0".to_string(),
+            }),
+        };
+        let batch = [
+            Entry {
+                id: id(1),
+                outputs: vec![traced(reply("a"), "1"), traced(Output::Db(cancelled), "1")],
+                snapshot: None,
+            },
+            Entry {
+                id: id(2),
+                outputs: vec![traced(reply("b"), "2")],
+                snapshot: None,
+            },
+        ];
+
+        let payloads = serialize(&batch);
+        let clients: Vec<&str> = payloads
+            .replies
+            .iter()
+            .map(|(client_id, _)| client_id.as_str())
+            .collect();
+        assert_eq!(clients, ["a", "b"]);
+        // Replies go out untagged, DB messages carry the correlation id
+        assert!(!payloads.replies[0].1.contains("correlation_id"));
+        assert_eq!(payloads.db.len(), 1);
+        let stored: Traced<DbMessage> = serde_json::from_str(&payloads.db[0]).unwrap();
+        assert_eq!(stored.correlation_id.as_deref(), Some("1"));
+        assert!(payloads.messages.is_empty());
+    }
+}
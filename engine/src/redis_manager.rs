@@ -1,13 +1,113 @@
-use engine::types::{DbMessage, MessageFromApi, MessageToApi, WsMessage};
+use crate::journal::{JournalEntry, JournalId, QueuedMessage};
+use engine::streams::{self, ADMIN_STREAM, API_STREAM, DB_STREAM, ENGINE_GROUP, PAYLOAD};
+use redis::streams::{StreamReadOptions, StreamReadReply};
 use redis::Client;
-use redis::{aio::Connection, AsyncCommands};
+use redis::{aio::Connection, AsyncCommands, Script};
+use std::time::Duration;
 use std::{env, error::Error};
 use tokio::sync::{Mutex, OnceCell};
 
+// Every message the engine applies, in order. Replicas read it; only the leader
+// appends to it.
+pub const JOURNAL: &str = "engine_journal";
+// Instance id of the current leader, kept alive by its TTL
+const LEADER_KEY: &str = "engine_leader";
+// Journal id of the last entry whose outputs have been published
+const PUBLISHED_KEY: &str = "engine_published";
+const SNAPSHOT_KEY: &str = "engine_snapshot";
+const SNAPSHOT_POSITION_KEY: &str = "engine_snapshot_position";
+// How long a blocking read waits before giving the caller a chance to shut down
+const POLL_TIMEOUT_SECS: usize = 1;
+const JOURNAL_BATCH_SIZE: usize = 256;
+const REQUEST_BATCH_SIZE: usize = 64;
+// Requests are read in this order, so admin commands are served ahead of queued
+// user traffic
+const REQUEST_STREAMS: [&str; 2] = [ADMIN_STREAM, API_STREAM];
+
+const RENEW_LEADERSHIP: &str = r#"
+if redis.call('GET', KEYS[1]) == ARGV[1] then
+    return redis.call('PEXPIRE', KEYS[1], ARGV[2])
+end
+return 0
+"#;
+
+const RELEASE_LEADERSHIP: &str = r#"
+if redis.call('GET', KEYS[1]) == ARGV[1] then
+    return redis.call('DEL', KEYS[1])
+end
+return 0
+"#;
+
+// A request moves into the journal and is acknowledged on its input stream in one
+// step, so a crash either side of it neither loses nor repeats it. The id it had on
+// the input stream is kept as `source`.
+const JOURNAL_REQUEST: &str = r#"
+if redis.call('GET', KEYS[1]) ~= ARGV[1] then
+    return false
+end
+local id = redis.call('XADD', KEYS[2], '*', 'queue', KEYS[3], 'payload', ARGV[4], 'source', ARGV[3])
+redis.call('XACK', KEYS[3], ARGV[2], ARGV[3])
+redis.call('XDEL', KEYS[3], ARGV[3])
+return id
+"#;
+
+// Outputs and the published mark move together, and only while the caller still
+// holds the leader key, so a deposed leader can never publish twice what its
+// successor publishes. Replies go on per-client streams that expire if nobody
+// reads them.
+const PUBLISH_OUTPUTS: &str = r#"
+if redis.call('GET', KEYS[1]) ~= ARGV[1] then
+    return 0
+end
+local db_count = tonumber(ARGV[3])
+local reply_count = tonumber(ARGV[4])
+local ttl = ARGV[5]
+local first_reply = 6 + db_count
+local first_message = first_reply + 2 * reply_count
+for i = 6, first_reply - 1 do
+    redis.call('XADD', KEYS[3], '*', 'payload', ARGV[i])
+end
+for i = first_reply, first_message - 1, 2 do
+    redis.call('XADD', ARGV[i], '*', 'payload', ARGV[i + 1])
+    redis.call('PEXPIRE', ARGV[i], ttl)
+end
+for i = first_message, #ARGV, 2 do
+    redis.call('PUBLISH', ARGV[i], ARGV[i + 1])
+end
+redis.call('SET', KEYS[2], ARGV[2])
+return 1
+"#;
+
+// Trims the journal up to the previous snapshot rather than this one, leaving a
+// replica that is a little behind the entries it has yet to read. A snapshot older
+// than the saved one is refused, since the journal before it may be gone.
+const SAVE_SNAPSHOT: &str = r#"
+if redis.call('GET', KEYS[1]) ~= ARGV[1] then
+    return 0
+end
+local previous = redis.call('GET', KEYS[3])
+if previous then
+    local new_ms, new_seq = string.match(ARGV[2], '(%d+)-(%d+)')
+    local old_ms, old_seq = string.match(previous, '(%d+)-(%d+)')
+    new_ms, new_seq = tonumber(new_ms), tonumber(new_seq)
+    old_ms, old_seq = tonumber(old_ms), tonumber(old_seq)
+    if new_ms < old_ms or (new_ms == old_ms and new_seq <= old_seq) then
+        return -1
+    end
+end
+redis.call('SET', KEYS[2], ARGV[3])
+redis.call('SET', KEYS[3], ARGV[2])
+if previous then
+    redis.call('XTRIM', KEYS[4], 'MINID', previous)
+end
+return 1
+"#;
+
 pub static REDIS_MANAGER: OnceCell<RedisManager> = OnceCell::const_new();
 
 pub struct RedisManager {
     pub reciever: Mutex<Connection>,
+    pub sequencer: Mutex<Connection>,
     pub writer: Mutex<Connection>,
     pub publisher: Mutex<Connection>,
 }
@@ -18,6 +118,7 @@ impl RedisManager {
         let client = Client::open(redis_url.clone())?;
         Ok(RedisManager {
             reciever: Mutex::new(client.get_async_connection().await?),
+            sequencer: Mutex::new(client.get_async_connection().await?),
             writer: Mutex::new(client.get_async_connection().await?),
             publisher: Mutex::new(client.get_async_connection().await?),
         })
@@ -32,39 +133,222 @@ impl RedisManager {
             .await
     }
 
-    pub async fn get_message(&self) -> Result<(String, MessageFromApi), Box<dyn Error>> {
+    pub async fn create_request_groups(&self) -> Result<(), Box<dyn Error>> {
+        let mut connection = self.sequencer.lock().await;
+        for stream in REQUEST_STREAMS {
+            streams::create_group(&mut connection, stream, ENGINE_GROUP).await?;
+        }
+        Ok(())
+    }
+
+    // Requests no instance has read yet, waiting up to the poll timeout for the first.
+    pub async fn take_requests(
+        &self,
+        instance_id: &str,
+    ) -> Result<Vec<QueuedMessage>, Box<dyn Error>> {
+        let mut connection = self.sequencer.lock().await;
+        let entries = streams::read_group(
+            &mut connection,
+            &REQUEST_STREAMS,
+            ENGINE_GROUP,
+            instance_id,
+            REQUEST_BATCH_SIZE,
+            Duration::from_secs(POLL_TIMEOUT_SECS as u64),
+        )
+        .await?;
+        Ok(entries
+            .into_iter()
+            .map(|(queue, entry)| QueuedMessage {
+                payload: entry.get(PAYLOAD).unwrap_or_default(),
+                id: entry.id,
+                queue,
+            })
+            .collect())
+    }
+
+    // Requests read by any instance, this one included, that have waited at least
+    // `min_idle` without making it into the journal.
+    pub async fn claim_requests(
+        &self,
+        instance_id: &str,
+        min_idle: Duration,
+    ) -> Result<Vec<QueuedMessage>, Box<dyn Error>> {
+        let mut connection = self.sequencer.lock().await;
+        let mut claimed = Vec::new();
+        for queue in REQUEST_STREAMS {
+            let entries =
+                streams::claim_idle(&mut connection, queue, ENGINE_GROUP, instance_id, min_idle)
+                    .await?;
+            claimed.extend(entries.into_iter().map(|entry| QueuedMessage {
+                payload: entry.get(PAYLOAD).unwrap_or_default(),
+                id: entry.id,
+                queue: queue.to_string(),
+            }));
+        }
+        Ok(claimed)
+    }
+
+    // Returns None, journaling nothing, when `instance_id` no longer leads.
+    pub async fn append_to_journal(
+        &self,
+        instance_id: &str,
+        message: &QueuedMessage,
+    ) -> Result<Option<JournalId>, Box<dyn Error>> {
+        let mut connection = self.writer.lock().await;
+        let id: Option<String> = Script::new(JOURNAL_REQUEST)
+            .key(LEADER_KEY)
+            .key(JOURNAL)
+            .key(&message.queue)
+            .arg(instance_id)
+            .arg(ENGINE_GROUP)
+            .arg(&message.id)
+            .arg(&message.payload)
+            .invoke_async(&mut *connection)
+            .await?;
+        Ok(id.map(|id| id.parse()).transpose()?)
+    }
+
+    // Entries after `after`, waiting up to the poll timeout for the first one.
+    pub async fn read_journal(
+        &self,
+        after: JournalId,
+    ) -> Result<Vec<JournalEntry>, Box<dyn Error>> {
+        let options = StreamReadOptions::default()
+            .block(POLL_TIMEOUT_SECS * 1000)
+            .count(JOURNAL_BATCH_SIZE);
         let mut connection = self.reciever.lock().await;
-        let (_, payload): (String, String) = connection.brpop("messages".to_string(), 0).await?;
-        let (client_id, message): (String, MessageFromApi) = serde_json::from_str(&payload)?;
-        Ok((client_id, message))
+        let reply: Option<StreamReadReply> = connection
+            .xread_options(&[JOURNAL], &[after.to_string()], &options)
+            .await?;
+        let mut entries = Vec::new();
+        for stream in reply.map(|reply| reply.keys).unwrap_or_default() {
+            for entry in stream.ids {
+                let (Some(queue), Some(payload)) = (entry.get("queue"), entry.get("payload"))
+                else {
+                    return Err(format!("Malformed journal entry {}", entry.id).into());
+                };
+                entries.push(JournalEntry {
+                    id: entry.id.parse()?,
+                    queue,
+                    payload,
+                });
+            }
+        }
+        Ok(entries)
+    }
+
+    pub async fn stream_length(&self, stream: &str) -> Result<u64, Box<dyn Error>> {
+        let mut connection = self.writer.lock().await;
+        Ok(connection.xlen(stream).await?)
+    }
+
+    pub async fn try_acquire_leadership(
+        &self,
+        instance_id: &str,
+        ttl: Duration,
+    ) -> Result<bool, Box<dyn Error>> {
+        let mut connection = self.writer.lock().await;
+        let acquired: Option<String> = redis::cmd("SET")
+            .arg(LEADER_KEY)
+            .arg(instance_id)
+            .arg("NX")
+            .arg("PX")
+            .arg(ttl.as_millis() as u64)
+            .query_async(&mut *connection)
+            .await?;
+        Ok(acquired.is_some())
+    }
+
+    pub async fn renew_leadership(
+        &self,
+        instance_id: &str,
+        ttl: Duration,
+    ) -> Result<bool, Box<dyn Error>> {
+        let mut connection = self.writer.lock().await;
+        let renewed: i64 = Script::new(RENEW_LEADERSHIP)
+            .key(LEADER_KEY)
+            .arg(instance_id)
+            .arg(ttl.as_millis() as u64)
+            .invoke_async(&mut *connection)
+            .await?;
+        Ok(renewed == 1)
     }
 
-    pub async fn push_message(&self, message: DbMessage) -> Result<(), Box<dyn Error>> {
-        let payload = serde_json::to_string(&message)?;
+    pub async fn release_leadership(&self, instance_id: &str) -> Result<(), Box<dyn Error>> {
         let mut connection = self.writer.lock().await;
-        let _: () = connection.lpush("db_processor", payload).await?;
+        let _: i64 = Script::new(RELEASE_LEADERSHIP)
+            .key(LEADER_KEY)
+            .arg(instance_id)
+            .invoke_async(&mut *connection)
+            .await?;
         Ok(())
     }
 
-    pub async fn send_to_api(
+    pub async fn published_up_to(&self) -> Result<Option<JournalId>, Box<dyn Error>> {
+        let mut connection = self.publisher.lock().await;
+        let published: Option<String> = connection.get(PUBLISHED_KEY).await?;
+        Ok(published.map(|id| id.parse()).transpose()?)
+    }
+
+    // Returns false, writing nothing, when `instance_id` no longer leads.
+    pub async fn publish_outputs(
         &self,
-        channel: String,
-        message: MessageToApi,
-    ) -> Result<(), Box<dyn Error>> {
-        let payload = serde_json::to_string(&message)?;
+        instance_id: &str,
+        up_to: JournalId,
+        db_payloads: Vec<String>,
+        replies: Vec<(String, String)>,
+        messages: Vec<(String, String)>,
+    ) -> Result<bool, Box<dyn Error>> {
+        let script = Script::new(PUBLISH_OUTPUTS);
+        let mut invocation = script.key(LEADER_KEY);
+        invocation
+            .key(PUBLISHED_KEY)
+            .key(DB_STREAM)
+            .arg(instance_id)
+            .arg(up_to.to_string())
+            .arg(db_payloads.len())
+            .arg(replies.len())
+            .arg(streams::REPLY_TTL_MS);
+        for payload in db_payloads {
+            invocation.arg(payload);
+        }
+        for (client_id, payload) in replies {
+            invocation
+                .arg(streams::reply_stream(&client_id))
+                .arg(payload);
+        }
+        for (channel, payload) in messages {
+            invocation.arg(channel).arg(payload);
+        }
         let mut connection = self.publisher.lock().await;
-        let _: () = connection.publish(channel, payload).await?;
-        Ok(())
+        let published: i64 = invocation.invoke_async(&mut *connection).await?;
+        Ok(published == 1)
     }
 
-    pub async fn publish_message(
+    pub async fn load_snapshot(&self) -> Result<Option<String>, Box<dyn Error>> {
+        let mut connection = self.writer.lock().await;
+        Ok(connection.get(SNAPSHOT_KEY).await?)
+    }
+
+    // Returns 1 once saved, 0 when `instance_id` no longer leads and -1 when a newer
+    // snapshot is already saved.
+    pub async fn save_snapshot(
         &self,
-        channel: String,
-        message: WsMessage,
-    ) -> Result<(), Box<dyn Error>> {
-        let payload = serde_json::to_string(&message)?;
+        instance_id: &str,
+        position: JournalId,
+        snapshot: String,
+    ) -> Result<i64, Box<dyn Error>> {
         let mut connection = self.publisher.lock().await;
-        let _: () = connection.publish(channel, payload).await?;
-        Ok(())
+        let saved: i64 = Script::new(SAVE_SNAPSHOT)
+            .key(LEADER_KEY)
+            .key(SNAPSHOT_KEY)
+            .key(SNAPSHOT_POSITION_KEY)
+            .key(JOURNAL)
+            .arg(instance_id)
+            .arg(position.to_string())
+            .arg(snapshot)
+            .invoke_async(&mut *connection)
+            .await?;
+        Ok(saved)
     }
 }
@@ -0,0 +1,27 @@
+use tokio::task::JoinHandle;
+use tracing::info;
+
+// Resolves on the first SIGTERM or Ctrl-C.
+pub async fn signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate =
+            signal(SignalKind::terminate()).expect("Failed to install SIGTERM handler");
+        tokio::select! {
+            _ = terminate.recv() => info!("Received SIGTERM, shutting down"),
+            _ = tokio::signal::ctrl_c() => info!("Received Ctrl-C, shutting down"),
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+        info!("Received Ctrl-C, shutting down");
+    }
+}
+
+// Services poll the returned handle with `is_finished` between units of work, so
+// a signal never interrupts a message halfway through.
+pub fn listen() -> JoinHandle<()> {
+    tokio::spawn(signal())
+}
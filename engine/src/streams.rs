@@ -0,0 +1,221 @@
+use std::time::Duration;
+
+use redis::aio::Connection;
+use redis::streams::{
+    StreamId, StreamPendingCountReply, StreamRangeReply, StreamReadOptions, StreamReadReply,
+};
+use redis::{AsyncCommands, RedisResult, Value};
+
+// Requests for the engine. The API writes to `messages`; only exchange-admin writes
+// to `admin_messages`, and Redis ACLs should keep every other client away from it.
+pub const API_STREAM: &str = "messages";
+pub const ADMIN_STREAM: &str = "admin_messages";
+// Engine outputs for the database service
+pub const DB_STREAM: &str = "db_processor";
+// Database messages that could not be stored, kept for an operator to look at
+pub const DB_DEAD_LETTER_STREAM: &str = "db_processor_dead";
+pub const ENGINE_GROUP: &str = "engine";
+pub const DB_GROUP: &str = "db";
+// Field every entry keeps its message in
+pub const PAYLOAD: &str = "payload";
+// Fields a dead-lettered entry carries next to its payload
+pub const SOURCE_ID: &str = "source_id";
+pub const REASON: &str = "reason";
+// How long a reply nobody has read is kept around
+pub const REPLY_TTL_MS: u64 = 60_000;
+
+// The engine answers a request on a stream named after the request's client id, so
+// a reply written before the caller starts reading is still there when it does.
+pub fn reply_stream(client_id: &str) -> String {
+    format!("reply:{}", client_id)
+}
+
+// Appends a message and returns the id Redis gave it. Ids only ever grow within a
+// stream, so they double as sequence numbers.
+pub async fn push(connection: &mut Connection, stream: &str, payload: &str) -> RedisResult<String> {
+    connection.xadd(stream, "*", &[(PAYLOAD, payload)]).await
+}
+
+// Creates the group at the start of the stream, so entries added before any
+// consumer existed are delivered too. An existing group is left as it is.
+pub async fn create_group(
+    connection: &mut Connection,
+    stream: &str,
+    group: &str,
+) -> RedisResult<()> {
+    match connection
+        .xgroup_create_mkstream::<_, _, _, ()>(stream, group, "0")
+        .await
+    {
+        Err(e) if e.code() == Some("BUSYGROUP") => Ok(()),
+        result => result,
+    }
+}
+
+// New entries for the group, waiting up to `block` for the first one. Each is
+// tagged with the stream it came from.
+pub async fn read_group(
+    connection: &mut Connection,
+    streams: &[&str],
+    group: &str,
+    consumer: &str,
+    count: usize,
+    block: Duration,
+) -> RedisResult<Vec<(String, StreamId)>> {
+    let options = StreamReadOptions::default()
+        .group(group, consumer)
+        .count(count)
+        .block(block.as_millis() as usize);
+    let ids = vec![">"; streams.len()];
+    let reply: Option<StreamReadReply> = connection.xread_options(streams, &ids, &options).await?;
+    Ok(flatten(reply))
+}
+
+// Every entry already delivered to `consumer` but never acknowledged, such as the
+// ones it was working on when it last stopped.
+pub async fn read_pending(
+    connection: &mut Connection,
+    streams: &[&str],
+    group: &str,
+    consumer: &str,
+) -> RedisResult<Vec<(String, StreamId)>> {
+    let options = StreamReadOptions::default().group(group, consumer);
+    let ids = vec!["0"; streams.len()];
+    let reply: Option<StreamReadReply> = connection.xread_options(streams, &ids, &options).await?;
+    Ok(flatten(reply))
+}
+
+// Moves every entry that has sat unacknowledged for at least `min_idle`, whichever
+// consumer holds it, over to `consumer` and returns them.
+pub async fn claim_idle(
+    connection: &mut Connection,
+    stream: &str,
+    group: &str,
+    consumer: &str,
+    min_idle: Duration,
+) -> RedisResult<Vec<StreamId>> {
+    let mut claimed = Vec::new();
+    let mut cursor = "0-0".to_string();
+    loop {
+        // Redis 7 appends the ids of deleted entries, which nothing here needs
+        let reply: Vec<Value> = redis::cmd("XAUTOCLAIM")
+            .arg(stream)
+            .arg(group)
+            .arg(consumer)
+            .arg(min_idle.as_millis() as u64)
+            .arg(&cursor)
+            .arg("COUNT")
+            .arg(100)
+            .query_async(connection)
+            .await?;
+        let (Some(next), Some(entries)) = (reply.first(), reply.get(1)) else {
+            return Err((redis::ErrorKind::TypeError, "Unexpected XAUTOCLAIM reply").into());
+        };
+        cursor = redis::from_redis_value(next)?;
+        let entries: StreamRangeReply = redis::from_redis_value(entries)?;
+        claimed.extend(entries.ids);
+        if cursor == "0-0" {
+            return Ok(claimed);
+        }
+    }
+}
+
+// Acknowledges an entry and removes it, so the stream only ever holds work that
+// is still to be done.
+pub async fn ack(
+    connection: &mut Connection,
+    stream: &str,
+    group: &str,
+    id: &str,
+) -> RedisResult<()> {
+    redis::pipe()
+        .atomic()
+        .xack(stream, group, &[id])
+        .ignore()
+        .xdel(stream, &[id])
+        .ignore()
+        .query_async(connection)
+        .await
+}
+
+// How many times the group has handed the entry out, the current delivery
+// included. Zero when it is no longer pending.
+pub async fn delivery_count(
+    connection: &mut Connection,
+    stream: &str,
+    group: &str,
+    id: &str,
+) -> RedisResult<usize> {
+    let reply: StreamPendingCountReply =
+        connection.xpending_count(stream, group, id, id, 1).await?;
+    Ok(reply
+        .ids
+        .first()
+        .map_or(0, |pending| pending.times_delivered))
+}
+
+// Copies an entry the group gives up on to `dead_letter` with the reason, then
+// acknowledges and removes it, all in one step.
+pub async fn dead_letter(
+    connection: &mut Connection,
+    stream: &str,
+    group: &str,
+    dead_letter: &str,
+    entry: &StreamId,
+    reason: &str,
+) -> RedisResult<()> {
+    let payload: String = entry.get(PAYLOAD).unwrap_or_default();
+    redis::pipe()
+        .atomic()
+        .xadd(
+            dead_letter,
+            "*",
+            &[
+                (PAYLOAD, payload.as_str()),
+                (SOURCE_ID, entry.id.as_str()),
+                (REASON, reason),
+            ],
+        )
+        .ignore()
+        .xack(stream, group, &[&entry.id])
+        .ignore()
+        .xdel(stream, &[&entry.id])
+        .ignore()
+        .query_async(connection)
+        .await
+}
+
+// Takes the oldest reply on the stream for `client_id`, waiting up to `timeout`.
+pub async fn take_reply(
+    connection: &mut Connection,
+    client_id: &str,
+    timeout: Duration,
+) -> RedisResult<Option<String>> {
+    let stream = reply_stream(client_id);
+    let options = StreamReadOptions::default()
+        .count(1)
+        .block(timeout.as_millis() as usize);
+    let reply: Option<StreamReadReply> = connection
+        .xread_options(&[&stream], &["0"], &options)
+        .await?;
+    let Some((_, entry)) = flatten(reply).into_iter().next() else {
+        return Ok(None);
+    };
+    connection.xdel::<_, _, ()>(&stream, &[&entry.id]).await?;
+    Ok(entry.get(PAYLOAD))
+}
+
+fn flatten(reply: Option<StreamReadReply>) -> Vec<(String, StreamId)> {
+    reply
+        .map(|reply| reply.keys)
+        .unwrap_or_default()
+        .into_iter()
+        .flat_map(|stream| {
+            let key = stream.key;
+            stream
+                .ids
+                .into_iter()
+                .map(move |entry| (key.clone(), entry))
+        })
+        .collect()
+}
@@ -0,0 +1,60 @@
+use metrics_exporter_prometheus::{BuildError, PrometheusBuilder};
+use std::{env, net::SocketAddr};
+use tracing::{info, warn};
+use tracing_subscriber::EnvFilter;
+
+pub use metrics_exporter_prometheus::PrometheusHandle;
+
+// Latency buckets in seconds, from 10µs for matching up to a few seconds for
+// round trips through Redis and Postgres
+const LATENCY_BUCKETS: &[f64] = &[
+    0.00001, 0.00005, 0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25,
+    0.5, 1.0, 2.5,
+];
+
+// Logs go to stdout as one JSON object per line, carrying the fields of every
+// enclosing span such as `correlation_id`. `LOG_FORMAT=text` switches to plain
+// text for local runs, and `RUST_LOG` sets the level (info by default).
+pub fn init_tracing() {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+    if env::var("LOG_FORMAT").is_ok_and(|format| format == "text") {
+        builder.init();
+    } else {
+        builder.json().with_current_span(false).init();
+    }
+}
+
+fn prometheus_builder() -> Result<PrometheusBuilder, BuildError> {
+    PrometheusBuilder::new().set_buckets(LATENCY_BUCKETS)
+}
+
+// For services without an HTTP server of their own: serves the metrics on
+// `METRICS_ADDR`, answering /metrics (and any other path).
+pub fn serve_metrics(default_addr: &str) {
+    let addr: SocketAddr = match env::var("METRICS_ADDR")
+        .unwrap_or_else(|_| default_addr.to_string())
+        .parse()
+    {
+        Ok(addr) => addr,
+        Err(e) => {
+            warn!("Invalid METRICS_ADDR, metrics disabled: {}", e);
+            return;
+        }
+    };
+    match prometheus_builder().and_then(|builder| builder.with_http_listener(addr).install()) {
+        Ok(()) => info!("Serving metrics on {}", addr),
+        Err(e) => warn!("Failed to start metrics exporter: {}", e),
+    }
+}
+
+// For services that render the metrics from a route of their own.
+pub fn install_metrics_recorder() -> Option<PrometheusHandle> {
+    match prometheus_builder().and_then(|builder| builder.install_recorder()) {
+        Ok(handle) => Some(handle),
+        Err(e) => {
+            warn!("Failed to install metrics recorder: {}", e);
+            None
+        }
+    }
+}
@@ -0,0 +1,59 @@
+use chrono::{DateTime, TimeZone, Utc};
+use std::time::{Duration, Instant};
+
+// Engine time. Messages from the journal carry the time they were appended, and the
+// clock follows those timestamps rather than the local clock, so every replica sees
+// the same time at the same point of the journal. `Instant`s handed out are offsets
+// from when the clock was created.
+pub struct Clock {
+    anchor: Instant,
+    // Millisecond timestamp `anchor` stands for, set by the first message
+    anchor_millis: Option<i64>,
+    now_millis: i64,
+}
+
+impl Clock {
+    pub fn new() -> Self {
+        Self {
+            anchor: Instant::now(),
+            anchor_millis: None,
+            now_millis: 0,
+        }
+    }
+
+    pub fn starting_at(millis: i64) -> Self {
+        Self {
+            anchor: Instant::now(),
+            anchor_millis: Some(millis),
+            now_millis: millis,
+        }
+    }
+
+    // Never moves backwards, even if timestamps do.
+    pub fn advance_to(&mut self, millis: i64) {
+        let anchor_millis = *self.anchor_millis.get_or_insert(millis);
+        self.now_millis = self.now_millis.max(millis).max(anchor_millis);
+    }
+
+    pub fn now(&self) -> Instant {
+        match self.anchor_millis {
+            Some(anchor_millis) => {
+                self.anchor + Duration::from_millis((self.now_millis - anchor_millis) as u64)
+            }
+            None => self.anchor,
+        }
+    }
+
+    pub fn millis(&self) -> i64 {
+        match self.anchor_millis {
+            Some(_) => self.now_millis,
+            None => Utc::now().timestamp_millis(),
+        }
+    }
+
+    pub fn utc(&self) -> DateTime<Utc> {
+        Utc.timestamp_millis_opt(self.millis())
+            .single()
+            .unwrap_or_else(Utc::now)
+    }
+}
@@ -1,20 +1,40 @@
-use super::{Orderbook, OrderbookFill};
-use crate::redis_manager::RedisManager;
+use super::clock::Clock;
+use super::events::TICKER_UPDATE;
+use super::margin::{AccountValue, MarginState, INTEREST_PRECISION, VALUATION_ASSET};
+use super::risk::{OrderExposure, UserRisk};
+use super::snapshot::EngineSnapshot;
+use super::ticker::TickerSummary;
+use super::{AuctionFill, Orderbook, OrderbookFill, DEPTH_STREAM_PRECISIONS};
+use crate::journal::JournalId;
+use crate::publisher::OutputSender;
 use chrono::Utc;
 use engine::types::{
-    DbMessage, DbMessageData, DbMessageType, DepthUpdateMessage, InternalCreateOrderPayload,
-    InternalMessage, MessageFromApi, MessageToApi, Order, OrderCancelledPayload,
-    OrderPlacedPayload, OrderUpdate, Side, TradeAdd, TradeUpdateMessage, WsMessage, WsPayload,
+    AdminCommand, AdminRejectedPayload, AuctionUpdateMessage, BalanceAdjustment, BookTopPayload,
+    DbMessage, DbMessageData, DbMessageType, DepthUpdateMessage, FundingUpdateMessage,
+    InternalAdjustBalancePayload, InternalCreateOrderPayload, InternalMessage,
+    InternalTransferPayload, L3UpdateMessage, MarginAccountPayload, MarginBalance, MarketKind,
+    MarketOrders, MarketPayload, MarketStatus, MarketStatusPayload, MessageFromApi, MessageToApi,
+    Order, OrderCancelled, OrderCancelledPayload, OrderPlacedPayload, OrderRejectedPayload,
+    OrderUpdate, PositionPayload, RejectReason, RiskLimitsPayload, Side, StatusUpdateMessage,
+    SubAccountsPayload, TickerUpdateMessage, TradeAdd, TradeUpdateMessage, TransferRecord,
+    TransferRejectedPayload, UserStatePayload, WsMessage, WsPayload,
 };
+use metrics::{counter, histogram};
+use rust_decimal::prelude::ToPrimitive;
 use rust_decimal::Decimal;
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
 use std::str::FromStr;
-
-pub const BASE_CURRENCY: &str = "INR";
+use std::time::Instant;
+use tracing::{info, info_span, warn};
 
 pub struct ProcessParams {
     pub message: InternalMessage,
     pub client_id: String,
+    pub correlation_id: Option<String>,
+    // Journal entry the message was read from, which sets engine time and the ids
+    // handed out while processing it
+    pub journal_id: Option<JournalId>,
 }
 
 impl ProcessParams {
@@ -26,46 +46,205 @@ impl ProcessParams {
         Ok(ProcessParams {
             message: internal_message,
             client_id,
+            correlation_id: None,
+            journal_id: None,
+        })
+    }
+
+    pub fn from_admin_command(command: AdminCommand, client_id: String) -> Result<Self, String> {
+        Ok(ProcessParams {
+            message: InternalMessage::from_admin_command(command)?,
+            client_id,
+            correlation_id: None,
+            journal_id: None,
         })
     }
+
+    pub fn with_correlation_id(mut self, correlation_id: Option<String>) -> Self {
+        self.correlation_id = correlation_id;
+        self
+    }
+
+    pub fn with_journal_id(mut self, journal_id: JournalId) -> Self {
+        self.journal_id = Some(journal_id);
+        self
+    }
 }
 
-type UserBalance = HashMap<String, Balance>;
-struct Balance {
+pub type UserBalance = HashMap<String, Balance>;
+#[derive(Default, Clone, Serialize, Deserialize)]
+pub struct Balance {
     available: Decimal,
     locked: Decimal,
+    // Margin debt, with interest tracked apart from the principal
+    borrowed: Decimal,
+    interest: Decimal,
+    // Interest accrued below `INTEREST_PRECISION`, charged once it adds up
+    #[serde(default)]
+    interest_carry: Decimal,
+}
+
+impl Balance {
+    fn liabilities(&self) -> Decimal {
+        self.borrowed + self.interest
+    }
 }
 
 pub struct Engine {
     orderbooks: Vec<Orderbook>,
     balances: HashMap<String, UserBalance>,
+    outputs: OutputSender,
+    // Total units of each asset held across all users; only deposits may change it
+    asset_supply: HashMap<String, Decimal>,
+    risk: HashMap<String, UserRisk>,
+    margin: MarginState,
+    // Sub-account names created by each user; balances are keyed by `<user_id>:<name>`
+    sub_accounts: HashMap<String, BTreeSet<String>>,
+    clock: Clock,
+    // Last journal entry processed
+    journal_id: Option<JournalId>,
+    // Entry being processed and how many ids it has handed out so far
+    id_source: Option<(JournalId, u32)>,
+    audit: bool,
 }
 
 impl Engine {
-    pub fn new() -> Self {
-        let orderbook = Orderbook::new("TATA".to_string(), vec![], vec![], None, None);
+    pub fn new(outputs: OutputSender) -> Self {
+        let clock = Clock::new();
+        let now = clock.now();
+        let orderbooks = vec![
+            Orderbook::new(
+                "TATA".to_string(),
+                "INR".to_string(),
+                vec![],
+                vec![],
+                None,
+                None,
+                now,
+            ),
+            Orderbook::new_perpetual(
+                "TATA-PERP".to_string(),
+                "INR".to_string(),
+                "TATA_INR".to_string(),
+                now,
+            ),
+            Orderbook::new(
+                "TATA".to_string(),
+                "USDT".to_string(),
+                vec![],
+                vec![],
+                None,
+                None,
+                now,
+            ),
+            Orderbook::new(
+                "BTC".to_string(),
+                "INR".to_string(),
+                vec![],
+                vec![],
+                None,
+                None,
+                now,
+            ),
+        ];
         let mut balances: HashMap<String, Balance> = HashMap::new();
-        balances.insert(
-            BASE_CURRENCY.to_string(),
-            Balance {
-                available: Decimal::from_str("1000000").unwrap(),
-                locked: Decimal::from_str("0").unwrap(),
-            },
-        );
-        balances.insert(
-            "TATA".to_string(),
-            Balance {
-                available: Decimal::from_str("1000000").unwrap(),
-                locked: Decimal::from_str("0").unwrap(),
-            },
-        );
+        for asset in ["INR", "USDT", "TATA", "BTC"] {
+            balances.insert(
+                asset.to_string(),
+                Balance {
+                    available: Decimal::from_str("1000000").unwrap(),
+                    locked: Decimal::from_str("0").unwrap(),
+                    ..Default::default()
+                },
+            );
+        }
 
         let mut user_balances: HashMap<String, UserBalance> = HashMap::new();
         user_balances.insert("1".to_string(), balances);
 
+        let asset_supply = Self::total_holdings(&user_balances);
         Self {
-            orderbooks: vec![orderbook],
+            orderbooks,
             balances: user_balances,
+            outputs,
+            asset_supply,
+            risk: HashMap::new(),
+            margin: MarginState::new(now),
+            sub_accounts: HashMap::new(),
+            clock,
+            journal_id: None,
+            id_source: None,
+            audit: false,
+        }
+    }
+
+    // Re-checks the balance invariants after every processed message and panics on
+    // the first violation, so accounting bugs surface at the message that caused them.
+    pub fn enable_audit(&mut self) {
+        self.audit = true;
+    }
+
+    pub fn journal_id(&self) -> Option<JournalId> {
+        self.journal_id
+    }
+
+    // Taken between messages, so the snapshot reflects every entry up to its
+    // journal id and nothing after it.
+    pub fn snapshot(&self) -> EngineSnapshot {
+        let now = self.clock.now();
+        EngineSnapshot {
+            journal_id: self.journal_id,
+            taken_at: self.clock.millis(),
+            balances: self.balances.clone(),
+            asset_supply: self.asset_supply.clone(),
+            risk_limits: self
+                .risk
+                .iter()
+                .map(|(user_id, risk)| (user_id.clone(), risk.limits.clone()))
+                .collect(),
+            margin: self.margin.snapshot(now),
+            sub_accounts: self.sub_accounts.clone(),
+            markets: self
+                .orderbooks
+                .iter()
+                .map(|orderbook| orderbook.snapshot(now))
+                .collect(),
+        }
+    }
+
+    pub fn restore(outputs: OutputSender, snapshot: EngineSnapshot) -> Self {
+        let clock = Clock::starting_at(snapshot.taken_at);
+        let now = clock.now();
+        let borrowers = snapshot
+            .balances
+            .iter()
+            .flat_map(|(user_id, user_balance)| {
+                user_balance
+                    .iter()
+                    .filter(|(_, balance)| !balance.borrowed.is_zero())
+                    .map(|(asset, _)| (user_id.clone(), asset.clone()))
+            })
+            .collect();
+        Self {
+            orderbooks: snapshot
+                .markets
+                .into_iter()
+                .map(|market| Orderbook::restore(market, now))
+                .collect(),
+            balances: snapshot.balances,
+            outputs,
+            asset_supply: snapshot.asset_supply,
+            risk: snapshot
+                .risk_limits
+                .into_iter()
+                .map(|(user_id, limits)| (user_id, UserRisk::new(limits)))
+                .collect(),
+            margin: MarginState::restore(snapshot.margin, borrowers, now),
+            sub_accounts: snapshot.sub_accounts,
+            clock,
+            journal_id: snapshot.journal_id,
+            id_source: None,
+            audit: false,
         }
     }
 
@@ -73,114 +252,108 @@ impl Engine {
         Decimal::from(value)
     }
 
-    pub async fn process(&mut self, params: ProcessParams) {
+    // Everything logged or emitted while handling the message, including the
+    // interest, funding and liquidations it triggers, carries its correlation id.
+    pub fn process(&mut self, params: ProcessParams) {
+        let kind = params.message.kind();
+        let correlation_id = params
+            .correlation_id
+            .clone()
+            .unwrap_or_else(|| params.client_id.clone());
+        let _span = info_span!("process", %correlation_id, kind).entered();
+        self.outputs.set_correlation_id(Some(correlation_id));
+        match params.journal_id {
+            Some(journal_id) => self.clock.advance_to(journal_id.millis as i64),
+            None => self.clock.advance_to(Utc::now().timestamp_millis()),
+        }
+        self.journal_id = params.journal_id.or(self.journal_id);
+        self.id_source = params.journal_id.map(|journal_id| (journal_id, 0));
+        let started = Instant::now();
+        self.accrue_interest();
+        self.settle_funding();
+        self.resume_expired_halts();
+        self.process_message(params);
+        self.run_liquidations();
+        self.publish_l3_events();
+        if self.audit {
+            if let Err(e) = self.check_invariants() {
+                panic!("Balance audit failed: {}", e);
+            }
+        }
+        counter!("engine_messages_processed_total", "type" => kind).increment(1);
+        histogram!("engine_process_duration_seconds", "type" => kind)
+            .record(started.elapsed().as_secs_f64());
+    }
+
+    fn process_message(&mut self, params: ProcessParams) {
         match params.message {
             InternalMessage::CreateOrder(payload) => {
-                let result: Result<MessageToApi, String> = self.create_order(payload).await;
-                let redis = RedisManager::get_instance().await;
+                let started = Instant::now();
+                let result: Result<MessageToApi, RejectReason> = self.create_order(payload);
+                histogram!("engine_matching_duration_seconds")
+                    .record(started.elapsed().as_secs_f64());
                 match result {
                     Ok(order) => {
-                        if let Err(e) = redis.send_to_api(params.client_id.clone(), order).await {
-                            eprintln!("Failed to send order placed message to Redis: {:?}", e);
-                        }
+                        self.outputs.send_to_api(params.client_id, order);
                     }
-                    Err(e) => {
-                        if let Err(redis_err) = redis
-                            .send_to_api(
-                                params.client_id.clone(),
-                                MessageToApi::OrderCancelled(OrderCancelledPayload {
-                                    order_id: "".to_string(),
-                                    executed_qty: 0,
-                                    remaining_qty: 0,
-                                }),
-                            )
-                            .await
-                        {
-                            eprintln!(
-                                "Failed to send order cancelled message to Redis: {:?}",
-                                redis_err
-                            );
-                        }
+                    Err(reason) => {
+                        warn!("Order rejected: {:?}", reason);
+                        counter!("engine_orders_rejected_total", "reason" => format!("{:?}", reason))
+                            .increment(1);
+                        self.outputs.send_to_api(
+                            params.client_id,
+                            MessageToApi::OrderRejected(OrderRejectedPayload { reason }),
+                        );
                     }
                 }
             }
             InternalMessage::CancelOrder(cancel_order_payload) => {
-                let order_id = cancel_order_payload.order_id;
-                let cancel_market = cancel_order_payload.market;
-                let quote_asset = cancel_market.split("_").next().unwrap();
-                let cancel_orderbook = match self
-                    .orderbooks
-                    .iter_mut()
-                    .find(|ob| ob.ticker() == cancel_market)
-                {
-                    Some(ob) => ob,
-                    None => {
-                        eprintln!("Orderbook not found");
-                        return;
-                    }
-                };
-                let order = cancel_orderbook
-                    .asks
-                    .iter()
-                    .find_map(|(_, orders)| orders.iter().find(|o| o.order_id == order_id))
-                    .or_else(|| {
-                        cancel_orderbook
-                            .bids
-                            .iter()
-                            .find_map(|(_, orders)| orders.iter().find(|o| o.order_id == order_id))
-                    })
-                    .cloned();
-
-                let order = match order {
-                    Some(o) => o,
+                if let Err(reason) = self.check_cancels_allowed(&cancel_order_payload.market) {
+                    self.outputs.send_to_api(
+                        params.client_id,
+                        MessageToApi::OrderRejected(OrderRejectedPayload { reason }),
+                    );
+                    return;
+                }
+                // Always answer so callers are not left waiting on an order that has
+                // already filled or never existed.
+                let cancelled = self
+                    .cancel_order(&cancel_order_payload.market, &cancel_order_payload.order_id)
+                    .unwrap_or(OrderCancelledPayload {
+                        order_id: cancel_order_payload.order_id,
+                        executed_qty: 0,
+                        remaining_qty: 0,
+                    });
+                self.outputs
+                    .send_to_api(params.client_id, MessageToApi::OrderCancelled(cancelled));
+            }
+            InternalMessage::CancelAllOrders(cancel_all_payload) => {
+                let market = cancel_all_payload.market;
+                if let Err(reason) = self.check_cancels_allowed(&market) {
+                    self.outputs.send_to_api(
+                        params.client_id,
+                        MessageToApi::OrderRejected(OrderRejectedPayload { reason }),
+                    );
+                    return;
+                }
+                let open_orders = match self.orderbooks.iter().find(|ob| ob.ticker() == market) {
+                    Some(orderbook) => orderbook.get_open_orders(cancel_all_payload.user_id),
                     None => {
-                        eprintln!("Order to be cancelled was not found");
+                        warn!("Orderbook not found");
                         return;
                     }
                 };
 
-                match order.side {
-                    Side::Buy => {
-                        if let Some(price) = cancel_orderbook.cancel_bid(&order) {
-                            let left_quantity_decimal =
-                                Decimal::from((order.quantity - order.filled) * order.price);
-                            let balance = self.balances.get_mut(&order.user_id).unwrap();
-                            balance.get_mut(BASE_CURRENCY).unwrap().available +=
-                                left_quantity_decimal;
-                            balance.get_mut(BASE_CURRENCY).unwrap().locked -= left_quantity_decimal;
-
-                            self.send_updated_depth_at(&price.to_string(), &cancel_market)
-                                .await;
-                        }
+                let mut cancelled_orders = Vec::with_capacity(open_orders.len());
+                for order in open_orders {
+                    if let Some(cancelled) = self.cancel_order(&market, &order.order_id) {
+                        cancelled_orders.push(cancelled);
                     }
-                    Side::Sell => {
-                        if let Some(price) = cancel_orderbook.cancel_ask(&order) {
-                            let left_quantity_decimal =
-                                Decimal::from(order.quantity - order.filled);
-                            let balance = self.balances.get_mut(&order.user_id).unwrap();
-                            balance.get_mut(quote_asset).unwrap().available +=
-                                left_quantity_decimal;
-                            balance.get_mut(quote_asset).unwrap().locked -= left_quantity_decimal;
-
-                            self.send_updated_depth_at(&price.to_string(), &cancel_market)
-                                .await;
-                        }
-                    }
-                }
-                if let Err(e) = RedisManager::get_instance()
-                    .await
-                    .send_to_api(
-                        params.client_id,
-                        MessageToApi::OrderCancelled(OrderCancelledPayload {
-                            order_id,
-                            executed_qty: 0,
-                            remaining_qty: 0,
-                        }),
-                    )
-                    .await
-                {
-                    eprintln!("Failed to send order cancelled message to Redis: {:?}", e);
                 }
+                self.outputs.send_to_api(
+                    params.client_id,
+                    MessageToApi::OrdersCancelled(cancelled_orders),
+                );
             }
             InternalMessage::GetOpenOrders(get_open_orders_payload) => {
                 match self
@@ -191,487 +364,3426 @@ impl Engine {
                     Some(open_order_book) => {
                         let open_orders =
                             open_order_book.get_open_orders(get_open_orders_payload.user_id);
-                        if let Err(e) = RedisManager::get_instance()
-                            .await
-                            .send_to_api(params.client_id, MessageToApi::OpenOrders(open_orders))
-                            .await
-                        {
-                            eprintln!("Failed to send open orders message to Redis: {:?}", e);
-                        }
+                        self.outputs
+                            .send_to_api(params.client_id, MessageToApi::OpenOrders(open_orders));
                     }
                     None => {
                         eprint!("No orderbook found");
-                        return;
                     }
                 }
             }
             InternalMessage::GetDepth(get_depth_payload) => {
                 let market = get_depth_payload.market;
-                match self.orderbooks.iter().find(|ob| ob.ticker() == market) {
-                    Some(orderbook) => {
-                        if let Err(e) = RedisManager::get_instance()
-                            .await
-                            .send_to_api(
-                                params.client_id,
-                                MessageToApi::Depth(orderbook.get_depth()),
-                            )
-                            .await
-                        {
-                            eprintln!("Failed to send depth message to Redis: {:?}", e);
-                        }
-                    }
-                    None => {}
+                if let Some(orderbook) = self.orderbooks.iter().find(|ob| ob.ticker() == market) {
+                    let depth = orderbook.aggregated_depth(
+                        get_depth_payload.limit,
+                        get_depth_payload.precision.unwrap_or(1).max(1),
+                    );
+                    self.outputs
+                        .send_to_api(params.client_id, MessageToApi::Depth(depth));
+                }
+            }
+            InternalMessage::GetL3Snapshot(get_l3_payload) => {
+                match self
+                    .orderbooks
+                    .iter()
+                    .find(|ob| ob.ticker() == get_l3_payload.market)
+                {
+                    Some(orderbook) => self.outputs.send_to_api(
+                        params.client_id,
+                        MessageToApi::L3Snapshot(orderbook.l3_snapshot()),
+                    ),
+                    None => warn!("Orderbook not found"),
                 }
             }
             InternalMessage::OnRamp(on_ramp_payload) => {
                 let user_id = on_ramp_payload.user_id;
-                let amount = on_ramp_payload.amount;
-                self.on_ramp(&user_id, amount);
+                let asset = on_ramp_payload.asset;
+                if !self.is_listed_asset(&asset) {
+                    warn!("On-ramp of unlisted asset {}", asset);
+                    return;
+                }
+                if let Err(reason) = self.check_account(&user_id) {
+                    warn!("On-ramp rejected: {:?}", reason);
+                    return;
+                }
+                self.on_ramp(&user_id, &asset, on_ramp_payload.amount);
+            }
+            InternalMessage::SetMarketStatus(set_status_payload) => {
+                let market = set_status_payload.market;
+                if let Some(orderbook) = self.orderbooks.iter_mut().find(|ob| ob.ticker() == market)
+                {
+                    orderbook.protection.reset_trip();
+                }
+                if let Some(status) = self.set_market_status(&market, set_status_payload.status) {
+                    self.outputs.send_to_api(
+                        params.client_id,
+                        MessageToApi::MarketStatus(MarketStatusPayload { market, status }),
+                    );
+                }
+            }
+            InternalMessage::GetMarkets => {
+                let markets = self
+                    .orderbooks
+                    .iter()
+                    .map(|orderbook| MarketPayload {
+                        market: orderbook.ticker(),
+                        base_asset: orderbook.base_asset.clone(),
+                        quote_asset: orderbook.quote_asset.clone(),
+                        kind: if orderbook.perpetual.is_some() {
+                            MarketKind::Perpetual
+                        } else {
+                            MarketKind::Spot
+                        },
+                        status: orderbook.status,
+                    })
+                    .collect();
+                self.outputs
+                    .send_to_api(params.client_id, MessageToApi::Markets(markets));
+            }
+            InternalMessage::GetTickers => {
+                let now = self.clock.now();
+                let tickers = self
+                    .orderbooks
+                    .iter()
+                    .map(|orderbook| Self::ticker_message(orderbook, now))
+                    .collect();
+                self.outputs
+                    .send_to_api(params.client_id, MessageToApi::Tickers(tickers));
+            }
+            InternalMessage::SetPriceProtection(protection_payload) => {
+                let market = protection_payload.market.clone();
+                match self.orderbooks.iter_mut().find(|ob| ob.ticker() == market) {
+                    Some(orderbook) => {
+                        orderbook.protection.configure(&protection_payload);
+                        self.outputs.send_to_api(
+                            params.client_id,
+                            MessageToApi::PriceProtection(orderbook.protection.config(market)),
+                        );
+                    }
+                    None => warn!("Orderbook not found"),
+                }
+            }
+            InternalMessage::SetRiskLimits(risk_limits_payload) => {
+                self.risk.insert(
+                    risk_limits_payload.user_id.clone(),
+                    UserRisk::new(risk_limits_payload.limits.clone()),
+                );
+                self.outputs.push_message(DbMessage {
+                    db_message_type: DbMessageType::RiskLimitsUpdated,
+                    data: DbMessageData::RiskLimits(risk_limits_payload.clone()),
+                });
+                self.outputs.send_to_api(
+                    params.client_id,
+                    MessageToApi::RiskLimits(risk_limits_payload),
+                );
+            }
+            InternalMessage::GetRiskLimits(get_risk_limits_payload) => {
+                let limits = self
+                    .risk
+                    .get(&get_risk_limits_payload.user_id)
+                    .map(|user_risk| user_risk.limits.clone())
+                    .unwrap_or_default();
+                self.outputs.send_to_api(
+                    params.client_id,
+                    MessageToApi::RiskLimits(RiskLimitsPayload {
+                        user_id: get_risk_limits_payload.user_id,
+                        limits,
+                    }),
+                );
+            }
+            InternalMessage::SetMarginConfig(margin_config) => {
+                self.margin.config = margin_config.clone();
+                let enabled_users = self.margin.enabled_users.iter().cloned();
+                self.margin.unchecked.extend(enabled_users);
+                self.outputs
+                    .send_to_api(params.client_id, MessageToApi::MarginConfig(margin_config));
+            }
+            InternalMessage::SetMarginEnabled(margin_enabled_payload) => {
+                let user_id = margin_enabled_payload.user_id;
+                if margin_enabled_payload.enabled {
+                    self.margin.enabled_users.insert(user_id.clone());
+                    self.margin.unchecked.insert(user_id.clone());
+                } else if self.account_value(&user_id).liabilities.is_zero() {
+                    // Accounts with debt stay enabled so they keep being watched
+                    self.margin.enabled_users.remove(&user_id);
+                }
+                self.outputs.send_to_api(
+                    params.client_id,
+                    MessageToApi::MarginAccount(self.margin_account(&user_id)),
+                );
+            }
+            InternalMessage::RepayMargin(repay_payload) => {
+                self.repay(
+                    &repay_payload.user_id,
+                    &repay_payload.asset,
+                    repay_payload.amount,
+                );
+                self.outputs.send_to_api(
+                    params.client_id,
+                    MessageToApi::MarginAccount(self.margin_account(&repay_payload.user_id)),
+                );
+            }
+            InternalMessage::GetPositions(get_positions_payload) => {
+                let positions = self.positions(&get_positions_payload.user_id);
+                self.outputs
+                    .send_to_api(params.client_id, MessageToApi::Positions(positions));
+            }
+            InternalMessage::CreateSubAccount(sub_account_payload) => {
+                let user_id = sub_account_payload.user_id;
+                let name = sub_account_payload.name;
+                if name.is_empty() || name.contains(':') || user_id.contains(':') {
+                    warn!("Invalid sub-account name {:?} for {}", name, user_id);
+                } else {
+                    let account_id = format!("{}:{}", user_id, name);
+                    self.balances.entry(account_id).or_default();
+                    self.sub_accounts
+                        .entry(user_id.clone())
+                        .or_default()
+                        .insert(name);
+                }
+                self.outputs.send_to_api(
+                    params.client_id,
+                    MessageToApi::SubAccounts(self.sub_accounts_of(user_id)),
+                );
+            }
+            InternalMessage::GetSubAccounts(get_sub_accounts_payload) => {
+                self.outputs.send_to_api(
+                    params.client_id,
+                    MessageToApi::SubAccounts(
+                        self.sub_accounts_of(get_sub_accounts_payload.user_id),
+                    ),
+                );
+            }
+            InternalMessage::Transfer(transfer_payload) => match self.transfer(transfer_payload) {
+                Ok(transfer) => {
+                    self.outputs.push_message(DbMessage {
+                        db_message_type: DbMessageType::TransferCompleted,
+                        data: DbMessageData::Transfer(transfer.clone()),
+                    });
+                    self.outputs
+                        .send_to_api(params.client_id, MessageToApi::TransferCompleted(transfer));
+                }
+                Err(reason) => {
+                    warn!("Transfer rejected: {:?}", reason);
+                    self.outputs.send_to_api(
+                        params.client_id,
+                        MessageToApi::TransferRejected(TransferRejectedPayload { reason }),
+                    );
+                }
+            },
+            InternalMessage::GetUserState(get_user_payload) => {
+                let user_id = get_user_payload.user_id;
+                let open_orders = self
+                    .orderbooks
+                    .iter()
+                    .map(|orderbook| MarketOrders {
+                        market: orderbook.ticker(),
+                        orders: orderbook.get_open_orders(user_id.clone()),
+                    })
+                    .filter(|market_orders| !market_orders.orders.is_empty())
+                    .collect();
+                self.outputs.send_to_api(
+                    params.client_id,
+                    MessageToApi::UserState(UserStatePayload {
+                        balances: self.balances_of(&user_id),
+                        user_id,
+                        open_orders,
+                    }),
+                );
+            }
+            InternalMessage::GetBookTop(get_book_payload) => {
+                let message = match self
+                    .orderbooks
+                    .iter()
+                    .find(|ob| ob.ticker() == get_book_payload.market)
+                {
+                    Some(orderbook) => {
+                        let (bids, asks) = orderbook.top_orders(get_book_payload.levels);
+                        MessageToApi::BookTop(BookTopPayload {
+                            market: get_book_payload.market,
+                            status: orderbook.status,
+                            last_price: orderbook.current_price,
+                            bids,
+                            asks,
+                        })
+                    }
+                    None => MessageToApi::AdminRejected(AdminRejectedPayload {
+                        reason: RejectReason::MarketNotFound,
+                    }),
+                };
+                self.outputs.send_to_api(params.client_id, message);
             }
+            // Unlike a user cancel this ignores the market status, so operators can
+            // pull orders out of halted markets.
+            InternalMessage::ForceCancel(cancel_order_payload) => {
+                let market = cancel_order_payload.market;
+                let message = match self.cancel_order(&market, &cancel_order_payload.order_id) {
+                    Some(cancelled) => {
+                        info!("Force cancelled order {} on {}", cancelled.order_id, market);
+                        MessageToApi::OrderCancelled(cancelled)
+                    }
+                    None => MessageToApi::AdminRejected(AdminRejectedPayload {
+                        reason: if self.orderbooks.iter().any(|ob| ob.ticker() == market) {
+                            RejectReason::OrderNotFound
+                        } else {
+                            RejectReason::MarketNotFound
+                        },
+                    }),
+                };
+                self.outputs.send_to_api(params.client_id, message);
+            }
+            InternalMessage::AdjustBalance(adjust_payload) => {
+                match self.adjust_balance(adjust_payload) {
+                    Ok(adjustment) => {
+                        info!(
+                            "Adjusted {} balance of {} by {}: {}",
+                            adjustment.asset,
+                            adjustment.user_id,
+                            adjustment.amount,
+                            adjustment.reason
+                        );
+                        self.outputs.push_message(DbMessage {
+                            db_message_type: DbMessageType::BalanceAdjusted,
+                            data: DbMessageData::BalanceAdjustment(adjustment.clone()),
+                        });
+                        self.outputs.send_to_api(
+                            params.client_id,
+                            MessageToApi::BalanceAdjusted(adjustment),
+                        );
+                    }
+                    Err(reason) => {
+                        warn!("Balance adjustment rejected: {:?}", reason);
+                        self.outputs.send_to_api(
+                            params.client_id,
+                            MessageToApi::AdminRejected(AdminRejectedPayload { reason }),
+                        );
+                    }
+                }
+            }
+            InternalMessage::GetMarginAccount(get_margin_account_payload) => {
+                self.outputs.send_to_api(
+                    params.client_id,
+                    MessageToApi::MarginAccount(
+                        self.margin_account(&get_margin_account_payload.user_id),
+                    ),
+                );
+            }
+        }
+    }
+
+    fn sub_accounts_of(&self, user_id: String) -> SubAccountsPayload {
+        let sub_accounts = self
+            .sub_accounts
+            .get(&user_id)
+            .into_iter()
+            .flatten()
+            .map(|name| format!("{}:{}", user_id, name))
+            .collect();
+        SubAccountsPayload {
+            user_id,
+            sub_accounts,
         }
     }
 
-    pub fn add_orderbook(&mut self, orderbook: Orderbook) {
-        self.orderbooks.push(orderbook);
+    // Plain user ids are created on first use, sub-accounts only through
+    // CreateSubAccount.
+    fn check_account(&self, account_id: &str) -> Result<(), RejectReason> {
+        match account_id.split_once(':') {
+            Some((user_id, name))
+                if !self
+                    .sub_accounts
+                    .get(user_id)
+                    .is_some_and(|names| names.contains(name)) =>
+            {
+                Err(RejectReason::AccountNotFound)
+            }
+            _ => Ok(()),
+        }
     }
 
-    async fn create_order(
+    fn transfer(
         &mut self,
-        payload: InternalCreateOrderPayload,
-    ) -> Result<MessageToApi, String> {
-        let orderbook_exists = self
-            .orderbooks
-            .iter()
-            .any(|ob| ob.ticker() == payload.market);
-        if !orderbook_exists {
-            return Err("Orderbook not found".to_string());
+        payload: InternalTransferPayload,
+    ) -> Result<TransferRecord, RejectReason> {
+        self.check_account(&payload.from_account)?;
+        self.check_account(&payload.to_account)?;
+        if !self
+            .account_value(&payload.from_account)
+            .liabilities
+            .is_zero()
+        {
+            return Err(RejectReason::OutstandingDebt);
+        }
+        if self.available(&payload.from_account, &payload.asset) < payload.amount {
+            return Err(RejectReason::InsufficientFunds);
         }
 
-        let mut market_parts = payload.market.split("_");
-        let base_asset = market_parts.next().expect("Invalid market");
-        let quote_asset = market_parts.next().expect("Invalid market");
+        self.balance_mut(&payload.from_account, &payload.asset)
+            .available -= payload.amount;
+        self.balance_mut(&payload.to_account, &payload.asset)
+            .available += payload.amount;
+        Ok(TransferRecord {
+            id: self.next_id(),
+            from_account: payload.from_account,
+            to_account: payload.to_account,
+            asset: payload.asset,
+            amount: payload.amount,
+        })
+    }
 
-        self.check_and_lock_funds(
-            base_asset,
-            quote_asset,
-            &payload.side,
-            &payload.user_id,
-            payload.price_decimal,
-            payload.quantity_decimal,
-        )?;
+    // Credits add to the asset supply like a deposit and debits take units out of it.
+    fn adjust_balance(
+        &mut self,
+        payload: InternalAdjustBalancePayload,
+    ) -> Result<BalanceAdjustment, RejectReason> {
+        if !self.is_listed_asset(&payload.asset) {
+            return Err(RejectReason::AssetNotListed);
+        }
+        self.check_account(&payload.user_id)?;
+        if self.available(&payload.user_id, &payload.asset) + payload.amount < Decimal::ZERO {
+            return Err(RejectReason::InsufficientFunds);
+        }
 
-        let mut order = Order {
-            price: payload.price,
-            quantity: payload.quantity,
-            order_id: self.get_random_id(),
-            filled: 0,
-            side: payload.side.clone(),
-            user_id: payload.user_id.clone(),
-        };
+        self.on_ramp(&payload.user_id, &payload.asset, payload.amount);
+        Ok(BalanceAdjustment {
+            id: self.next_id(),
+            user_id: payload.user_id,
+            asset: payload.asset,
+            amount: payload.amount,
+            reason: payload.reason,
+        })
+    }
 
-        let orderbook = self
+    fn positions(&self, user_id: &str) -> Vec<PositionPayload> {
+        self.orderbooks
+            .iter()
+            .filter_map(|orderbook| {
+                let position = orderbook.perpetual.as_ref()?.positions.get(user_id)?;
+                let mark_price = Decimal::from(orderbook.current_price);
+                Some(PositionPayload {
+                    market: orderbook.ticker(),
+                    size: position.size,
+                    entry_price: position.entry_price,
+                    margin: position.margin,
+                    mark_price,
+                    unrealized_pnl: position.unrealized_pnl(mark_price),
+                })
+            })
+            .collect()
+    }
+
+    // Funding is settled lazily, on the first message after each interval ends.
+    fn settle_funding(&mut self) {
+        let now = self.clock.now();
+        let last_prices: HashMap<String, u64> = self
             .orderbooks
-            .iter_mut()
-            .find(|ob| ob.ticker() == payload.market)
-            .unwrap();
-        let created = orderbook.add_order(&mut order);
-        self.update_balances(
-            &payload.user_id,
-            base_asset,
-            quote_asset,
-            &payload.side,
-            &created.fills,
-            created.executed_quantity,
-        );
+            .iter()
+            .map(|ob| (ob.ticker(), ob.current_price))
+            .collect();
+        let mut applied = Vec::new();
+        for orderbook in self.orderbooks.iter_mut() {
+            let market = orderbook.ticker();
+            let mark_price = Decimal::from(orderbook.current_price);
+            let Some(perpetual) = orderbook.perpetual.as_mut() else {
+                continue;
+            };
+            if !perpetual.funding_due(now) {
+                continue;
+            }
+            let index_price = Decimal::from(
+                last_prices
+                    .get(&perpetual.index_market)
+                    .copied()
+                    .unwrap_or(0),
+            );
+            if let Some(rate) = perpetual.settle_funding(now, mark_price, index_price) {
+                applied.push((market, rate, mark_price, index_price));
+            }
+        }
 
-        let timestamp = Utc::now().to_string();
-        self.create_db_trades(&created.fills, &payload.market, &payload.side, &timestamp)
-            .await;
-        self.update_db_orders(
-            &order,
-            created.executed_quantity,
-            &created.fills,
-            &payload.market,
-        )
-        .await;
-        self.publish_ws_depth_updates(
-            &created.fills,
-            &payload.price.to_string(),
-            &payload.market,
-            &payload.side,
-        )
-        .await;
-        self.publish_ws_trades(&created.fills, &payload.market, &payload.side)
-            .await;
-        Ok(MessageToApi::OrderPlaced(OrderPlacedPayload {
-            order_id: order.order_id,
-            executed_qty: created.executed_quantity,
-            fills: created
-                .fills
-                .iter()
-                .map(|fill| fill.fill.to_external_fill())
-                .collect(),
-        }))
+        for (market, rate, mark_price, index_price) in applied {
+            info!("Funding rate {} applied to {}", rate, market);
+            self.outputs.publish_message(
+                format!("funding@{}", market),
+                WsMessage {
+                    stream: format!("funding@{}", market),
+                    data: WsPayload::Funding(FundingUpdateMessage {
+                        e: "funding".to_string(),
+                        s: market,
+                        r: rate.to_string(),
+                        p: mark_price.to_string(),
+                        i: index_price.to_string(),
+                    }),
+                },
+            );
+        }
     }
 
-    fn update_balances(
+    fn settle_perpetual_fills(
         &mut self,
+        market: &str,
         user_id: &str,
-        base_asset: &str,
-        quote_asset: &str,
         side: &Side,
-        fills: &Vec<OrderbookFill>,
-        executed_quantity: u64,
+        limit_price: u64,
+        fills: &[OrderbookFill],
     ) {
-        match side {
-            Side::Buy => {
-                fills.iter().for_each(|fill| {
-                    // Use pre-calculated decimal values - no parsing needed!
-                    let fill_qty_decimal = Self::u64_to_decimal(fill.fill.qty);
-                    let fill_amount_decimal = fill.fill.price_decimal * fill_qty_decimal;
-
-                    //update quote balance
-                    let other_user = self.balances.get_mut(&fill.other_user_id).unwrap();
-                    other_user.get_mut(quote_asset).unwrap().available -= fill_amount_decimal;
-                    let currrent_user = self.balances.get_mut(user_id).unwrap();
-                    currrent_user.get_mut(quote_asset).unwrap().locked += fill_amount_decimal;
-
-                    //update base balance
-                    let other_user = self.balances.get_mut(&fill.other_user_id).unwrap();
-                    other_user.get_mut(base_asset).unwrap().locked -= fill_qty_decimal;
-                    let current_user = self.balances.get_mut(user_id).unwrap();
-                    current_user.get_mut(base_asset).unwrap().locked += fill_qty_decimal;
-                });
-            }
-            Side::Sell => {
-                fills.iter().for_each(|fill| {
-                    let fill_qty_decimal = Self::u64_to_decimal(fill.fill.qty);
-                    let fill_amount_decimal = fill.fill.price_decimal * fill_qty_decimal;
-
-                    //update quote balance
-                    let other_user = self.balances.get_mut(&fill.other_user_id).unwrap();
-                    other_user.get_mut(quote_asset).unwrap().locked -= fill_amount_decimal;
-                    let current_user = self.balances.get_mut(user_id).unwrap();
-                    current_user.get_mut(quote_asset).unwrap().available += fill_amount_decimal;
-
-                    //update base asset
-                    let other_user = self.balances.get_mut(&fill.other_user_id).unwrap();
-                    other_user.get_mut(base_asset).unwrap().available += fill_qty_decimal;
-                    let current_user = self.balances.get_mut(user_id).unwrap();
-                    current_user.get_mut(base_asset).unwrap().locked -= fill_qty_decimal;
-                });
+        for fill in fills {
+            // Makers locked collateral at their own price, which is the fill price
+            match side {
+                Side::Buy => self.settle_perpetual_fill(
+                    market,
+                    (user_id, limit_price),
+                    (&fill.other_user_id, fill.fill.price_u64),
+                    fill.fill.qty,
+                    fill.fill.price_u64,
+                ),
+                Side::Sell => self.settle_perpetual_fill(
+                    market,
+                    (&fill.other_user_id, fill.fill.price_u64),
+                    (user_id, limit_price),
+                    fill.fill.qty,
+                    fill.fill.price_u64,
+                ),
             }
         }
     }
 
-    fn on_ramp(&mut self, user_id: &str, amount: Decimal) {
-        match self.balances.get_mut(user_id) {
-            Some(user_balance) => {
-                if let Some(base_balance) = user_balance.get_mut(BASE_CURRENCY) {
-                    base_balance.available += amount;
-                } else {
-                    user_balance.insert(
-                        BASE_CURRENCY.to_string(),
-                        Balance {
-                            available: amount,
-                            locked: Decimal::from(0),
-                        },
-                    );
-                }
+    // Buyer and seller come with the limit price their collateral was locked at.
+    fn settle_perpetual_fill(
+        &mut self,
+        market: &str,
+        (buyer_id, buyer_limit): (&str, u64),
+        (seller_id, seller_limit): (&str, u64),
+        quantity: u64,
+        price: u64,
+    ) {
+        let Some(orderbook) = self.orderbooks.iter_mut().find(|ob| ob.ticker() == market) else {
+            return;
+        };
+        let quote_asset = orderbook.quote_asset.clone();
+        let Some(perpetual) = orderbook.perpetual.as_mut() else {
+            return;
+        };
+        let quantity_decimal = Decimal::from(quantity);
+        let price_decimal = Decimal::from(price);
+        let buyer_collateral = perpetual.collateral(Decimal::from(buyer_limit), quantity_decimal);
+        let seller_collateral = perpetual.collateral(Decimal::from(seller_limit), quantity_decimal);
+        let buyer_credit =
+            perpetual.apply_fill(buyer_id, quantity as i64, price_decimal, buyer_collateral);
+        let seller_credit = perpetual.apply_fill(
+            seller_id,
+            -(quantity as i64),
+            price_decimal,
+            seller_collateral,
+        );
+
+        let mut shortfall = Decimal::ZERO;
+        for (user_id, collateral, credit) in [
+            (buyer_id, buyer_collateral, buyer_credit),
+            (seller_id, seller_collateral, seller_credit),
+        ] {
+            let balance = self.balance_mut(user_id, &quote_asset);
+            balance.locked -= collateral;
+            balance.available += credit;
+            // Losses beyond what the user holds are absorbed by the settlement pool
+            if balance.available.is_sign_negative() {
+                shortfall -= balance.available;
+                balance.available = Decimal::ZERO;
             }
-            None => {
-                let mut new_balance = UserBalance::new();
-                new_balance.insert(
-                    BASE_CURRENCY.to_string(),
-                    Balance {
-                        available: amount,
-                        locked: Decimal::from(0),
-                    },
-                );
-                self.balances.insert(user_id.to_string(), new_balance);
+        }
+        if !shortfall.is_zero() {
+            warn!("Perpetual loss of {} not covered on {}", shortfall, market);
+            if let Some(perpetual) = self
+                .orderbooks
+                .iter_mut()
+                .find(|ob| ob.ticker() == market)
+                .and_then(|ob| ob.perpetual.as_mut())
+            {
+                perpetual.settlement_pool -= shortfall;
             }
         }
     }
 
-    fn check_and_lock_funds(
-        &mut self,
-        base_asset: &str,
-        quote_asset: &str,
-        side: &Side,
-        user_id: &str,
-        price_decimal: Decimal,
-        quantity_decimal: Decimal,
-    ) -> Result<(), String> {
-        let user = match self.balances.get_mut(user_id) {
-            Some(user) => user,
-            None => return Err("User not found".to_string()),
+    // Last traded price of an asset in the valuation currency. Assets that only
+    // trade against another quote are valued through that quote's own market.
+    fn asset_price(&self, asset: &str) -> Option<Decimal> {
+        if asset == VALUATION_ASSET {
+            return Some(Decimal::ONE);
+        }
+        let last_price = |base: &str, quote: &str| {
+            self.orderbooks
+                .iter()
+                .find(|ob| {
+                    ob.perpetual.is_none() && ob.base_asset == base && ob.quote_asset == quote
+                })
+                .filter(|ob| ob.current_price > 0)
+                .map(|ob| Decimal::from(ob.current_price))
         };
+        last_price(asset, VALUATION_ASSET).or_else(|| {
+            self.orderbooks
+                .iter()
+                .filter(|ob| ob.perpetual.is_none() && ob.base_asset == asset)
+                .find_map(|ob| {
+                    Some(
+                        last_price(&ob.base_asset, &ob.quote_asset)?
+                            * last_price(&ob.quote_asset, VALUATION_ASSET)?,
+                    )
+                })
+        })
+    }
 
-        match side {
-            Side::Buy => {
-                let user_quote_balance = match user.get_mut(quote_asset) {
-                    Some(balance) => balance,
-                    None => return Err("User quote balance not found".to_string()),
-                };
+    fn account_value(&self, user_id: &str) -> AccountValue {
+        let mut value = AccountValue::default();
+        for (asset, balance) in self.balances.get(user_id).into_iter().flatten() {
+            let price = self.asset_price(asset).unwrap_or_default();
+            value.assets += (balance.available + balance.locked) * price;
+            value.liabilities += balance.liabilities() * price;
+        }
+        value
+    }
 
-                let required_quote_amount = price_decimal * quantity_decimal;
-                if user_quote_balance.available < required_quote_amount {
-                    return Err("Insufficient quote balance".to_string());
-                }
+    fn balances_of(&self, user_id: &str) -> Vec<MarginBalance> {
+        let mut balances: Vec<MarginBalance> = self
+            .balances
+            .get(user_id)
+            .into_iter()
+            .flatten()
+            .map(|(asset, balance)| MarginBalance {
+                asset: asset.clone(),
+                available: balance.available,
+                locked: balance.locked,
+                borrowed: balance.borrowed,
+                interest: balance.interest,
+            })
+            .collect();
+        balances.sort_by(|a, b| a.asset.cmp(&b.asset));
+        balances
+    }
 
-                user_quote_balance.available -= required_quote_amount;
-                user_quote_balance.locked += required_quote_amount; // Lock quote currency amount, not just quantity
-                Ok(())
-            }
-            Side::Sell => {
-                let user_base_balance = match user.get_mut(base_asset) {
-                    Some(balance) => balance,
-                    None => return Err("User base balance not found".to_string()),
-                };
+    fn margin_account(&self, user_id: &str) -> MarginAccountPayload {
+        let value = self.account_value(user_id);
+        MarginAccountPayload {
+            user_id: user_id.to_string(),
+            enabled: self.margin.enabled_users.contains(user_id),
+            assets_value: value.assets,
+            liabilities_value: value.liabilities,
+            margin_level: value.margin_level(),
+            balances: self.balances_of(user_id),
+        }
+    }
 
-                if user_base_balance.available < quantity_decimal {
-                    return Err("Insufficient base balance".to_string());
-                }
+    // Borrows whatever the order needs beyond the available balance, provided the
+    // account stays within the configured leverage. Otherwise the order goes on to
+    // fail the usual funds check.
+    fn borrow_for_order(&mut self, user_id: &str, asset: &str, required: Decimal) {
+        if !self.margin.enabled_users.contains(user_id) {
+            return;
+        }
+        let available = self
+            .balances
+            .get(user_id)
+            .and_then(|user_balance| user_balance.get(asset))
+            .map_or(Decimal::ZERO, |balance| balance.available);
+        if available >= required {
+            return;
+        }
+        let shortfall = required - available;
+        let Some(price) = self.asset_price(asset) else {
+            return;
+        };
+        if !self
+            .account_value(user_id)
+            .can_borrow(shortfall * price, self.margin.config.max_leverage)
+        {
+            return;
+        }
+        let balance = self.balance_mut(user_id, asset);
+        balance.available += shortfall;
+        balance.borrowed += shortfall;
+        self.margin
+            .borrowers
+            .insert((user_id.to_string(), asset.to_string()));
+    }
+
+    // Pays interest first, then principal, out of the available balance.
+    fn repay(&mut self, user_id: &str, asset: &str, amount: Decimal) -> Decimal {
+        // A negative amount would add to both the debt and the available balance
+        if amount <= Decimal::ZERO {
+            return Decimal::ZERO;
+        }
+        let Some(balance) = self
+            .balances
+            .get_mut(user_id)
+            .and_then(|user_balance| user_balance.get_mut(asset))
+        else {
+            return Decimal::ZERO;
+        };
+        let amount = amount.min(balance.available);
+        let to_interest = amount.min(balance.interest);
+        let to_principal = (amount - to_interest).min(balance.borrowed);
+        balance.interest -= to_interest;
+        balance.borrowed -= to_principal;
+        balance.available -= to_interest + to_principal;
+        let repaid = to_interest + to_principal;
+        if balance.borrowed.is_zero() {
+            self.margin
+                .borrowers
+                .remove(&(user_id.to_string(), asset.to_string()));
+        }
+        if !repaid.is_zero() {
+            self.margin.unchecked.insert(user_id.to_string());
+        }
+        repaid
+    }
 
-                user_base_balance.available -= quantity_decimal;
-                user_base_balance.locked += quantity_decimal;
-                Ok(())
+    // What rounding leaves over is carried to the next accrual, so a debt is
+    // charged for the time it is held however often messages arrive.
+    fn accrue_interest(&mut self) {
+        let factor = self.margin.take_interest_factor(self.clock.now());
+        if factor.is_zero() {
+            return;
+        }
+        for (user_id, asset) in &self.margin.borrowers {
+            let Some(balance) = self
+                .balances
+                .get_mut(user_id)
+                .and_then(|user_balance| user_balance.get_mut(asset))
+            else {
+                continue;
+            };
+            let accrued = balance.borrowed * factor + balance.interest_carry;
+            let interest = accrued.round_dp(INTEREST_PRECISION);
+            balance.interest_carry = accrued - interest;
+            if interest.is_zero() {
+                continue;
             }
+            balance.interest += interest;
+            *self
+                .margin
+                .interest_charged
+                .entry(asset.clone())
+                .or_default() += interest;
+            self.margin.unchecked.insert(user_id.clone());
         }
     }
 
-    async fn send_updated_depth_at(&self, price: &str, market: &str) {
-        let depth = match self.orderbooks.iter().find(|ob| ob.ticker() == market) {
-            Some(orderbook) => orderbook.get_depth(),
-            None => {
-                eprintln!("Orderbook not found");
-                return;
+    // Only accounts whose margin level may have moved are valued: those whose
+    // balances changed, those holding or owing an asset whose price moved, and
+    // those a liquidation could not bring back above the threshold, which wait
+    // for liquidity on the book.
+    fn run_liquidations(&mut self) {
+        for asset in self.moved_prices() {
+            for user_id in &self.margin.enabled_users {
+                if self
+                    .balances
+                    .get(user_id)
+                    .is_some_and(|user_balance| user_balance.contains_key(&asset))
+                {
+                    self.margin.unchecked.insert(user_id.clone());
+                }
             }
-        };
+        }
+        let unchecked = std::mem::take(&mut self.margin.unchecked);
+        let mut breached: Vec<String> = unchecked
+            .into_iter()
+            .filter(|user_id| {
+                self.margin.enabled_users.contains(user_id) && self.is_breached(user_id)
+            })
+            .collect();
+        breached.sort();
+        for user_id in breached {
+            self.liquidate(&user_id);
+            if self.is_breached(&user_id) {
+                self.margin.unchecked.insert(user_id);
+            }
+        }
+    }
+
+    fn is_breached(&self, user_id: &str) -> bool {
+        self.account_value(user_id)
+            .margin_level()
+            .is_some_and(|level| level < self.margin.config.liquidation_margin_level)
+    }
 
-        let updated_bids: Vec<[String; 2]> = depth
-            .bids
+    // Assets whose price changed since accounts were last checked against it
+    fn moved_prices(&mut self) -> Vec<String> {
+        let assets: BTreeSet<String> = self
+            .orderbooks
             .iter()
-            .filter(|x| x.get(0).map_or(false, |p| p == price))
-            .map(|x| [x[0].clone(), x[1].clone()])
+            .filter(|ob| ob.perpetual.is_none())
+            .flat_map(|ob| [ob.base_asset.clone(), ob.quote_asset.clone()])
             .collect();
+        let mut moved = Vec::new();
+        for asset in assets {
+            let price = self.asset_price(&asset).unwrap_or_default();
+            if self.margin.checked_prices.insert(asset.clone(), price) != Some(price) {
+                moved.push(asset);
+            }
+        }
+        moved
+    }
 
-        let updated_asks: Vec<[String; 2]> = depth
-            .asks
+    // Cancels the account's orders, pays debts from what that frees up, then trades
+    // against the book to cover what is still owed: buying back borrowed base assets
+    // first and selling base assets for any quote debt after. Closing orders that
+    // cannot fill right away are cancelled rather than left resting.
+    fn liquidate(&mut self, user_id: &str) {
+        info!("Liquidating margin account {}", user_id);
+        let markets: Vec<(String, String, String)> = self
+            .orderbooks
             .iter()
-            .filter(|x| x.get(0).map_or(false, |p| p == price))
-            .map(|x| [x[0].clone(), x[1].clone()])
+            .filter(|ob| ob.perpetual.is_none())
+            .map(|ob| (ob.ticker(), ob.base_asset.clone(), ob.quote_asset.clone()))
             .collect();
 
-        if let Err(e) = RedisManager::get_instance()
-            .await
-            .publish_message(
-                format!("depth@{}", market),
-                WsMessage {
-                    stream: format!("depth@{}", market),
-                    data: WsPayload::Depth(DepthUpdateMessage {
-                        e: "depth".to_string(),
-                        a: updated_asks,
-                        b: updated_bids,
-                    }),
-                },
-            )
-            .await
-        {
-            eprintln!("Failed to send depth update message to Redis: {:?}", e);
+        for (market, _, _) in &markets {
+            let open_orders = self
+                .orderbooks
+                .iter()
+                .find(|ob| &ob.ticker() == market)
+                .map(|ob| ob.get_open_orders(user_id.to_string()))
+                .unwrap_or_default();
+            for order in open_orders {
+                self.cancel_order(market, &order.order_id);
+            }
         }
+        self.repay_all(user_id);
+
+        for (market, base_asset, quote_asset) in &markets {
+            let debt = self.liabilities(user_id, base_asset);
+            let Some(price) = self.sweep_price(market, &Side::Buy) else {
+                continue;
+            };
+            let affordable = self.available(user_id, quote_asset) / Decimal::from(price);
+            let quantity = debt.ceil().min(affordable.floor());
+            self.submit_closing_order(user_id, market, Side::Buy, price, quantity);
+        }
+        self.repay_all(user_id);
+
+        for (market, base_asset, quote_asset) in &markets {
+            let debt = self.liabilities(user_id, quote_asset);
+            let Some(price) = self.sweep_price(market, &Side::Sell) else {
+                continue;
+            };
+            let needed = (debt / Decimal::from(price)).ceil();
+            let quantity = needed.min(self.available(user_id, base_asset).floor());
+            self.submit_closing_order(user_id, market, Side::Sell, price, quantity);
+        }
+        self.repay_all(user_id);
     }
 
-    async fn publish_ws_depth_updates(
-        &self,
-        fills: &Vec<OrderbookFill>,
-        price: &str,
+    fn repay_all(&mut self, user_id: &str) {
+        let assets: Vec<String> = self
+            .balances
+            .get(user_id)
+            .into_iter()
+            .flatten()
+            .filter(|(_, balance)| !balance.liabilities().is_zero())
+            .map(|(asset, _)| asset.clone())
+            .collect();
+        for asset in assets {
+            self.repay(user_id, &asset, Decimal::MAX);
+        }
+    }
+
+    fn available(&self, user_id: &str, asset: &str) -> Decimal {
+        self.balances
+            .get(user_id)
+            .and_then(|user_balance| user_balance.get(asset))
+            .map_or(Decimal::ZERO, |balance| balance.available)
+    }
+
+    fn liabilities(&self, user_id: &str, asset: &str) -> Decimal {
+        self.balances
+            .get(user_id)
+            .and_then(|user_balance| user_balance.get(asset))
+            .map_or(Decimal::ZERO, |balance| balance.liabilities())
+    }
+
+    // The limit a closing order needs to sweep every level on the other side.
+    fn sweep_price(&self, market: &str, side: &Side) -> Option<u64> {
+        let orderbook = self.orderbooks.iter().find(|ob| ob.ticker() == market)?;
+        match side {
+            Side::Buy => orderbook.asks.keys().next_back().copied(),
+            Side::Sell => orderbook.bids.keys().next().copied(),
+        }
+    }
+
+    fn submit_closing_order(
+        &mut self,
+        user_id: &str,
         market: &str,
-        side: &Side,
+        side: Side,
+        price: u64,
+        quantity: Decimal,
     ) {
-        let depth = match self.orderbooks.iter().find(|ob| ob.ticker() == market) {
-            Some(orderbook) => orderbook.get_depth(),
-            None => {
-                eprintln!("Orderbook not found");
-                return;
+        let Some(quantity) = quantity.to_u64().filter(|&q| q > 0) else {
+            return;
+        };
+        let payload = InternalCreateOrderPayload {
+            market: market.to_string(),
+            price,
+            quantity,
+            price_decimal: Decimal::from(price),
+            quantity_decimal: Decimal::from(quantity),
+            side,
+            user_id: user_id.to_string(),
+        };
+        match self.create_order(payload) {
+            Ok(MessageToApi::OrderPlaced(placed)) => {
+                if placed.executed_qty < quantity {
+                    self.cancel_order(market, &placed.order_id);
+                }
             }
+            Ok(_) => {}
+            Err(reason) => warn!("Liquidation order rejected: {:?}", reason),
+        }
+    }
+
+    fn check_risk_limits(
+        &mut self,
+        payload: &InternalCreateOrderPayload,
+    ) -> Result<(), RejectReason> {
+        let Some(user_risk) = self.risk.get_mut(&payload.user_id) else {
+            return Ok(());
         };
-        let fill_prices: Vec<String> = fills
-            .iter()
-            .map(|f| &f.fill.price_string)
-            .cloned()
-            .collect();
-        if let Side::Buy = side {
-            let updated_asks: Vec<[String; 2]> = depth
-                .asks
+        let exposure = OrderExposure {
+            open_orders_in_market: self
+                .orderbooks
                 .iter()
-                .filter(|x| x.get(0).map_or(false, |p| fill_prices.contains(p)))
-                .map(|x| [x[0].clone(), x[1].clone()])
-                .collect();
-            let updated_bids = depth
-                .bids
+                .find(|ob| ob.ticker() == payload.market)
+                .map_or(0, |orderbook| orderbook.open_order_count(&payload.user_id)),
+            order_notional: payload.price_decimal * payload.quantity_decimal,
+            open_notional: self
+                .orderbooks
                 .iter()
-                .filter(|x: &&[String; 2]| x[0] == price)
-                .map(|x| [x[0].clone(), x[1].clone()])
-                .collect();
-            println!("publishing updated depth");
-            if let Err(e) = RedisManager::get_instance()
-                .await
-                .publish_message(
-                    format!("depth@{}", market),
-                    WsMessage {
-                        stream: format!("depth@{}", market),
-                        data: WsPayload::Depth(DepthUpdateMessage {
-                            e: "depth".to_string(),
-                            a: updated_asks,
-                            b: updated_bids,
-                        }),
-                    },
-                )
-                .await
-            {
-                eprintln!("Failed to send depth update message to Redis: {:?}", e);
+                .map(|orderbook| orderbook.open_notional(&payload.user_id))
+                .sum(),
+        };
+        user_risk.check_order(self.clock.now(), &exposure)
+    }
+
+    // Cool-downs are checked lazily as messages arrive rather than on a timer.
+    fn resume_expired_halts(&mut self) {
+        let now = self.clock.now();
+        let expired: Vec<(String, MarketStatus)> = self
+            .orderbooks
+            .iter_mut()
+            .filter_map(|orderbook| {
+                let status = orderbook.protection.take_expired_trip(now)?;
+                Some((orderbook.ticker(), status))
+            })
+            .collect();
+        for (market, status) in expired {
+            info!("Circuit breaker cool-down over for {}", market);
+            self.set_market_status(&market, status);
+        }
+    }
+
+    fn check_circuit_breaker(&mut self, market: &str, fills: &[OrderbookFill]) {
+        let Some(orderbook) = self.orderbooks.iter_mut().find(|ob| ob.ticker() == market) else {
+            return;
+        };
+        let now = self.clock.now();
+        let tripped = orderbook
+            .protection
+            .record_trades(now, fills.iter().map(|fill| fill.fill.price_u64));
+        if tripped && orderbook.status.accepts_orders().is_ok() {
+            info!("Circuit breaker tripped for {}", market);
+            orderbook.protection.trip(now, orderbook.status);
+            self.set_market_status(market, MarketStatus::Halted);
+        }
+    }
+
+    fn check_cancels_allowed(&self, market: &str) -> Result<(), RejectReason> {
+        match self.orderbooks.iter().find(|ob| ob.ticker() == market) {
+            Some(orderbook) => orderbook.status.accepts_cancels(),
+            None => Err(RejectReason::MarketNotFound),
+        }
+    }
+
+    // Returns the status the market ends up in. Delisting is final.
+    fn set_market_status(&mut self, market: &str, status: MarketStatus) -> Option<MarketStatus> {
+        let orderbook = match self.orderbooks.iter_mut().find(|ob| ob.ticker() == market) {
+            Some(orderbook) => orderbook,
+            None => {
+                warn!("Orderbook not found");
+                return None;
             }
-        } else {
-            let updated_bids: Vec<[String; 2]> = depth
-                .bids
-                .iter()
-                .filter(|x| x.get(0).map_or(false, |price| fill_prices.contains(price)))
-                .map(|x| [x[0].clone(), x[1].clone()])
-                .collect();
-            let updated_asks = depth
-                .asks
-                .iter()
-                .filter(|x: &&[String; 2]| x[0] == price)
-                .map(|x| [x[0].clone(), x[1].clone()])
-                .collect();
-            println!("publishing updated depth");
-            if let Err(e) = RedisManager::get_instance()
-                .await
-                .publish_message(
-                    format!("depth@{}", market),
-                    WsMessage {
-                        stream: format!("depth@{}", market),
-                        data: WsPayload::Depth(DepthUpdateMessage {
-                            e: "depth".to_string(),
-                            a: updated_asks,
-                            b: updated_bids,
-                        }),
-                    },
-                )
-                .await
-            {
-                eprintln!("Failed to send depth update message to Redis: {:?}", e);
+        };
+        let previous = orderbook.status;
+        if previous == MarketStatus::Delisted || previous == status {
+            return Some(previous);
+        }
+        orderbook.status = status;
+        info!("Market {} status {:?} -> {:?}", market, previous, status);
+
+        match status {
+            // The book may still be crossed from an auction that was interrupted by a
+            // halt, so uncross whenever matching resumes.
+            MarketStatus::Trading | MarketStatus::PostOnly => {
+                let fills = orderbook.uncross();
+                self.settle_auction(market, &fills);
+            }
+            MarketStatus::Auction => self.publish_auction_update(market),
+            MarketStatus::Delisted => {
+                let order_ids: Vec<String> = orderbook
+                    .resting_orders()
+                    .map(|order| order.order_id.clone())
+                    .collect();
+                for order_id in order_ids {
+                    self.cancel_order(market, &order_id);
+                }
             }
+            MarketStatus::Halted | MarketStatus::CancelOnly => {}
         }
+
+        self.outputs.publish_message(
+            format!("status@{}", market),
+            WsMessage {
+                stream: format!("status@{}", market),
+                data: WsPayload::Status(StatusUpdateMessage {
+                    e: "status".to_string(),
+                    s: market.to_string(),
+                    status,
+                }),
+            },
+        );
+        Some(status)
     }
 
-    async fn publish_ws_trades(&self, fills: &Vec<OrderbookFill>, market: &str, side: &Side) {
-        for fill in fills.iter() {
-            let is_buyer_maker = matches!(side, Side::Sell);
-            if let Err(e) = RedisManager::get_instance()
-                .await
-                .publish_message(
-                    format!("trades@{}", market),
-                    WsMessage {
-                        stream: format!("trades@{}", market),
-                        data: WsPayload::Trade(TradeUpdateMessage {
-                            e: "trade".to_string(),
-                            t: fill.fill.trade_id,
-                            m: is_buyer_maker,
-                            p: fill.fill.price_string.clone(),
-                            q: fill.fill.qty.to_string(),
-                            s: market.to_string(),
-                        }),
-                    },
-                )
-                .await
-            {
-                eprintln!("Failed to publish trade message to Redis: {:?}", e);
+    fn settle_auction(&mut self, market: &str, fills: &[AuctionFill]) {
+        let orderbook = self
+            .orderbooks
+            .iter()
+            .find(|ob| ob.ticker() == market)
+            .unwrap();
+        let base_asset = orderbook.base_asset.clone();
+        let quote_asset = orderbook.quote_asset.clone();
+        let is_perpetual = orderbook.perpetual.is_some();
+        let timestamp = self.clock.utc().to_string();
+
+        let mut bid_prices = Vec::new();
+        let mut ask_prices = Vec::new();
+        for auction_fill in fills {
+            let quantity = Self::u64_to_decimal(auction_fill.fill.qty);
+            if is_perpetual {
+                self.settle_perpetual_fill(
+                    market,
+                    (&auction_fill.buyer_user_id, auction_fill.buy_order.price),
+                    (&auction_fill.seller_user_id, auction_fill.sell_order.price),
+                    auction_fill.fill.qty,
+                    auction_fill.fill.price_u64,
+                );
+            } else {
+                self.settle_fill(
+                    &auction_fill.buyer_user_id,
+                    &auction_fill.seller_user_id,
+                    &base_asset,
+                    &quote_asset,
+                    quantity,
+                    auction_fill.fill.price_decimal * quantity,
+                    Decimal::from(auction_fill.buy_order.price) * quantity,
+                );
+            }
+
+            let quote_quantity = auction_fill.fill.qty * auction_fill.fill.price_u64;
+            self.outputs.push_message(DbMessage {
+                db_message_type: DbMessageType::TradeAdded,
+                data: DbMessageData::TradeAdd(TradeAdd {
+                    id: Self::db_trade_id(market, auction_fill.fill.trade_id),
+                    is_buyer_maker: false,
+                    price: auction_fill.fill.price_string.clone(),
+                    quantity: auction_fill.fill.qty.to_string(),
+                    quote_quantity: quote_quantity.to_string(),
+                    timestamp: timestamp.clone(),
+                    market: market.to_string(),
+                }),
+            });
+            for order in [&auction_fill.buy_order, &auction_fill.sell_order] {
+                self.outputs.push_message(DbMessage {
+                    db_message_type: DbMessageType::OrderUpdate,
+                    data: DbMessageData::OrderUpdate(OrderUpdate {
+                        order_id: order.order_id.clone(),
+                        executed_quantity: order.filled,
+                        price: Some(order.price.to_string()),
+                        market: Some(market.to_string()),
+                        quantity: Some(order.quantity.to_string()),
+                        side: Some(order.side.clone()),
+                    }),
+                });
             }
+            self.outputs.publish_message(
+                format!("trades@{}", market),
+                WsMessage {
+                    stream: format!("trades@{}", market),
+                    data: WsPayload::Trade(TradeUpdateMessage {
+                        e: "trade".to_string(),
+                        t: auction_fill.fill.trade_id,
+                        m: false,
+                        p: auction_fill.fill.price_string.clone(),
+                        q: auction_fill.fill.qty.to_string(),
+                        s: market.to_string(),
+                    }),
+                },
+            );
+
+            bid_prices.push(auction_fill.buy_order.price);
+            ask_prices.push(auction_fill.sell_order.price);
+        }
+
+        if !fills.is_empty() {
+            self.publish_depth_levels(market, bid_prices, ask_prices);
         }
+        self.record_ticker_trades(
+            market,
+            fills
+                .iter()
+                .map(|auction_fill| (auction_fill.fill.price_u64, auction_fill.fill.qty)),
+        );
     }
 
-    async fn create_db_trades(
-        &self,
-        fills: &Vec<OrderbookFill>,
-        market: &str,
-        side: &Side,
-        timestamp: &str,
-    ) {
-        for fill in fills.iter() {
-            let quote_quantity = fill.fill.qty.checked_mul(fill.fill.price_u64).unwrap();
-            if let Err(e) = RedisManager::get_instance()
-                .await
-                .push_message(DbMessage {
-                    db_message_type: DbMessageType::TradeAdded,
-                    data: DbMessageData::TradeAdd(TradeAdd {
-                        id: fill.fill.trade_id.to_string(),
-                        is_buyer_maker: matches!(side, Side::Sell),
-                        price: fill.fill.price_string.clone(),
-                        quantity: fill.fill.qty.to_string(),
-                        quote_quantity: quote_quantity.to_string(),
-                        timestamp: timestamp.to_string(),
-                        market: market.to_string(),
+    // Adds trades to the market's rolling 24h ticker and publishes the new values.
+    fn record_ticker_trades(&mut self, market: &str, trades: impl Iterator<Item = (u64, u64)>) {
+        let Some(orderbook) = self.orderbooks.iter_mut().find(|ob| ob.ticker() == market) else {
+            return;
+        };
+        let now = self.clock.now();
+        let mut fills = 0;
+        for (price, quantity) in trades {
+            orderbook.ticker_stats.record_trade(now, price, quantity);
+            fills += 1;
+        }
+        if fills == 0 {
+            return;
+        }
+        counter!("engine_fills_total", "market" => market.to_string()).increment(fills);
+
+        let ticker = Self::ticker_message(orderbook, now);
+        self.outputs.publish_message(
+            format!("ticker@{}", market),
+            WsMessage {
+                stream: format!("ticker@{}", market),
+                data: WsPayload::Ticker(ticker),
+            },
+        );
+    }
+
+    fn ticker_message(orderbook: &Orderbook, now: Instant) -> TickerUpdateMessage {
+        let summary = orderbook.ticker_stats.summary(now);
+        let field = |value: fn(&TickerSummary) -> String| summary.as_ref().map(value);
+        TickerUpdateMessage {
+            o: field(|summary| summary.open.to_string()),
+            c: field(|summary| summary.close.to_string()),
+            h: field(|summary| summary.high.to_string()),
+            l: field(|summary| summary.low.to_string()),
+            v: field(|summary| summary.volume.to_string()),
+            V: field(|summary| summary.quote_volume.to_string()),
+            s: Some(orderbook.ticker()),
+            id: orderbook.last_trade_id,
+            e: TICKER_UPDATE.to_string(),
+        }
+    }
+
+    // Everything a message changed in a book goes out as one batch per market.
+    fn publish_l3_events(&mut self) {
+        for orderbook in self.orderbooks.iter_mut() {
+            let Some((first_update_id, events)) = orderbook.l3.take() else {
+                continue;
+            };
+            let market = orderbook.ticker();
+            self.outputs.publish_message(
+                format!("l3@{}", market),
+                WsMessage {
+                    stream: format!("l3@{}", market),
+                    data: WsPayload::L3(L3UpdateMessage {
+                        e: "l3".to_string(),
+                        s: market,
+                        U: first_update_id,
+                        u: orderbook.l3.update_id,
+                        events,
                     }),
-                })
-                .await
-            {
-                eprintln!("Failed to push trade added message to Redis: {:?}", e);
-            }
+                },
+            );
         }
     }
 
-    async fn update_db_orders(
-        &self,
-        order: &Order,
-        executed_quantity: u64,
-        fills: &Vec<OrderbookFill>,
+    // Sends the current size of each given level, with "0" for levels that emptied.
+    // Every level sent takes the next depth update id of the market, so clients can
+    // line diffs up with a snapshot and notice when one went missing.
+    fn publish_depth_levels(
+        &mut self,
         market: &str,
+        mut bid_prices: Vec<u64>,
+        mut ask_prices: Vec<u64>,
     ) {
-        if let Err(e) = RedisManager::get_instance()
-            .await
-            .push_message(DbMessage {
-                db_message_type: DbMessageType::OrderUpdate,
-                data: DbMessageData::OrderUpdate(OrderUpdate {
-                    order_id: order.order_id.clone(),
-                    executed_quantity,
-                    price: Some(order.price.to_string()),
-                    market: Some(market.to_string()),
-                    quantity: Some(order.quantity.to_string()),
-                    side: Some(order.side.clone()),
-                }),
-            })
-            .await
-        {
-            eprintln!("Failed to push order update message to Redis: {:?}", e);
+        let orderbook = match self.orderbooks.iter_mut().find(|ob| ob.ticker() == market) {
+            Some(orderbook) => orderbook,
+            None => {
+                warn!("Orderbook not found");
+                return;
+            }
+        };
+        bid_prices.sort_unstable_by(|a, b| b.cmp(a));
+        bid_prices.dedup();
+        ask_prices.sort_unstable();
+        ask_prices.dedup();
+        let levels = (bid_prices.len() + ask_prices.len()) as u64;
+        if levels == 0 {
+            return;
         }
-        for fill in fills.iter() {
-            if let Err(e) = RedisManager::get_instance()
-                .await
-                .push_message(DbMessage {
-                    db_message_type: DbMessageType::OrderUpdate,
-                    data: DbMessageData::OrderUpdate(OrderUpdate {
-                        order_id: fill.fill.trade_id.to_string(),
-                        executed_quantity: fill.fill.qty,
-                        price: None,
-                        market: None,
-                        quantity: None,
-                        side: None,
+        let first_update_id = orderbook.depth_update_id + 1;
+        orderbook.depth_update_id += levels;
+        let last_update_id = orderbook.depth_update_id;
+        let orderbook = &*orderbook;
+
+        // Aggregated streams carry the same update ids, so the sync rules hold for
+        // every variant.
+        for precision in std::iter::once(1).chain(DEPTH_STREAM_PRECISIONS) {
+            let buckets = |side: &Side, prices: &[u64]| {
+                let mut buckets: Vec<u64> = prices
+                    .iter()
+                    .map(|&price| Orderbook::bucket(side, price, precision))
+                    .collect();
+                buckets.dedup();
+                buckets
+                    .into_iter()
+                    .map(|bucket| {
+                        let quantity = orderbook.aggregated_level(side, bucket, precision);
+                        [bucket.to_string(), quantity.to_string()]
+                    })
+                    .collect()
+            };
+            let stream = match precision {
+                1 => format!("depth@{}", market),
+                _ => format!("depth@{}@{}", market, precision),
+            };
+            self.outputs.publish_message(
+                stream.clone(),
+                WsMessage {
+                    stream,
+                    data: WsPayload::Depth(DepthUpdateMessage {
+                        e: "depth".to_string(),
+                        U: first_update_id,
+                        u: last_update_id,
+                        c: orderbook.checksum(precision),
+                        b: buckets(&Side::Buy, &bid_prices),
+                        a: buckets(&Side::Sell, &ask_prices),
                     }),
-                })
-                .await
-            {
-                eprintln!("Failed to push order update message to Redis: {:?}", e);
-            }
+                },
+            );
         }
     }
 
-    pub fn get_random_id(&self) -> String {
-        use rand::Rng;
-        let mut rng = rand::thread_rng();
-        let id1 = rng.gen::<u64>();
-        let id2 = rng.gen::<u64>();
-        format!("{:x}{:x}", id1, id2)
+    fn publish_auction_update(&self, market: &str) {
+        let Some(orderbook) = self.orderbooks.iter().find(|ob| ob.ticker() == market) else {
+            return;
+        };
+        if orderbook.status != MarketStatus::Auction {
+            return;
+        }
+        let indicative = orderbook.indicative_uncross();
+        self.outputs.publish_message(
+            format!("auction@{}", market),
+            WsMessage {
+                stream: format!("auction@{}", market),
+                data: WsPayload::Auction(AuctionUpdateMessage {
+                    e: "auction".to_string(),
+                    p: indicative.map(|(price, _)| price.to_string()),
+                    q: indicative.map_or(0, |(_, volume)| volume).to_string(),
+                    s: market.to_string(),
+                }),
+            },
+        );
+    }
+
+    fn cancel_order(&mut self, market: &str, order_id: &str) -> Option<OrderCancelledPayload> {
+        let cancel_orderbook = match self.orderbooks.iter_mut().find(|ob| ob.ticker() == market) {
+            Some(ob) => ob,
+            None => {
+                warn!("Orderbook not found");
+                return None;
+            }
+        };
+        let order = match cancel_orderbook.get_order(order_id).cloned() {
+            Some(o) => o,
+            None => {
+                warn!("Order to be cancelled was not found");
+                return None;
+            }
+        };
+
+        let executed_qty = order.filled;
+        let remaining_qty = order.quantity - order.filled;
+        let price = match order.side {
+            Side::Buy => cancel_orderbook.cancel_bid(&order),
+            Side::Sell => cancel_orderbook.cancel_ask(&order),
+        }?;
+
+        let (asset, amount) = cancel_orderbook.lock_for(&order.side, order.price, remaining_qty);
+        let asset = asset.to_string();
+        let balance = self.balance_mut(&order.user_id, &asset);
+        balance.available += amount;
+        balance.locked -= amount;
+
+        self.send_updated_depth_at(&order.side, price, market);
+        self.publish_auction_update(market);
+        self.update_db_cancelled_order(
+            &order,
+            executed_qty,
+            remaining_qty,
+            market,
+            &self.clock.utc().to_string(),
+        );
+
+        Some(OrderCancelledPayload {
+            order_id: order.order_id,
+            executed_qty,
+            remaining_qty,
+        })
+    }
+
+    fn create_order(
+        &mut self,
+        payload: InternalCreateOrderPayload,
+    ) -> Result<MessageToApi, RejectReason> {
+        let orderbook = match self
+            .orderbooks
+            .iter()
+            .find(|ob| ob.ticker() == payload.market)
+        {
+            Some(orderbook) => orderbook,
+            None => return Err(RejectReason::MarketNotFound),
+        };
+        self.check_account(&payload.user_id)?;
+        orderbook.status.accepts_orders()?;
+        if orderbook.status == MarketStatus::PostOnly
+            && orderbook.would_cross(&payload.side, payload.price)
+        {
+            return Err(RejectReason::WouldTakeLiquidity);
+        }
+        if !orderbook
+            .protection
+            .within_band(payload.price, orderbook.current_price)
+        {
+            return Err(RejectReason::PriceOutsideBand);
+        }
+        let is_perpetual = orderbook.perpetual.is_some();
+        let (lock_asset, lock_amount) =
+            orderbook.lock_for(&payload.side, payload.price, payload.quantity);
+        let lock_asset = lock_asset.to_string();
+        let base_asset = orderbook.base_asset.clone();
+        let quote_asset = orderbook.quote_asset.clone();
+        self.check_risk_limits(&payload)?;
+
+        if !is_perpetual {
+            self.borrow_for_order(&payload.user_id, &lock_asset, lock_amount);
+        }
+        self.check_and_lock_funds(&payload.user_id, &lock_asset, lock_amount)
+            .map_err(|e| {
+                warn!("Failed to lock funds: {}", e);
+                RejectReason::InsufficientFunds
+            })?;
+
+        let mut order = Order {
+            price: payload.price,
+            quantity: payload.quantity,
+            order_id: self.next_id(),
+            filled: 0,
+            side: payload.side.clone(),
+            user_id: payload.user_id.clone(),
+        };
+
+        let orderbook = self
+            .orderbooks
+            .iter_mut()
+            .find(|ob| ob.ticker() == payload.market)
+            .unwrap();
+        let created = orderbook.add_order(&mut order);
+        if is_perpetual {
+            self.settle_perpetual_fills(
+                &payload.market,
+                &payload.user_id,
+                &payload.side,
+                payload.price,
+                &created.fills,
+            );
+        } else {
+            self.update_balances(
+                &payload.user_id,
+                &base_asset,
+                &quote_asset,
+                &payload.side,
+                payload.price_decimal,
+                &created.fills,
+            );
+        }
+
+        let timestamp = self.clock.utc().to_string();
+        self.create_db_trades(&created.fills, &payload.market, &payload.side, &timestamp);
+        self.update_db_orders(
+            &order,
+            created.executed_quantity,
+            &created.fills,
+            &payload.market,
+        );
+        self.publish_ws_depth_updates(
+            &created.fills,
+            payload.price,
+            &payload.market,
+            &payload.side,
+        );
+        self.publish_ws_trades(&created.fills, &payload.market, &payload.side);
+        self.record_ticker_trades(
+            &payload.market,
+            created
+                .fills
+                .iter()
+                .map(|fill| (fill.fill.price_u64, fill.fill.qty)),
+        );
+        self.publish_auction_update(&payload.market);
+        self.check_circuit_breaker(&payload.market, &created.fills);
+        Ok(MessageToApi::OrderPlaced(OrderPlacedPayload {
+            order_id: order.order_id,
+            executed_qty: created.executed_quantity,
+            fills: created
+                .fills
+                .iter()
+                .map(|fill| fill.fill.to_external_fill())
+                .collect(),
+        }))
+    }
+
+    fn update_balances(
+        &mut self,
+        user_id: &str,
+        base_asset: &str,
+        quote_asset: &str,
+        side: &Side,
+        limit_price: Decimal,
+        fills: &[OrderbookFill],
+    ) {
+        for fill in fills {
+            let fill_qty_decimal = Self::u64_to_decimal(fill.fill.qty);
+            let fill_amount_decimal = fill.fill.price_decimal * fill_qty_decimal;
+            match side {
+                // The taker locked quote at its own limit price, so any price improvement
+                // is released back to it while the maker ask is paid at the fill price.
+                Side::Buy => self.settle_fill(
+                    user_id,
+                    &fill.other_user_id,
+                    base_asset,
+                    quote_asset,
+                    fill_qty_decimal,
+                    fill_amount_decimal,
+                    limit_price * fill_qty_decimal,
+                ),
+                // Resting bids always fill at their own price, which is what they locked.
+                Side::Sell => self.settle_fill(
+                    &fill.other_user_id,
+                    user_id,
+                    base_asset,
+                    quote_asset,
+                    fill_qty_decimal,
+                    fill_amount_decimal,
+                    fill_amount_decimal,
+                ),
+            }
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn settle_fill(
+        &mut self,
+        buyer_id: &str,
+        seller_id: &str,
+        base_asset: &str,
+        quote_asset: &str,
+        quantity: Decimal,
+        quote_amount: Decimal,
+        buyer_locked_quote: Decimal,
+    ) {
+        let buyer_quote = self.balance_mut(buyer_id, quote_asset);
+        buyer_quote.locked -= buyer_locked_quote;
+        buyer_quote.available += buyer_locked_quote - quote_amount;
+        self.balance_mut(buyer_id, base_asset).available += quantity;
+
+        self.balance_mut(seller_id, base_asset).locked -= quantity;
+        self.balance_mut(seller_id, quote_asset).available += quote_amount;
+    }
+
+    fn balance_mut(&mut self, user_id: &str, asset: &str) -> &mut Balance {
+        if self.margin.enabled_users.contains(user_id) {
+            self.margin.unchecked.insert(user_id.to_string());
+        }
+        self.balances
+            .entry(user_id.to_string())
+            .or_default()
+            .entry(asset.to_string())
+            .or_default()
+    }
+
+    fn on_ramp(&mut self, user_id: &str, asset: &str, amount: Decimal) {
+        *self.asset_supply.entry(asset.to_string()).or_default() += amount;
+        self.balance_mut(user_id, asset).available += amount;
+    }
+
+    // Assets that can be deposited: anything spot markets trade or quote in.
+    fn is_listed_asset(&self, asset: &str) -> bool {
+        self.orderbooks
+            .iter()
+            .any(|ob| ob.quote_asset == asset || (ob.perpetual.is_none() && ob.base_asset == asset))
+    }
+
+    fn check_and_lock_funds(
+        &mut self,
+        user_id: &str,
+        asset: &str,
+        amount: Decimal,
+    ) -> Result<(), String> {
+        let user = match self.balances.get_mut(user_id) {
+            Some(user) => user,
+            None => return Err("User not found".to_string()),
+        };
+        let balance = match user.get_mut(asset) {
+            Some(balance) => balance,
+            None => return Err(format!("User {} balance not found", asset)),
+        };
+        if balance.available < amount {
+            return Err(format!("Insufficient {} balance", asset));
+        }
+
+        balance.available -= amount;
+        balance.locked += amount;
+        Ok(())
+    }
+
+    fn send_updated_depth_at(&mut self, side: &Side, price: u64, market: &str) {
+        match side {
+            Side::Buy => self.publish_depth_levels(market, vec![price], vec![]),
+            Side::Sell => self.publish_depth_levels(market, vec![], vec![price]),
+        }
+    }
+
+    // A taker touches the levels it filled against on the other side and the level
+    // its remainder rests at on its own side.
+    fn publish_ws_depth_updates(
+        &mut self,
+        fills: &[OrderbookFill],
+        price: u64,
+        market: &str,
+        side: &Side,
+    ) {
+        let fill_prices = fills.iter().map(|f| f.fill.price_u64).collect();
+        match side {
+            Side::Buy => self.publish_depth_levels(market, vec![price], fill_prices),
+            Side::Sell => self.publish_depth_levels(market, fill_prices, vec![price]),
+        }
+    }
+
+    fn publish_ws_trades(&self, fills: &[OrderbookFill], market: &str, side: &Side) {
+        for fill in fills.iter() {
+            let is_buyer_maker = matches!(side, Side::Sell);
+            self.outputs.publish_message(
+                format!("trades@{}", market),
+                WsMessage {
+                    stream: format!("trades@{}", market),
+                    data: WsPayload::Trade(TradeUpdateMessage {
+                        e: "trade".to_string(),
+                        t: fill.fill.trade_id,
+                        m: is_buyer_maker,
+                        p: fill.fill.price_string.clone(),
+                        q: fill.fill.qty.to_string(),
+                        s: market.to_string(),
+                    }),
+                },
+            );
+        }
+    }
+
+    // Trade ids count up per book from 1, so the stored id carries the market to
+    // stay unique across books.
+    fn db_trade_id(market: &str, trade_id: u64) -> String {
+        format!("{}-{}", market, trade_id)
+    }
+
+    fn create_db_trades(
+        &self,
+        fills: &[OrderbookFill],
+        market: &str,
+        side: &Side,
+        timestamp: &str,
+    ) {
+        for fill in fills.iter() {
+            let quote_quantity = fill.fill.qty.checked_mul(fill.fill.price_u64).unwrap();
+            self.outputs.push_message(DbMessage {
+                db_message_type: DbMessageType::TradeAdded,
+                data: DbMessageData::TradeAdd(TradeAdd {
+                    id: Self::db_trade_id(market, fill.fill.trade_id),
+                    is_buyer_maker: matches!(side, Side::Sell),
+                    price: fill.fill.price_string.clone(),
+                    quantity: fill.fill.qty.to_string(),
+                    quote_quantity: quote_quantity.to_string(),
+                    timestamp: timestamp.to_string(),
+                    market: market.to_string(),
+                }),
+            });
+        }
+    }
+
+    fn update_db_orders(
+        &self,
+        order: &Order,
+        executed_quantity: u64,
+        fills: &[OrderbookFill],
+        market: &str,
+    ) {
+        self.outputs.push_message(DbMessage {
+            db_message_type: DbMessageType::OrderUpdate,
+            data: DbMessageData::OrderUpdate(OrderUpdate {
+                order_id: order.order_id.clone(),
+                executed_quantity,
+                price: Some(order.price.to_string()),
+                market: Some(market.to_string()),
+                quantity: Some(order.quantity.to_string()),
+                side: Some(order.side.clone()),
+            }),
+        });
+        for fill in fills.iter() {
+            self.outputs.push_message(DbMessage {
+                db_message_type: DbMessageType::OrderUpdate,
+                data: DbMessageData::OrderUpdate(OrderUpdate {
+                    order_id: fill.maker_order_id.clone(),
+                    executed_quantity: fill.maker_filled,
+                    price: None,
+                    market: None,
+                    quantity: None,
+                    side: None,
+                }),
+            });
+        }
+    }
+
+    fn update_db_cancelled_order(
+        &self,
+        order: &Order,
+        executed_quantity: u64,
+        remaining_quantity: u64,
+        market: &str,
+        timestamp: &str,
+    ) {
+        self.outputs.push_message(DbMessage {
+            db_message_type: DbMessageType::OrderCancelled,
+            data: DbMessageData::OrderCancelled(OrderCancelled {
+                order_id: order.order_id.clone(),
+                market: market.to_string(),
+                executed_quantity,
+                remaining_quantity,
+                timestamp: timestamp.to_string(),
+            }),
+        });
+    }
+
+    fn total_holdings(balances: &HashMap<String, UserBalance>) -> HashMap<String, Decimal> {
+        let mut totals: HashMap<String, Decimal> = HashMap::new();
+        for user_balance in balances.values() {
+            for (asset, balance) in user_balance {
+                *totals.entry(asset.clone()).or_default() += balance.available + balance.locked;
+            }
+        }
+        totals
+    }
+
+    // Money is neither created nor destroyed by trading, and every locked unit is
+    // backed by a resting order that still needs it.
+    pub fn check_invariants(&self) -> Result<(), String> {
+        let mut holdings = Self::total_holdings(&self.balances);
+        // Collateral backing open positions and the perpetual settlement pools are
+        // still quote asset owned by someone
+        for orderbook in &self.orderbooks {
+            if let Some(perpetual) = &orderbook.perpetual {
+                let margins: Decimal = perpetual.positions.values().map(|p| p.margin).sum();
+                *holdings.entry(orderbook.quote_asset.clone()).or_default() +=
+                    margins + perpetual.settlement_pool;
+            }
+        }
+        let mut liabilities: HashMap<&str, Decimal> = HashMap::new();
+        for user_balance in self.balances.values() {
+            for (asset, balance) in user_balance {
+                *liabilities.entry(asset.as_str()).or_default() += balance.liabilities();
+            }
+        }
+        for asset in holdings.keys().chain(self.asset_supply.keys()) {
+            let held = holdings.get(asset).copied().unwrap_or_default();
+            // Borrowed units are owed back, and interest charged is owed on top of them
+            let net = held - liabilities.get(asset.as_str()).copied().unwrap_or_default()
+                + self
+                    .margin
+                    .interest_charged
+                    .get(asset)
+                    .copied()
+                    .unwrap_or_default();
+            let supply = self.asset_supply.get(asset).copied().unwrap_or_default();
+            if net != supply {
+                return Err(format!(
+                    "{} holdings {} net of margin debt do not match supply {}",
+                    asset, net, supply
+                ));
+            }
+        }
+
+        let mut required_locks: HashMap<(&str, &str), Decimal> = HashMap::new();
+        for orderbook in &self.orderbooks {
+            for order in orderbook.resting_orders() {
+                let (asset, amount) =
+                    orderbook.lock_for(&order.side, order.price, order.quantity - order.filled);
+                *required_locks
+                    .entry((order.user_id.as_str(), asset))
+                    .or_default() += amount;
+            }
+        }
+
+        for (user_id, user_balance) in &self.balances {
+            for (asset, balance) in user_balance {
+                if balance.borrowed.is_sign_negative() || balance.interest.is_sign_negative() {
+                    return Err(format!(
+                        "user {} has negative {} debt: {} borrowed, {} interest",
+                        user_id, asset, balance.borrowed, balance.interest
+                    ));
+                }
+                if balance.available.is_sign_negative() {
+                    return Err(format!(
+                        "user {} has negative available {}: {}",
+                        user_id, asset, balance.available
+                    ));
+                }
+                let required = required_locks
+                    .remove(&(user_id.as_str(), asset.as_str()))
+                    .unwrap_or_default();
+                if balance.locked != required {
+                    return Err(format!(
+                        "user {} has {} {} locked but resting orders require {}",
+                        user_id, balance.locked, asset, required
+                    ));
+                }
+            }
+        }
+        if let Some(((user_id, asset), required)) = required_locks.into_iter().next() {
+            return Err(format!(
+                "user {} has resting orders requiring {} {} but no balance",
+                user_id, required, asset
+            ));
+        }
+
+        Ok(())
+    }
+
+    // Ids are derived from the journal entry being processed so every replica hands
+    // out the same ones. Messages that did not come through the journal, as in
+    // tests, get random ids.
+    fn next_id(&mut self) -> String {
+        if let Some((journal_id, issued)) = &mut self.id_source {
+            *issued += 1;
+            return format!("{}-{}", journal_id, issued);
+        }
+        use rand::Rng;
+        let mut rng = rand::thread_rng();
+        let id1 = rng.gen::<u64>();
+        let id2 = rng.gen::<u64>();
+        format!("{:x}{:x}", id1, id2)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::perpetual::{PerpetualConfig, PerpetualState};
+    use super::super::CHECKSUM_LEVELS;
+    use super::*;
+    use crate::journal::IncomingMessage;
+    use crate::publisher::Output;
+    use engine::types::{
+        AdjustBalancePayload, CancelAllOrdersPayload, CancelOrderPayload, CircuitBreakerConfig,
+        CreateOrderPayload, DepthPayload, L3Event, L3Order, L3SnapshotPayload, L3UpdateMessage,
+        MarginConfig, MarginEnabledPayload, OnRampPayload, PriceProtectionPayload,
+        RepayMarginPayload, RiskLimits, SetMarketStatusPayload, SubAccountPayload, Traced,
+        TransferPayload,
+    };
+    use metrics_exporter_prometheus::PrometheusBuilder;
+    use proptest::prelude::*;
+    use std::collections::{BTreeMap, HashSet};
+    use std::time::Duration;
+    use tokio::sync::mpsc::UnboundedReceiver;
+
+    const MARKET: &str = "TATA_INR";
+    const QUOTE: &str = "INR";
+    const PERP_MARKET: &str = "TATA-PERP_INR";
+    const USERS: usize = 4;
+    // Accounts random flow acts for. The sub-accounts only exist once created.
+    const ACCOUNTS: [&str; 6] = ["0", "1", "2", "3", "0:hedge", "1:hedge"];
+
+    #[derive(Debug, Clone)]
+    enum Op {
+        Place(usize, bool, u64, u64),
+        Cancel(usize, usize),
+        CancelAll(usize),
+        OnRamp(usize, u64),
+        SetStatus(MarketStatus),
+        EnableMargin(usize),
+        Repay(usize, bool),
+        Transfer(usize, usize, bool, u64),
+        CreateSubAccount(usize),
+    }
+
+    fn op_strategy() -> impl Strategy<Value = Op> {
+        prop_oneof![
+            6 => (0..ACCOUNTS.len(), any::<bool>(), 1u64..=10, 1u64..=10)
+                .prop_map(|(u, b, p, q)| Op::Place(u, b, p, q)),
+            2 => (0..ACCOUNTS.len(), any::<usize>()).prop_map(|(u, i)| Op::Cancel(u, i)),
+            1 => (0..ACCOUNTS.len()).prop_map(Op::CancelAll),
+            1 => (0..ACCOUNTS.len(), 1u64..=1000).prop_map(|(u, a)| Op::OnRamp(u, a)),
+            1 => prop_oneof![
+                4 => Just(MarketStatus::Trading),
+                2 => Just(MarketStatus::Auction),
+                1 => Just(MarketStatus::Halted),
+                1 => Just(MarketStatus::CancelOnly),
+                1 => Just(MarketStatus::PostOnly),
+                1 => Just(MarketStatus::Delisted),
+            ]
+            .prop_map(Op::SetStatus),
+            1 => (0..ACCOUNTS.len()).prop_map(Op::EnableMargin),
+            1 => (0..ACCOUNTS.len(), any::<bool>()).prop_map(|(u, quote)| Op::Repay(u, quote)),
+            1 => (0..ACCOUNTS.len(), 0..ACCOUNTS.len(), any::<bool>(), 1u64..=200)
+                .prop_map(|(from, to, quote, a)| Op::Transfer(from, to, quote, a)),
+            1 => (0..USERS).prop_map(Op::CreateSubAccount),
+        ]
+    }
+
+    struct Harness {
+        engine: Engine,
+        outputs_rx: UnboundedReceiver<Traced<Output>>,
+    }
+
+    fn harness() -> Harness {
+        let (outputs, outputs_rx) = OutputSender::channel();
+        let mut engine = Engine::new(outputs);
+        engine.balances.clear();
+        for user in 0..USERS {
+            for asset in [QUOTE, "TATA"] {
+                engine.balance_mut(&user.to_string(), asset).available = Decimal::from(500);
+            }
+        }
+        engine.asset_supply = Engine::total_holdings(&engine.balances);
+        Harness { engine, outputs_rx }
+    }
+
+    // Harness for random flow on `market`, with every user holding its quote asset.
+    // Margin interest is steep enough to matter within a run, and on the perpetual
+    // funding falls due on every message.
+    fn flow_harness(market: &str, index_price: u64) -> Harness {
+        let mut harness = harness();
+        let engine = &mut harness.engine;
+        let quote = book(engine, market).quote_asset.clone();
+        for user in 0..USERS {
+            engine.balance_mut(&user.to_string(), &quote).available = Decimal::from(500);
+        }
+        engine.asset_supply = Engine::total_holdings(&engine.balances);
+        send_admin(
+            engine,
+            AdminCommand::SetMarginConfig(MarginConfig {
+                max_leverage: Decimal::from(3),
+                liquidation_margin_level: Decimal::new(11, 1),
+                hourly_interest_rate: Decimal::from(100),
+            }),
+        );
+        if market == PERP_MARKET {
+            engine.orderbooks[1].perpetual = Some(PerpetualState::new(
+                MARKET.to_string(),
+                PerpetualConfig {
+                    funding_interval: Duration::ZERO,
+                    ..Default::default()
+                },
+                engine.clock.now(),
+            ));
+            engine.orderbooks[0].current_price = index_price;
+        }
+        harness
+    }
+
+    fn send(engine: &mut Engine, message: MessageFromApi) {
+        let params = ProcessParams::from_api_message(message, "test".to_string()).unwrap();
+        engine.process(params);
+    }
+
+    fn send_admin(engine: &mut Engine, command: AdminCommand) {
+        let params = ProcessParams::from_admin_command(command, "admin".to_string()).unwrap();
+        engine.process(params);
+    }
+
+    fn place(engine: &mut Engine, user: usize, side: Side, price: u64, quantity: u64) {
+        place_as(engine, &user.to_string(), side, price, quantity);
+    }
+
+    fn place_as(engine: &mut Engine, account: &str, side: Side, price: u64, quantity: u64) {
+        send(
+            engine,
+            MessageFromApi::CreateOrder(CreateOrderPayload {
+                market: MARKET.to_string(),
+                price: price.to_string(),
+                quantity: quantity.to_string(),
+                side,
+                user_id: account.to_string(),
+            }),
+        );
+    }
+
+    fn apply(engine: &mut Engine, market: &str, op: Op) {
+        if let Some(message) = op_message(engine, market, op) {
+            engine.process(op_params(message));
+        }
+    }
+
+    fn op_params(message: IncomingMessage) -> ProcessParams {
+        match message {
+            IncomingMessage::Api(message) => {
+                ProcessParams::from_api_message(message, "test".to_string()).unwrap()
+            }
+            IncomingMessage::Admin(command) => {
+                ProcessParams::from_admin_command(command, "admin".to_string()).unwrap()
+            }
+        }
+    }
+
+    fn api(message: MessageFromApi) -> Option<IncomingMessage> {
+        Some(IncomingMessage::Api(message))
+    }
+
+    fn admin(command: AdminCommand) -> Option<IncomingMessage> {
+        Some(IncomingMessage::Admin(command))
+    }
+
+    fn book<'a>(engine: &'a Engine, market: &str) -> &'a Orderbook {
+        engine
+            .orderbooks
+            .iter()
+            .find(|orderbook| orderbook.ticker() == market)
+            .unwrap()
+    }
+
+    fn op_message(engine: &Engine, market: &str, op: Op) -> Option<IncomingMessage> {
+        let quote = book(engine, market).quote_asset.as_str();
+        match op {
+            Op::Place(user, is_buy, price, quantity) => {
+                api(MessageFromApi::CreateOrder(CreateOrderPayload {
+                    market: market.to_string(),
+                    price: price.to_string(),
+                    quantity: quantity.to_string(),
+                    side: if is_buy { Side::Buy } else { Side::Sell },
+                    user_id: ACCOUNTS[user].to_string(),
+                }))
+            }
+            Op::Cancel(user, index) => {
+                // Picked in queue order so replaying the ops on another engine cancels
+                // the same orders
+                let (bids, asks) = book(engine, market).top_orders(usize::MAX);
+                let open_orders: Vec<Order> = bids
+                    .into_iter()
+                    .chain(asks)
+                    .filter(|order| order.user_id == ACCOUNTS[user])
+                    .collect();
+                if open_orders.is_empty() {
+                    return None;
+                }
+                let order_id = open_orders[index % open_orders.len()].order_id.clone();
+                api(MessageFromApi::CancelOrder(CancelOrderPayload {
+                    order_id,
+                    market: market.to_string(),
+                }))
+            }
+            Op::CancelAll(user) => api(MessageFromApi::CancelAllOrders(CancelAllOrdersPayload {
+                market: market.to_string(),
+                user_id: ACCOUNTS[user].to_string(),
+            })),
+            Op::OnRamp(user, amount) => api(MessageFromApi::OnRamp(OnRampPayload {
+                asset: quote.to_string(),
+                amount: amount.to_string(),
+                user_id: ACCOUNTS[user].to_string(),
+                txn_id: "txn".to_string(),
+            })),
+            Op::SetStatus(status) => admin(AdminCommand::SetMarketStatus(SetMarketStatusPayload {
+                market: market.to_string(),
+                status,
+            })),
+            Op::EnableMargin(user) => admin(AdminCommand::SetMarginEnabled(MarginEnabledPayload {
+                user_id: ACCOUNTS[user].to_string(),
+                enabled: true,
+            })),
+            Op::Repay(user, quote_side) => api(MessageFromApi::RepayMargin(RepayMarginPayload {
+                user_id: ACCOUNTS[user].to_string(),
+                asset: if quote_side { quote } else { "TATA" }.to_string(),
+                amount: "1000".to_string(),
+            })),
+            Op::Transfer(from, to, quote_side, amount) => {
+                api(MessageFromApi::Transfer(TransferPayload {
+                    from_account: ACCOUNTS[from].to_string(),
+                    to_account: ACCOUNTS[to].to_string(),
+                    asset: if quote_side { quote } else { "TATA" }.to_string(),
+                    amount: amount.to_string(),
+                }))
+            }
+            Op::CreateSubAccount(user) => {
+                api(MessageFromApi::CreateSubAccount(SubAccountPayload {
+                    user_id: user.to_string(),
+                    name: "hedge".to_string(),
+                }))
+            }
+        }
+    }
+
+    // Checksum as a client computes it from its own copy of the book.
+    fn local_checksum(bids: &BTreeMap<String, String>, asks: &BTreeMap<String, String>) -> u32 {
+        let sorted = |side: &BTreeMap<String, String>| {
+            let mut levels: Vec<(u64, String)> = side
+                .iter()
+                .map(|(p, q)| (p.parse().unwrap(), format!("{}:{}", p, q)))
+                .collect();
+            levels.sort();
+            levels
+                .into_iter()
+                .map(|(_, entry)| entry)
+                .collect::<Vec<_>>()
+        };
+        let mut bid_entries = sorted(bids).into_iter().rev();
+        let mut ask_entries = sorted(asks).into_iter();
+        let mut entries = Vec::new();
+        for _ in 0..CHECKSUM_LEVELS {
+            entries.extend(bid_entries.next());
+            entries.extend(ask_entries.next());
+        }
+        crc32fast::hash(entries.join(":").as_bytes())
+    }
+
+    fn protect(
+        engine: &mut Engine,
+        band_bps: Option<u64>,
+        reference_price: Option<u64>,
+        circuit_breaker: Option<CircuitBreakerConfig>,
+    ) {
+        send_admin(
+            engine,
+            AdminCommand::SetPriceProtection(PriceProtectionPayload {
+                market: MARKET.to_string(),
+                band_bps,
+                reference_price,
+                circuit_breaker,
+            }),
+        );
+    }
+
+    // Applies depth diffs to a snapshot as a client would, checking each diff
+    // follows on from the last and that the result matches the book.
+    fn check_depth_replay(
+        orderbook: &Orderbook,
+        precision: u64,
+        snapshot: DepthPayload,
+        diffs: Vec<DepthUpdateMessage>,
+    ) -> Result<(), TestCaseError> {
+        let mut last_update_id = snapshot.last_update_id;
+        let mut bids: BTreeMap<String, String> =
+            snapshot.bids.into_iter().map(|[p, q]| (p, q)).collect();
+        let mut asks: BTreeMap<String, String> =
+            snapshot.asks.into_iter().map(|[p, q]| (p, q)).collect();
+
+        for diff in diffs {
+            prop_assert_eq!(diff.U, last_update_id + 1);
+            if precision == 1 {
+                prop_assert_eq!(diff.u - diff.U + 1, (diff.b.len() + diff.a.len()) as u64);
+            }
+            last_update_id = diff.u;
+            for (levels, side) in [(diff.b, &mut bids), (diff.a, &mut asks)] {
+                for [price, quantity] in levels {
+                    if quantity == "0" {
+                        side.remove(&price);
+                    } else {
+                        side.insert(price, quantity);
+                    }
+                }
+            }
+            prop_assert_eq!(local_checksum(&bids, &asks), diff.c);
+        }
+
+        let depth = orderbook.aggregated_depth(None, precision);
+        prop_assert_eq!(depth.last_update_id, last_update_id);
+        let levels = |side: &BTreeMap<String, String>| {
+            let mut levels: Vec<[String; 2]> =
+                side.iter().map(|(p, q)| [p.clone(), q.clone()]).collect();
+            levels.sort_by_key(|[p, _]| p.parse::<u64>().unwrap());
+            levels
+        };
+        let mut bid_levels = levels(&bids);
+        bid_levels.reverse();
+        prop_assert_eq!(depth.bids, bid_levels);
+        prop_assert_eq!(depth.asks, levels(&asks));
+        Ok(())
+    }
+
+    // Rebuilds the book from an L3 snapshot and the events after it, checking that
+    // every order sits where the book has it in its queue.
+    fn check_l3_rebuild(
+        orderbook: &Orderbook,
+        snapshot: L3SnapshotPayload,
+        updates: Vec<L3UpdateMessage>,
+    ) -> Result<(), TestCaseError> {
+        let mut last_update_id = snapshot.last_update_id;
+        // id -> (is_bid, price, remaining), plus the ids in arrival order
+        let mut orders: HashMap<u64, (bool, u64, u64)> = HashMap::new();
+        let mut arrival: Vec<u64> = Vec::new();
+        for (is_bid, side) in [(true, snapshot.bids), (false, snapshot.asks)] {
+            for order in side {
+                orders.insert(order.id, (is_bid, order.price, order.quantity));
+                arrival.push(order.id);
+            }
+        }
+
+        for update in updates {
+            prop_assert_eq!(update.U, last_update_id + 1);
+            prop_assert_eq!(update.u - update.U + 1, update.events.len() as u64);
+            last_update_id = update.u;
+            for event in update.events {
+                match event {
+                    L3Event::Add {
+                        id,
+                        side,
+                        price,
+                        quantity,
+                    } => {
+                        orders.insert(id, (matches!(side, Side::Buy), price, quantity));
+                        arrival.push(id);
+                    }
+                    L3Event::Execute { id, quantity } => {
+                        let remaining = &mut orders.get_mut(&id).unwrap().2;
+                        *remaining -= quantity;
+                        if *remaining == 0 {
+                            orders.remove(&id);
+                        }
+                    }
+                    L3Event::Cancel { id } => {
+                        prop_assert!(orders.remove(&id).is_some());
+                    }
+                }
+            }
+        }
+
+        let rebuilt = |is_bid: bool| {
+            let mut side: Vec<(u64, usize, u64)> = arrival
+                .iter()
+                .enumerate()
+                .filter_map(|(position, id)| {
+                    let &(bid, price, _) = orders.get(id)?;
+                    (bid == is_bid).then_some((price, position, *id))
+                })
+                .collect();
+            side.sort_by_key(|&(price, position, _)| {
+                (if is_bid { u64::MAX - price } else { price }, position)
+            });
+            side.into_iter()
+                .map(|(price, _, id)| (id, price, orders[&id].2))
+                .collect::<Vec<_>>()
+        };
+        let snapshot = orderbook.l3_snapshot();
+        let flatten = |orders: &[L3Order]| {
+            orders
+                .iter()
+                .map(|o| (o.id, o.price, o.quantity))
+                .collect::<Vec<_>>()
+        };
+        prop_assert_eq!(snapshot.last_update_id, last_update_id);
+        prop_assert_eq!(flatten(&snapshot.bids), rebuilt(true));
+        prop_assert_eq!(flatten(&snapshot.asks), rebuilt(false));
+        Ok(())
+    }
+
+    fn depth_diffs(harness: &mut Harness, channel: &str) -> Vec<DepthUpdateMessage> {
+        let mut diffs = Vec::new();
+        while let Ok(Traced {
+            message: output, ..
+        }) = harness.outputs_rx.try_recv()
+        {
+            if let Output::Ws(
+                name,
+                WsMessage {
+                    data: WsPayload::Depth(diff),
+                    ..
+                },
+            ) = output
+            {
+                if name == channel {
+                    diffs.push(diff);
+                }
+            }
+        }
+        diffs
+    }
+
+    // 24h ticker over trades all made within the window.
+    fn ticker_summary(trades: &[(u64, u64)]) -> Option<TickerSummary> {
+        let prices = || trades.iter().map(|&(price, _)| price);
+        Some(TickerSummary {
+            open: trades.first()?.0,
+            high: prices().max()?,
+            low: prices().min()?,
+            close: trades.last()?.0,
+            volume: trades.iter().map(|&(_, q)| Decimal::from(q)).sum(),
+            quote_volume: trades.iter().map(|&(p, q)| Decimal::from(p * q)).sum(),
+        })
+    }
+
+    // Everything the engine has emitted since the last call, split into DB messages
+    // and API replies
+    fn drain(harness: &mut Harness) -> (Vec<DbMessage>, Vec<MessageToApi>) {
+        let mut db_messages = Vec::new();
+        let mut replies = Vec::new();
+        while let Ok(traced) = harness.outputs_rx.try_recv() {
+            match traced.message {
+                Output::Db(message) => db_messages.push(message),
+                Output::Api(_, message) => replies.push(message),
+                _ => {}
+            }
+        }
+        (db_messages, replies)
+    }
+
+    fn placed_order_id(replies: &[MessageToApi]) -> String {
+        replies
+            .iter()
+            .find_map(|reply| match reply {
+                MessageToApi::OrderPlaced(placed) => Some(placed.order_id.clone()),
+                _ => None,
+            })
+            .expect("order was not placed")
+    }
+
+    #[test]
+    fn privileged_messages_are_not_accepted_from_the_api() {
+        let privileged = [
+            r#"{"type":"SET_MARKET_STATUS","data":{"market":"TATA_INR","status":"DELISTED"}}"#,
+            r#"{"type":"SET_PRICE_PROTECTION","data":{"market":"TATA_INR","band_bps":null,"reference_price":null,"circuit_breaker":null}}"#,
+            r#"{"type":"SET_MARGIN_CONFIG","data":{"max_leverage":"100","liquidation_margin_level":"0","hourly_interest_rate":"0"}}"#,
+            r#"{"type":"SET_MARGIN_ENABLED","data":{"user_id":"1","enabled":true}}"#,
+            r#"{"type":"SET_RISK_LIMITS","data":{"user_id":"1","limits":{"max_open_orders":null,"max_order_notional":null,"max_open_notional":null,"max_orders_per_second":null}}}"#,
+        ];
+        for payload in privileged {
+            assert!(
+                serde_json::from_str::<MessageFromApi>(payload).is_err(),
+                "{}",
+                payload
+            );
+            assert!(
+                serde_json::from_str::<AdminCommand>(payload).is_ok(),
+                "{}",
+                payload
+            );
+        }
+    }
+
+    #[test]
+    fn repaying_a_non_positive_amount_is_rejected() {
+        for amount in ["-100", "0"] {
+            let repay = MessageFromApi::RepayMargin(RepayMarginPayload {
+                user_id: "0".to_string(),
+                asset: QUOTE.to_string(),
+                amount: amount.to_string(),
+            });
+            assert!(ProcessParams::from_api_message(repay, "test".to_string()).is_err());
+        }
+
+        let mut harness = harness();
+        send(
+            &mut harness.engine,
+            MessageFromApi::RepayMargin(RepayMarginPayload {
+                user_id: "0".to_string(),
+                asset: QUOTE.to_string(),
+                amount: "100".to_string(),
+            }),
+        );
+        // Nothing is owed, so nothing is taken
+        assert_eq!(harness.engine.available("0", QUOTE), Decimal::from(500));
+
+        // Nor does the engine act on one that skipped the validation
+        let mut harness = borrower_harness(Decimal::ZERO);
+        let engine = &mut harness.engine;
+        for amount in [Decimal::from(-100), Decimal::ZERO] {
+            assert!(engine.repay("0", QUOTE, amount).is_zero());
+        }
+        assert!(engine.available("0", QUOTE).is_zero());
+        assert_eq!(engine.liabilities("0", QUOTE), Decimal::from(150));
+        assert_eq!(engine.check_invariants(), Ok(()));
+    }
+
+    // User 0 owing 150 INR on margin, borrowed for a leveraged buy of 25 TATA
+    fn borrower_harness(hourly_interest_rate: Decimal) -> Harness {
+        let mut harness = harness();
+        let engine = &mut harness.engine;
+        engine.balance_mut("0", QUOTE).available = Decimal::from(100);
+        engine.balance_mut("0", "TATA").available = Decimal::ZERO;
+        engine.asset_supply = Engine::total_holdings(&engine.balances);
+        send_admin(
+            engine,
+            AdminCommand::SetMarginConfig(MarginConfig {
+                max_leverage: Decimal::from(3),
+                liquidation_margin_level: Decimal::new(11, 1),
+                hourly_interest_rate,
+            }),
+        );
+        send_admin(
+            engine,
+            AdminCommand::SetMarginEnabled(MarginEnabledPayload {
+                user_id: "0".to_string(),
+                enabled: true,
+            }),
+        );
+        place(engine, 1, Side::Sell, 10, 25);
+        place(engine, 0, Side::Buy, 10, 25);
+        assert_eq!(engine.liabilities("0", QUOTE), Decimal::from(150));
+        harness
+    }
+
+    // A deposit by an unrelated user, journaled at `millis`
+    fn deposit_at(engine: &mut Engine, millis: u64, seq: u64) {
+        let params = ProcessParams::from_api_message(
+            MessageFromApi::OnRamp(OnRampPayload {
+                asset: QUOTE.to_string(),
+                amount: "1".to_string(),
+                user_id: "2".to_string(),
+                txn_id: "txn".to_string(),
+            }),
+            "test".to_string(),
+        )
+        .unwrap();
+        engine.process(params.with_journal_id(JournalId { millis, seq }));
+    }
+
+    #[test]
+    fn interest_is_charged_to_a_bounded_number_of_decimals() {
+        let mut harness = borrower_harness(Decimal::new(7, 2));
+        let engine = &mut harness.engine;
+
+        // Gaps that make the hourly fraction a long repeating decimal
+        let mut millis = engine.clock.millis() as u64;
+        for (seq, gap) in [1, 7, 333, 1_234_567].into_iter().enumerate() {
+            millis += gap;
+            deposit_at(engine, millis, seq as u64);
+        }
+        let interest = engine.balances["0"][QUOTE].interest;
+        assert!(interest > Decimal::ZERO);
+        assert!(interest.scale() <= INTEREST_PRECISION);
+        assert_eq!(engine.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn interest_does_not_depend_on_how_often_messages_arrive() {
+        // Each millisecond accrues well under the charging precision
+        let rate = Decimal::new(1, 4);
+        let mut busy = borrower_harness(rate);
+        let mut quiet = borrower_harness(rate);
+        let start = busy.engine.clock.millis().max(quiet.engine.clock.millis()) as u64;
+        // Both borrowed at wall-clock time; start them from the same point
+        for engine in [&mut busy.engine, &mut quiet.engine] {
+            deposit_at(engine, start, 0);
+            let balance = engine.balance_mut("0", QUOTE);
+            balance.interest = Decimal::ZERO;
+            balance.interest_carry = Decimal::ZERO;
+        }
+
+        for millis in 1..=5_000 {
+            deposit_at(&mut busy.engine, start + millis, 0);
+        }
+        deposit_at(&mut quiet.engine, start + 5_000, 0);
+
+        // 150 INR for 5 seconds at 0.01% an hour
+        let expected = Decimal::new(2083, 8);
+        assert_eq!(busy.engine.balances["0"][QUOTE].interest, expected);
+        assert_eq!(quiet.engine.balances["0"][QUOTE].interest, expected);
+    }
+
+    #[test]
+    fn the_borrower_index_follows_debts_and_survives_a_restore() {
+        let mut harness = borrower_harness(Decimal::ZERO);
+        let engine = &mut harness.engine;
+        let borrowers = HashSet::from([("0".to_string(), QUOTE.to_string())]);
+        assert_eq!(engine.margin.borrowers, borrowers);
+
+        let saved = serde_json::to_string(&engine.snapshot()).unwrap();
+        let (outputs, _outputs_rx) = OutputSender::channel();
+        let standby = Engine::restore(outputs, serde_json::from_str(&saved).unwrap());
+        assert_eq!(standby.margin.borrowers, borrowers);
+        assert!(standby.margin.unchecked.contains("0"));
+
+        engine.on_ramp("0", QUOTE, Decimal::from(150));
+        send(
+            engine,
+            MessageFromApi::RepayMargin(RepayMarginPayload {
+                user_id: "0".to_string(),
+                asset: QUOTE.to_string(),
+                amount: "150".to_string(),
+            }),
+        );
+        assert!(engine.liabilities("0", QUOTE).is_zero());
+        assert!(engine.margin.borrowers.is_empty());
+    }
+
+    #[test]
+    fn a_liquidation_short_of_bids_goes_ahead_once_they_arrive() {
+        let mut harness = borrower_harness(Decimal::ZERO);
+        let engine = &mut harness.engine;
+        // A trade at 1 leaves no bids to sell the account's TATA into
+        place(engine, 2, Side::Sell, 1, 1);
+        place(engine, 1, Side::Buy, 1, 1);
+        assert_eq!(engine.available("0", "TATA"), Decimal::from(25));
+        assert!(engine.margin.unchecked.contains("0"));
+
+        // A bid changes neither the account's balances nor any price
+        place(engine, 3, Side::Buy, 1, 30);
+        assert!(engine.available("0", "TATA").is_zero());
+        assert_eq!(engine.liabilities("0", QUOTE), Decimal::from(125));
+        assert_eq!(engine.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn a_replica_restored_mid_interval_keeps_the_same_schedule() {
+        let apply = |engine: &mut Engine, message: IncomingMessage, (offset, seq): (u64, u64)| {
+            let journal_id = JournalId {
+                millis: 1_700_000_000_000 + offset,
+                seq,
+            };
+            engine.process(op_params(message).with_journal_id(journal_id));
+        };
+        let order = |(market, user, side, price): (&str, usize, Side, u64)| {
+            IncomingMessage::Api(MessageFromApi::CreateOrder(CreateOrderPayload {
+                market: market.to_string(),
+                price: price.to_string(),
+                quantity: "2".to_string(),
+                side,
+                user_id: user.to_string(),
+            }))
+        };
+
+        let mut primary = harness();
+        let engine = &mut primary.engine;
+        let margin = [
+            AdminCommand::SetMarginConfig(MarginConfig {
+                max_leverage: Decimal::from(3),
+                liquidation_margin_level: Decimal::new(11, 1),
+                hourly_interest_rate: Decimal::new(1, 2),
+            }),
+            AdminCommand::SetMarginEnabled(MarginEnabledPayload {
+                user_id: "0".to_string(),
+                enabled: true,
+            }),
+        ];
+        for (seq, command) in margin.into_iter().enumerate() {
+            apply(engine, IncomingMessage::Admin(command), (0, seq as u64));
+        }
+        // A perpetual position and a borrowing spot trade, with the snapshot taken
+        // part way into a ticker bucket
+        let before = [
+            (PERP_MARKET, 1, Side::Sell, 10),
+            (PERP_MARKET, 0, Side::Buy, 10),
+            (MARKET, 1, Side::Sell, 300),
+            (MARKET, 0, Side::Buy, 300),
+        ];
+        for (seq, entry) in before.into_iter().enumerate() {
+            apply(engine, order(entry), (40_000, seq as u64));
+        }
+        assert!(engine.liabilities("0", QUOTE) > Decimal::ZERO);
+
+        let saved = serde_json::to_string(&engine.snapshot()).unwrap();
+        let (outputs, _outputs_rx) = OutputSender::channel();
+        let mut standby = Engine::restore(outputs, serde_json::from_str(&saved).unwrap());
+
+        // Trades either side of the bucket boundary, then one after funding is due
+        let after = [
+            ((MARKET, 2, Side::Sell, 200), 50_000),
+            ((MARKET, 3, Side::Buy, 200), 90_000),
+            ((MARKET, 2, Side::Sell, 100), 8 * 60 * 60 * 1000 + 1),
+        ];
+        for (entry, offset) in after {
+            for engine in [&mut primary.engine, &mut standby] {
+                apply(engine, order(entry.clone()), (offset, 0));
+            }
+        }
+
+        let perpetual = primary.engine.orderbooks[1].perpetual.as_ref().unwrap();
+        assert_ne!(
+            perpetual.positions["0"].margin,
+            perpetual.positions["1"].margin
+        );
+        assert_eq!(
+            serde_json::to_value(standby.snapshot()).unwrap(),
+            serde_json::to_value(primary.engine.snapshot()).unwrap()
+        );
+    }
+
+    #[test]
+    fn trades_on_different_markets_get_distinct_db_ids() {
+        let mut harness = harness();
+        let engine = &mut harness.engine;
+        for user in ["0", "1"] {
+            engine.balance_mut(user, "USDT").available = Decimal::from(500);
+        }
+        engine.asset_supply = Engine::total_holdings(&engine.balances);
+
+        for market in [MARKET, "TATA_USDT"] {
+            for (user, side) in [("0", Side::Sell), ("1", Side::Buy)] {
+                send(
+                    engine,
+                    MessageFromApi::CreateOrder(CreateOrderPayload {
+                        market: market.to_string(),
+                        price: "5".to_string(),
+                        quantity: "2".to_string(),
+                        side,
+                        user_id: user.to_string(),
+                    }),
+                );
+            }
+        }
+
+        let trade_ids: Vec<String> = drain(&mut harness)
+            .0
+            .into_iter()
+            .filter_map(|message| match message.data {
+                DbMessageData::TradeAdd(trade) => Some(trade.id),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(trade_ids, ["TATA_INR-1", "TATA_USDT-1"]);
+    }
+
+    // Whether each order was placed or rejected for its price, in request order.
+    fn band_outcomes(harness: &mut Harness, prices: &[u64]) -> Vec<bool> {
+        for &price in prices {
+            place(&mut harness.engine, 0, Side::Buy, price, 1);
+        }
+        drain(harness)
+            .1
+            .into_iter()
+            .filter_map(|reply| match reply {
+                MessageToApi::OrderPlaced(_) => Some(true),
+                MessageToApi::OrderRejected(OrderRejectedPayload {
+                    reason: RejectReason::PriceOutsideBand,
+                }) => Some(false),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn price_band_edges_are_inclusive() {
+        let mut harness = harness();
+        protect(&mut harness.engine, Some(500), Some(100), None);
+        drain(&mut harness);
+        assert_eq!(
+            band_outcomes(&mut harness, &[94, 95, 105, 106]),
+            [false, true, true, false]
+        );
+
+        // A zero band admits only the reference price itself
+        protect(&mut harness.engine, Some(0), Some(100), None);
+        drain(&mut harness);
+        assert_eq!(
+            band_outcomes(&mut harness, &[99, 100, 101]),
+            [false, true, false]
+        );
+    }
+
+    #[test]
+    fn price_band_follows_the_last_trade_without_a_reference_price() {
+        let mut harness = harness();
+        protect(&mut harness.engine, Some(1000), None, None);
+        // Nothing to measure against before the first trade
+        drain(&mut harness);
+        assert_eq!(band_outcomes(&mut harness, &[1]), [true]);
+
+        place(&mut harness.engine, 2, Side::Sell, 20, 1);
+        place(&mut harness.engine, 3, Side::Buy, 20, 1);
+        drain(&mut harness);
+        assert_eq!(
+            band_outcomes(&mut harness, &[17, 18, 22, 23]),
+            [false, true, true, false]
+        );
+    }
+
+    // Opens a long for user 0 and a short for user 1 at `mark`, lets one funding
+    // round settle against `index` and returns how much each position's margin moved.
+    fn funding_margin_changes(mark: u64, index: u64) -> (Decimal, Decimal) {
+        let mut harness = flow_harness(PERP_MARKET, index);
+        let engine = &mut harness.engine;
+        for (user, side) in [(1, Side::Sell), (0, Side::Buy)] {
+            send(
+                engine,
+                MessageFromApi::CreateOrder(CreateOrderPayload {
+                    market: PERP_MARKET.to_string(),
+                    price: mark.to_string(),
+                    quantity: "2".to_string(),
+                    side,
+                    user_id: user.to_string(),
+                }),
+            );
+        }
+        let margin = |engine: &Engine, user: &str| {
+            engine.orderbooks[1].perpetual.as_ref().unwrap().positions[user].margin
+        };
+        let before = (margin(engine, "0"), margin(engine, "1"));
+        send(
+            engine,
+            MessageFromApi::OnRamp(OnRampPayload {
+                asset: QUOTE.to_string(),
+                amount: "1".to_string(),
+                user_id: "2".to_string(),
+                txn_id: "txn".to_string(),
+            }),
+        );
+        assert_eq!(engine.check_invariants(), Ok(()));
+        (
+            margin(engine, "0") - before.0,
+            margin(engine, "1") - before.1,
+        )
+    }
+
+    #[test]
+    fn longs_pay_shorts_when_the_mark_is_above_the_index() {
+        // 10% premium, capped at the default 0.75% per interval
+        let (long, short) = funding_margin_changes(11, 10);
+        assert_eq!(long, Decimal::new(-165, 3));
+        assert_eq!(short, Decimal::new(165, 3));
+    }
+
+    #[test]
+    fn shorts_pay_longs_when_the_mark_is_below_the_index() {
+        let (long, short) = funding_margin_changes(9, 10);
+        assert_eq!(long, Decimal::new(135, 3));
+        assert_eq!(short, Decimal::new(-135, 3));
+    }
+
+    #[test]
+    fn no_funding_moves_when_the_mark_matches_the_index() {
+        assert_eq!(
+            funding_margin_changes(10, 10),
+            (Decimal::ZERO, Decimal::ZERO)
+        );
+    }
+
+    fn create_sub_account(engine: &mut Engine, user_id: &str, name: &str) {
+        send(
+            engine,
+            MessageFromApi::CreateSubAccount(SubAccountPayload {
+                user_id: user_id.to_string(),
+                name: name.to_string(),
+            }),
+        );
+    }
+
+    #[test]
+    fn sub_account_keys_resolve_only_once_created() {
+        let mut harness = harness();
+        let engine = &mut harness.engine;
+        assert_eq!(engine.check_account("0"), Ok(()));
+        assert_eq!(
+            engine.check_account("0:hedge"),
+            Err(RejectReason::AccountNotFound)
+        );
+
+        create_sub_account(engine, "0", "hedge");
+        assert_eq!(engine.check_account("0:hedge"), Ok(()));
+        // Sub-accounts belong to the user that created them
+        for account in ["1:hedge", "0:", "0:hedge:x", ":hedge"] {
+            assert_eq!(
+                engine.check_account(account),
+                Err(RejectReason::AccountNotFound),
+                "{}",
+                account
+            );
+        }
+    }
+
+    #[test]
+    fn sub_account_names_cannot_be_empty_or_contain_a_colon() {
+        let mut harness = harness();
+        let engine = &mut harness.engine;
+        for (user_id, name) in [("0", ""), ("0", "a:b"), ("0:a", "b")] {
+            create_sub_account(engine, user_id, name);
+        }
+        assert!(engine.sub_accounts.is_empty());
+        assert_eq!(
+            engine.check_account("0:a:b"),
+            Err(RejectReason::AccountNotFound)
+        );
+        let (_, replies) = drain(&mut harness);
+        assert!(replies
+            .iter()
+            .all(|reply| matches!(reply, MessageToApi::SubAccounts(payload) if payload.sub_accounts.is_empty())));
+    }
+
+    #[test]
+    fn sub_accounts_keep_balances_apart() {
+        let mut harness = harness();
+        let engine = &mut harness.engine;
+        let sub_account = "0:hedge";
+
+        // Orders from a sub-account that does not exist yet are rejected
+        place_as(engine, sub_account, Side::Buy, 5, 1);
+        assert_eq!(engine.orderbooks[0].resting_orders().count(), 0);
+
+        create_sub_account(engine, "0", "hedge");
+        send(
+            engine,
+            MessageFromApi::Transfer(TransferPayload {
+                from_account: "0".to_string(),
+                to_account: sub_account.to_string(),
+                asset: QUOTE.to_string(),
+                amount: "20".to_string(),
+            }),
+        );
+        assert_eq!(engine.available(sub_account, QUOTE), Decimal::from(20));
+        assert_eq!(engine.available("0", QUOTE), Decimal::from(480));
+
+        // The sub-account can only spend what was moved into it
+        place_as(engine, sub_account, Side::Buy, 5, 5);
+        assert_eq!(engine.orderbooks[0].resting_orders().count(), 0);
+        place_as(engine, sub_account, Side::Buy, 5, 4);
+        assert_eq!(
+            engine.orderbooks[0]
+                .get_open_orders(sub_account.to_string())
+                .len(),
+            1
+        );
+        assert!(engine.orderbooks[0]
+            .get_open_orders("0".to_string())
+            .is_empty());
+        assert_eq!(engine.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn markets_settle_in_their_own_quote_asset() {
+        let mut harness = flow_harness("TATA_USDT", 0);
+        let engine = &mut harness.engine;
+        for (user, side) in [("0", Side::Sell), ("1", Side::Buy)] {
+            send(
+                engine,
+                MessageFromApi::CreateOrder(CreateOrderPayload {
+                    market: "TATA_USDT".to_string(),
+                    price: "7".to_string(),
+                    quantity: "3".to_string(),
+                    side,
+                    user_id: user.to_string(),
+                }),
+            );
+        }
+
+        assert_eq!(engine.available("0", "USDT"), Decimal::from(521));
+        assert_eq!(engine.available("1", "USDT"), Decimal::from(479));
+        assert_eq!(engine.available("0", "TATA"), Decimal::from(497));
+        assert_eq!(engine.available("1", "TATA"), Decimal::from(503));
+        // INR is untouched even though both markets trade TATA
+        for user in ["0", "1"] {
+            assert_eq!(engine.available(user, QUOTE), Decimal::from(500));
+        }
+        let (db_messages, _) = drain(&mut harness);
+        assert!(db_messages.iter().any(|message| matches!(
+            &message.data,
+            DbMessageData::TradeAdd(trade) if trade.market == "TATA_USDT"
+        )));
+        assert_eq!(harness.engine.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn ticker_covers_a_rolling_24_hour_window() {
+        const MINUTE: u64 = 60_000;
+        let mut harness = harness();
+        let engine = &mut harness.engine;
+        let trades = [(0, 8, 1), (30 * MINUTE, 3, 2), (24 * 60 * MINUTE, 6, 3)];
+        for (seq, (millis, price, quantity)) in trades.into_iter().enumerate() {
+            let journal_id = JournalId {
+                millis: 1_000_000 + millis,
+                seq: seq as u64,
+            };
+            for (user, side) in [(0, Side::Sell), (1, Side::Buy)] {
+                let params = ProcessParams::from_api_message(
+                    MessageFromApi::CreateOrder(CreateOrderPayload {
+                        market: MARKET.to_string(),
+                        price: price.to_string(),
+                        quantity: quantity.to_string(),
+                        side,
+                        user_id: user.to_string(),
+                    }),
+                    "test".to_string(),
+                )
+                .unwrap();
+                engine.process(params.with_journal_id(journal_id));
+            }
+        }
+
+        // A day after the first trade its minute has left the window
+        let summary = engine.orderbooks[0]
+            .ticker_stats
+            .summary(engine.clock.now());
+        assert_eq!(summary, ticker_summary(&[(3, 2), (6, 3)]));
+        let day_later = engine.clock.now() + Duration::from_secs(24 * 60 * 60);
+        assert_eq!(engine.orderbooks[0].ticker_stats.summary(day_later), None);
+    }
+
+    #[test]
+    fn depth_update_ids_count_the_levels_each_diff_changes() {
+        let mut harness = harness();
+        let channel = format!("depth@{}", MARKET);
+        let ids = |diffs: Vec<DepthUpdateMessage>| -> Vec<(u64, u64)> {
+            diffs.iter().map(|diff| (diff.U, diff.u)).collect()
+        };
+
+        place(&mut harness.engine, 0, Side::Buy, 5, 1);
+        place(&mut harness.engine, 0, Side::Buy, 4, 1);
+        assert_eq!(ids(depth_diffs(&mut harness, &channel)), [(1, 1), (2, 2)]);
+
+        // Sweeping both bids sends them and the taker's level, all empty, in one
+        // diff taking an id per level
+        place(&mut harness.engine, 1, Side::Sell, 4, 2);
+        let diffs = depth_diffs(&mut harness, &channel);
+        let empty = |price: &str| [price.to_string(), "0".to_string()];
+        assert_eq!(
+            (&diffs[0].b, &diffs[0].a),
+            (&vec![empty("5"), empty("4")], &vec![empty("4")])
+        );
+        assert_eq!(ids(diffs), [(3, 5)]);
+        let depth = harness.engine.orderbooks[0].aggregated_depth(None, 1);
+        assert_eq!(depth.last_update_id, 5);
+        assert!(depth.bids.is_empty() && depth.asks.is_empty());
+
+        // Rejected orders change no level and take no id
+        place(&mut harness.engine, 1, Side::Sell, 4, 10_000);
+        assert!(depth_diffs(&mut harness, &channel).is_empty());
+        assert_eq!(harness.engine.orderbooks[0].depth_update_id, 5);
+    }
+
+    #[test]
+    fn a_missed_depth_diff_shows_up_as_a_checksum_mismatch() {
+        let mut harness = harness();
+        let channel = format!("depth@{}", MARKET);
+        let snapshot = harness.engine.orderbooks[0].aggregated_depth(None, 1);
+        for (user, side, price) in [(0, Side::Buy, 5), (1, Side::Sell, 7), (0, Side::Buy, 6)] {
+            place(&mut harness.engine, user, side, price, 1);
+        }
+        let mut diffs = depth_diffs(&mut harness, &channel);
+        assert_eq!(diffs.len(), 3);
+
+        // A client that lost the second diff sees a gap in the ids and a book whose
+        // checksum no longer matches
+        let last = diffs.pop().unwrap();
+        let first = diffs.remove(0);
+        assert_eq!(first.U, snapshot.last_update_id + 1);
+        assert_ne!(last.U, first.u + 1);
+        let mut bids: BTreeMap<String, String> = BTreeMap::new();
+        let mut asks: BTreeMap<String, String> = BTreeMap::new();
+        for [price, quantity] in first.b.into_iter().chain(last.b.clone()) {
+            bids.insert(price, quantity);
+        }
+        assert_ne!(local_checksum(&bids, &asks), last.c);
+
+        // With the missed diff applied as well the books agree again
+        for [price, quantity] in diffs.remove(0).a {
+            asks.insert(price, quantity);
+        }
+        assert_eq!(local_checksum(&bids, &asks), last.c);
+    }
+
+    #[test]
+    fn queue_positions_survive_partial_fills_and_cancels() {
+        let mut harness = harness();
+        let engine = &mut harness.engine;
+        for (user, quantity) in [(0, 3), (1, 2), (2, 4)] {
+            place(engine, user, Side::Buy, 5, quantity);
+        }
+        let queue = |engine: &Engine| -> Vec<(u64, u64)> {
+            let snapshot = engine.orderbooks[0].l3_snapshot();
+            snapshot.bids.iter().map(|o| (o.id, o.quantity)).collect()
+        };
+        let ids: Vec<u64> = queue(engine).into_iter().map(|(id, _)| id).collect();
+        let [first, second, third] = ids[..] else {
+            panic!("expected three resting bids, got {:?}", ids);
+        };
+
+        // A partial fill leaves the order at the front
+        place(engine, 3, Side::Sell, 5, 2);
+        assert_eq!(queue(engine), [(first, 1), (second, 2), (third, 4)]);
+
+        let order_id = engine.orderbooks[0].get_open_orders("1".to_string())[0]
+            .order_id
+            .clone();
+        send(
+            engine,
+            MessageFromApi::CancelOrder(CancelOrderPayload {
+                order_id,
+                market: MARKET.to_string(),
+            }),
+        );
+        place(engine, 1, Side::Buy, 5, 1);
+        let fourth = first.max(third) + 1;
+        assert_eq!(queue(engine), [(first, 1), (third, 4), (fourth, 1)]);
+
+        let events: Vec<L3Event> = std::iter::from_fn(|| harness.outputs_rx.try_recv().ok())
+            .filter_map(|traced| match traced.message {
+                Output::Ws(
+                    _,
+                    WsMessage {
+                        data: WsPayload::L3(update),
+                        ..
+                    },
+                ) => Some(update.events),
+                _ => None,
+            })
+            .flatten()
+            .skip(3)
+            .collect();
+        assert!(matches!(
+            events[..],
+            [
+                L3Event::Execute { id: a, quantity: 2 },
+                L3Event::Cancel { id: b },
+                L3Event::Add { id: d, price: 5, quantity: 1, .. },
+            ] if a == first && b == second && d == fourth
+        ));
+    }
+
+    #[test]
+    fn processing_records_message_fill_and_rejection_metrics() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let metrics = recorder.handle();
+        metrics::with_local_recorder(&recorder, || {
+            let mut harness = harness();
+            let engine = &mut harness.engine;
+            place(engine, 1, Side::Sell, 5, 2);
+            place(engine, 0, Side::Buy, 5, 2);
+            place(engine, 0, Side::Buy, 5, 10_000);
+        });
+
+        let rendered = metrics.render();
+        for line in [
+            r#"engine_messages_processed_total{type="create_order"} 3"#,
+            r#"engine_fills_total{market="TATA_INR"} 1"#,
+            r#"engine_orders_rejected_total{reason="InsufficientFunds"} 1"#,
+            "engine_matching_duration_seconds_count 3",
+            r#"engine_process_duration_seconds_count{type="create_order"} 3"#,
+        ] {
+            assert!(
+                rendered.lines().any(|rendered| rendered == line),
+                "{} missing from\n{}",
+                line,
+                rendered
+            );
+        }
+    }
+
+    #[test]
+    fn cancel_reports_filled_and_remaining_quantities() {
+        let mut harness = harness();
+        place(&mut harness.engine, 0, Side::Sell, 5, 5);
+        let order_id = placed_order_id(&drain(&mut harness).1);
+        place(&mut harness.engine, 1, Side::Buy, 5, 2);
+        drain(&mut harness);
+
+        send(
+            &mut harness.engine,
+            MessageFromApi::CancelOrder(CancelOrderPayload {
+                order_id: order_id.clone(),
+                market: MARKET.to_string(),
+            }),
+        );
+        let (db_messages, replies) = drain(&mut harness);
+        let Some(MessageToApi::OrderCancelled(cancelled)) = replies.first() else {
+            panic!("expected a cancel reply, got {:?}", replies);
+        };
+        assert_eq!((cancelled.executed_qty, cancelled.remaining_qty), (2, 3));
+        let stored = db_messages.iter().find_map(|message| match &message.data {
+            DbMessageData::OrderCancelled(cancelled) => Some(cancelled),
+            _ => None,
+        });
+        let stored = stored.expect("cancellation was not persisted");
+        assert_eq!(stored.order_id, order_id);
+        assert_eq!(
+            (stored.executed_quantity, stored.remaining_quantity),
+            (2, 3)
+        );
+    }
+
+    #[test]
+    fn cancelling_an_order_that_is_not_resting_still_replies() {
+        let mut harness = harness();
+        place(&mut harness.engine, 0, Side::Sell, 5, 2);
+        let order_id = placed_order_id(&drain(&mut harness).1);
+        place(&mut harness.engine, 1, Side::Buy, 5, 2);
+        drain(&mut harness);
+
+        for order_id in [order_id, "unknown".to_string()] {
+            send(
+                &mut harness.engine,
+                MessageFromApi::CancelOrder(CancelOrderPayload {
+                    order_id: order_id.clone(),
+                    market: MARKET.to_string(),
+                }),
+            );
+            let (db_messages, replies) = drain(&mut harness);
+            let [MessageToApi::OrderCancelled(cancelled)] = replies.as_slice() else {
+                panic!("expected a single cancel reply, got {:?}", replies);
+            };
+            assert_eq!(cancelled.order_id, order_id);
+            assert_eq!((cancelled.executed_qty, cancelled.remaining_qty), (0, 0));
+            assert!(db_messages.is_empty());
+        }
+    }
+
+    #[test]
+    fn fills_update_the_maker_order_in_db() {
+        let mut harness = harness();
+        place(&mut harness.engine, 0, Side::Sell, 5, 5);
+        let maker_order_id = placed_order_id(&drain(&mut harness).1);
+
+        let mut executed = Vec::new();
+        for quantity in [2, 1] {
+            place(&mut harness.engine, 1, Side::Buy, 5, quantity);
+            let (db_messages, replies) = drain(&mut harness);
+            let taker_order_id = placed_order_id(&replies);
+            for message in db_messages {
+                if let DbMessageData::OrderUpdate(update) = message.data {
+                    assert!(
+                        update.order_id == maker_order_id || update.order_id == taker_order_id,
+                        "update for unknown order {}",
+                        update.order_id
+                    );
+                    if update.order_id == maker_order_id {
+                        executed.push(update.executed_quantity);
+                    }
+                }
+            }
+        }
+        // The maker's executed quantity is cumulative, not the size of each fill
+        assert_eq!(executed, vec![2, 3]);
+    }
+
+    proptest! {
+        #[test]
+        fn random_order_flow_keeps_the_engine_consistent(
+            market in prop::sample::select(vec![MARKET, "TATA_USDT", PERP_MARKET]),
+            ops in prop::collection::vec(op_strategy(), 1..150),
+            index_price in 1u64..=10,
+        ) {
+            let mut harness = flow_harness(market, index_price);
+            let engine = &mut harness.engine;
+            let precisions = [1, DEPTH_STREAM_PRECISIONS[0], DEPTH_STREAM_PRECISIONS[1]];
+            let depth_snapshots =
+                precisions.map(|precision| book(engine, market).aggregated_depth(None, precision));
+            let l3_snapshot = book(engine, market).l3_snapshot();
+
+            for op in ops {
+                apply(engine, market, op);
+
+                prop_assert_eq!(engine.check_invariants(), Ok(()));
+                // Accounts left out of the next margin check are above the threshold
+                for user_id in &engine.margin.enabled_users {
+                    prop_assert!(
+                        engine.margin.unchecked.contains(user_id) || !engine.is_breached(user_id),
+                        "{} is below the margin threshold but not due a check",
+                        user_id
+                    );
+                }
+                let orderbook = book(engine, market);
+                if let Some(perpetual) = &orderbook.perpetual {
+                    let net_size: i64 = perpetual.positions.values().map(|p| p.size).sum();
+                    prop_assert_eq!(net_size, 0);
+                }
+                if orderbook.status == MarketStatus::Delisted {
+                    prop_assert_eq!(orderbook.resting_orders().count(), 0);
+                }
+                // Only continuous matching guarantees an uncrossed book
+                if !matches!(orderbook.status, MarketStatus::Trading | MarketStatus::PostOnly) {
+                    continue;
+                }
+                if let (Some(best_bid), Some(best_ask)) =
+                    (orderbook.bids.keys().next_back(), orderbook.asks.keys().next())
+                {
+                    prop_assert!(best_bid < best_ask, "book is crossed: {} >= {}", best_bid, best_ask);
+                }
+            }
+
+            let mut trades: Vec<(u64, u64)> = Vec::new();
+            let mut depth_diffs: HashMap<String, Vec<DepthUpdateMessage>> = HashMap::new();
+            let mut l3_updates = Vec::new();
+            while let Ok(Traced { message: output, .. }) = harness.outputs_rx.try_recv() {
+                match output {
+                    Output::Db(DbMessage { data: DbMessageData::TradeAdd(trade), .. })
+                        if trade.market == market =>
+                    {
+                        trades.push((trade.price.parse().unwrap(), trade.quantity.parse().unwrap()));
+                    }
+                    Output::Ws(channel, WsMessage { data: WsPayload::Depth(diff), .. }) => {
+                        depth_diffs.entry(channel).or_default().push(diff);
+                    }
+                    Output::Ws(_, WsMessage { data: WsPayload::L3(update), .. }) if update.s == market => {
+                        l3_updates.push(update);
+                    }
+                    _ => {}
+                }
+            }
+
+            let engine = &harness.engine;
+            let orderbook = book(engine, market);
+            prop_assert_eq!(orderbook.ticker_stats.summary(engine.clock.now()), ticker_summary(&trades));
+            for (precision, snapshot) in precisions.into_iter().zip(depth_snapshots) {
+                let channel = match precision {
+                    1 => format!("depth@{}", market),
+                    _ => format!("depth@{}@{}", market, precision),
+                };
+                let diffs = depth_diffs.remove(&channel).unwrap_or_default();
+                check_depth_replay(orderbook, precision, snapshot, diffs)?;
+            }
+            check_l3_rebuild(orderbook, l3_snapshot, l3_updates)?;
+        }
+
+        #[test]
+        fn restored_snapshot_continues_like_the_original(
+            before in prop::collection::vec(op_strategy(), 1..80),
+            after in prop::collection::vec(op_strategy(), 1..80),
+        ) {
+            let mut harness = harness();
+            send_admin(&mut harness.engine, AdminCommand::SetMarginConfig(MarginConfig {
+                max_leverage: Decimal::from(3),
+                ..MarginConfig::default()
+            }));
+            for op in before {
+                apply(&mut harness.engine, MARKET, op);
+            }
+
+            let saved = serde_json::to_string(&harness.engine.snapshot()).unwrap();
+            let (outputs, _outputs_rx) = OutputSender::channel();
+            let mut restored = Engine::restore(outputs, serde_json::from_str(&saved).unwrap());
+            prop_assert_eq!(restored.check_invariants(), Ok(()));
+
+            // Order ids are random, so the books are compared through the L3 view
+            let state = |engine: &Engine| {
+                serde_json::json!({
+                    "balances": engine.balances,
+                    "asset_supply": engine.asset_supply,
+                    "books": engine.orderbooks.iter().map(|orderbook| (
+                        orderbook.l3_snapshot(),
+                        orderbook.aggregated_depth(None, 1),
+                        orderbook.status,
+                        orderbook.last_trade_id,
+                    )).collect::<Vec<_>>(),
+                })
+            };
+            prop_assert_eq!(state(&restored), state(&harness.engine));
+            for op in after {
+                apply(&mut harness.engine, MARKET, op.clone());
+                apply(&mut restored, MARKET, op);
+                prop_assert_eq!(state(&restored), state(&harness.engine));
+            }
+        }
+
+        #[test]
+        fn replicas_applying_the_same_journal_agree(
+            ops in prop::collection::vec((op_strategy(), 0u64..=2_000), 1..80),
+        ) {
+            let mut primary = harness();
+            let mut standby = harness();
+            let mut millis = 1_700_000_000_000;
+            for (seq, (op, gap)) in ops.into_iter().enumerate() {
+                millis += gap;
+                let journal_id = JournalId { millis, seq: seq as u64 };
+                // Both replicas get the message as built against the primary's book,
+                // so a cancel names the same order id on each
+                let messages = (
+                    op_message(&primary.engine, MARKET, op.clone()),
+                    op_message(&primary.engine, MARKET, op),
+                );
+                let (Some(message), Some(standby_message)) = messages else {
+                    continue;
+                };
+                for (engine, message) in [(&mut primary.engine, message), (&mut standby.engine, standby_message)] {
+                    engine.process(op_params(message).with_journal_id(journal_id));
+                }
+            }
+
+            let state = |engine: &Engine| {
+                serde_json::json!({
+                    "journal_id": engine.journal_id(),
+                    "balances": engine.balances,
+                    "asset_supply": engine.asset_supply,
+                    "books": engine.orderbooks.iter().map(|orderbook| (
+                        orderbook.top_orders(usize::MAX),
+                        orderbook.status,
+                        orderbook.last_trade_id,
+                    )).collect::<Vec<_>>(),
+                })
+            };
+            prop_assert_eq!(state(&standby.engine), state(&primary.engine));
+        }
+
+        #[test]
+        fn taker_buy_releases_price_improvement(ask_price in 1u64..=10, improvement in 0u64..=10, quantity in 1u64..=10) {
+            let mut harness = harness();
+            let engine = &mut harness.engine;
+            let bid_price = ask_price + improvement;
+
+            for (user, side, price) in [("0", Side::Sell, ask_price), ("1", Side::Buy, bid_price)] {
+                send(
+                    engine,
+                    MessageFromApi::CreateOrder(CreateOrderPayload {
+                        market: MARKET.to_string(),
+                        price: price.to_string(),
+                        quantity: quantity.to_string(),
+                        side,
+                        user_id: user.to_string(),
+                    }),
+                );
+            }
+
+            let paid = Decimal::from(ask_price * quantity);
+            let buyer_quote = &engine.balances["1"][QUOTE];
+            prop_assert_eq!(buyer_quote.available, Decimal::from(500) - paid);
+            prop_assert_eq!(buyer_quote.locked, Decimal::ZERO);
+            prop_assert_eq!(engine.balances["0"][QUOTE].available, Decimal::from(500) + paid);
+            prop_assert_eq!(engine.balances["1"]["TATA"].available, Decimal::from(500 + quantity));
+            prop_assert_eq!(engine.check_invariants(), Ok(()));
+        }
+
+        #[test]
+        fn outputs_carry_the_request_correlation_id(price in 1u64..=10, quantity in 1u64..=10) {
+            let mut harness = harness();
+            for (user, side, correlation_id) in [(0, Side::Sell, "req-1"), (1, Side::Buy, "req-2")] {
+                let message = MessageFromApi::CreateOrder(CreateOrderPayload {
+                    market: MARKET.to_string(),
+                    price: price.to_string(),
+                    quantity: quantity.to_string(),
+                    side,
+                    user_id: user.to_string(),
+                });
+                let params = ProcessParams::from_api_message(message, "test".to_string())
+                    .unwrap()
+                    .with_correlation_id(Some(correlation_id.to_string()));
+                harness.engine.process(params);
+            }
+
+            let mut db_ids = Vec::new();
+            let mut pubsub_ids = Vec::new();
+            while let Ok(traced) = harness.outputs_rx.try_recv() {
+                match traced.message {
+                    Output::Db(_) => db_ids.push(traced.correlation_id),
+                    _ => pubsub_ids.push(traced.correlation_id),
+                }
+            }
+            // Each target sees the maker's outputs, then only the taker's
+            for ids in [db_ids, pubsub_ids] {
+                let maker_outputs = ids.iter().take_while(|id| id.as_deref() == Some("req-1")).count();
+                prop_assert!(maker_outputs > 0 && maker_outputs < ids.len());
+                prop_assert!(ids[maker_outputs..].iter().all(|id| id.as_deref() == Some("req-2")));
+            }
+        }
+
+        #[test]
+        fn orders_outside_price_band_are_rejected(reference in 10u64..=20, band_bps in 0u64..=5000, price in 1u64..=40) {
+            let mut harness = harness();
+            let engine = &mut harness.engine;
+            protect(engine, Some(band_bps), Some(reference), None);
+
+            place(engine, 0, Side::Buy, price, 1);
+
+            let allowed = price.abs_diff(reference) * 10_000 <= band_bps * reference;
+            prop_assert_eq!(engine.orderbooks[0].resting_orders().count(), allowed as usize);
+            let locked = engine.balances["0"][QUOTE].locked;
+            prop_assert_eq!(locked, if allowed { Decimal::from(price) } else { Decimal::ZERO });
+        }
+
+        #[test]
+        fn risk_limits_cap_resting_orders_and_notional(
+            max_open_orders in 0u64..=6,
+            max_order_notional in 1u64..=40,
+            max_open_notional in 1u64..=120,
+            orders in prop::collection::vec((1u64..=5, 1u64..=5), 1..20),
+        ) {
+            let mut harness = harness();
+            let engine = &mut harness.engine;
+            let limits = RiskLimits {
+                max_open_orders: Some(max_open_orders),
+                max_order_notional: Some(Decimal::from(max_order_notional)),
+                max_open_notional: Some(Decimal::from(max_open_notional)),
+                max_orders_per_second: None,
+            };
+            send_admin(engine, AdminCommand::SetRiskLimits(RiskLimitsPayload { user_id: "0".to_string(), limits }));
+
+            // Bids never cross, so every accepted order rests
+            let (mut resting, mut open_notional) = (0u64, 0u64);
+            for (price, quantity) in orders {
+                place(engine, 0, Side::Buy, price, quantity);
+                let notional = price * quantity;
+                if resting < max_open_orders
+                    && notional <= max_order_notional
+                    && open_notional + notional <= max_open_notional
+                {
+                    resting += 1;
+                    open_notional += notional;
+                }
+                prop_assert_eq!(engine.orderbooks[0].open_order_count("0"), resting);
+                prop_assert_eq!(engine.orderbooks[0].open_notional("0"), Decimal::from(open_notional));
+            }
+            prop_assert_eq!(engine.check_invariants(), Ok(()));
+        }
+
+        #[test]
+        fn order_rate_limit_rejects_burst(max_per_second in 1u64..=5, burst in 1u64..=10) {
+            let mut harness = harness();
+            let engine = &mut harness.engine;
+            let limits = RiskLimits { max_orders_per_second: Some(max_per_second), ..RiskLimits::default() };
+            send_admin(engine, AdminCommand::SetRiskLimits(RiskLimitsPayload { user_id: "0".to_string(), limits }));
+
+            for _ in 0..burst {
+                place(engine, 0, Side::Buy, 1, 1);
+            }
+            prop_assert_eq!(engine.orderbooks[0].open_order_count("0"), burst.min(max_per_second));
+        }
+
+        #[test]
+        fn closed_perpetual_position_realizes_pnl(open_price in 1u64..=10, close_price in 1u64..=10, quantity in 1u64..=10) {
+            let mut harness = harness();
+            let engine = &mut harness.engine;
+            let initial = engine.available("0", QUOTE);
+
+            for (user, side, price) in [
+                (1, Side::Sell, open_price),
+                (0, Side::Buy, open_price),
+                (1, Side::Buy, close_price),
+                (0, Side::Sell, close_price),
+            ] {
+                send(
+                    engine,
+                    MessageFromApi::CreateOrder(CreateOrderPayload {
+                        market: PERP_MARKET.to_string(),
+                        price: price.to_string(),
+                        quantity: quantity.to_string(),
+                        side,
+                        user_id: user.to_string(),
+                    }),
+                );
+            }
+
+            let pnl = (Decimal::from(close_price) - Decimal::from(open_price)) * Decimal::from(quantity);
+            prop_assert_eq!(engine.available("0", QUOTE), initial + pnl);
+            prop_assert_eq!(engine.available("1", QUOTE), initial - pnl);
+            let perpetual = engine.orderbooks[1].perpetual.as_ref().unwrap();
+            prop_assert!(perpetual.positions.is_empty());
+            prop_assert_eq!(perpetual.settlement_pool, Decimal::ZERO);
+            prop_assert_eq!(engine.check_invariants(), Ok(()));
+        }
+
+        #[test]
+        fn admin_commands_keep_balances_consistent(
+            credit in 1u64..=1000,
+            debit in 1u64..=2000,
+            price in 1u64..=10,
+            quantity in 1u64..=10,
+        ) {
+            let mut harness = harness();
+            let engine = &mut harness.engine;
+            place(engine, 0, Side::Buy, price, quantity);
+            send_admin(engine, AdminCommand::SetMarketStatus(SetMarketStatusPayload {
+                market: MARKET.to_string(),
+                status: MarketStatus::Halted,
+            }));
+            let order_id = engine.orderbooks[0].get_open_orders("0".to_string())[0]
+                .order_id
+                .clone();
+            send_admin(engine, AdminCommand::ForceCancel(CancelOrderPayload {
+                order_id,
+                market: MARKET.to_string(),
+            }));
+            prop_assert!(engine.orderbooks[0].get_open_orders("0".to_string()).is_empty());
+            prop_assert_eq!(engine.balance_mut("0", QUOTE).locked, Decimal::ZERO);
+
+            for amount in [credit.to_string(), format!("-{}", debit)] {
+                send_admin(engine, AdminCommand::AdjustBalance(AdjustBalancePayload {
+                    user_id: "0".to_string(),
+                    asset: QUOTE.to_string(),
+                    amount,
+                    reason: "test".to_string(),
+                }));
+            }
+            // A debit larger than the available balance is refused outright
+            let credited = 500 + credit;
+            let expected = if debit <= credited { credited - debit } else { credited };
+            prop_assert_eq!(engine.available("0", QUOTE), Decimal::from(expected));
+            prop_assert_eq!(engine.check_invariants(), Ok(()));
+        }
+
+        #[test]
+        fn margin_account_is_liquidated_when_price_falls(crash_price in 1u64..=6) {
+            let mut harness = harness();
+            let engine = &mut harness.engine;
+            engine.balance_mut("0", QUOTE).available = Decimal::from(100);
+            engine.balance_mut("0", "TATA").available = Decimal::ZERO;
+            engine.asset_supply = Engine::total_holdings(&engine.balances);
+            send_admin(engine, AdminCommand::SetMarginConfig(MarginConfig {
+                max_leverage: Decimal::from(3),
+                liquidation_margin_level: Decimal::new(11, 1),
+                hourly_interest_rate: Decimal::ZERO,
+            }));
+            send_admin(engine, AdminCommand::SetMarginEnabled(MarginEnabledPayload {
+                user_id: "0".to_string(),
+                enabled: true,
+            }));
+
+            // 250 worth of TATA on 100 of equity borrows 150
+            place(engine, 1, Side::Sell, 10, 25);
+            place(engine, 0, Side::Buy, 10, 25);
+            prop_assert_eq!(engine.balances["0"]["TATA"].available, Decimal::from(25));
+            prop_assert_eq!(engine.liabilities("0", QUOTE), Decimal::from(150));
+            prop_assert_eq!(engine.check_invariants(), Ok(()));
+
+            // A trade at the crash price drops the margin level below 1.1
+            place(engine, 2, Side::Buy, crash_price, 60);
+            place(engine, 1, Side::Sell, crash_price, 1);
+
+            prop_assert_eq!(engine.check_invariants(), Ok(()));
+            let account = engine.margin_account("0");
+            let debt_cleared = account.liabilities_value.is_zero();
+            let nothing_left = engine.available("0", "TATA").is_zero()
+                && engine.available("0", QUOTE).is_zero();
+            prop_assert!(debt_cleared || nothing_left);
+            prop_assert_eq!(engine.orderbooks[0].open_order_count("0"), 0);
+        }
+
+        #[test]
+        fn circuit_breaker_halts_on_large_moves(first in 1u64..=10, second in 1u64..=10, max_move_bps in 0u64..=10_000) {
+            let mut harness = harness();
+            let engine = &mut harness.engine;
+            let breaker = CircuitBreakerConfig { max_move_bps, window_secs: 3600, cooldown_secs: 3600 };
+            protect(engine, None, None, Some(breaker));
+
+            for price in [first, second] {
+                place(engine, 0, Side::Sell, price, 1);
+                place(engine, 1, Side::Buy, price, 1);
+            }
+
+            let (low, high) = (first.min(second), first.max(second));
+            let tripped = (high - low) * 10_000 > max_move_bps * low;
+            let expected = if tripped { MarketStatus::Halted } else { MarketStatus::Trading };
+            prop_assert_eq!(engine.orderbooks[0].status, expected);
+            prop_assert_eq!(engine.check_invariants(), Ok(()));
+
+            // Once the cool-down has elapsed the next message resumes trading
+            if tripped {
+                protect(engine, None, None, Some(CircuitBreakerConfig { cooldown_secs: 0, ..breaker }));
+                engine.orderbooks[0].protection.trip(engine.clock.now(), MarketStatus::Trading);
+                place(engine, 0, Side::Sell, first, 1);
+                prop_assert_eq!(engine.orderbooks[0].status, MarketStatus::Trading);
+                prop_assert_eq!(engine.orderbooks[0].resting_orders().count(), 1);
+            }
+        }
     }
 }
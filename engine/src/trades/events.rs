@@ -1,2 +1 @@
-pub const DEPTH_UPDATE: &str = "DEPTH_UPDATE";
 pub const TICKER_UPDATE: &str = "TICKER_UPDATE";
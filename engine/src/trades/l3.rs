@@ -0,0 +1,50 @@
+use engine::types::L3Event;
+
+use super::snapshot::L3Snapshot;
+
+// Order-by-order feed of a market. Resting orders get public ids from a counter so
+// the feed never reveals real order ids, and every event takes the next update id.
+#[derive(Default)]
+pub struct L3Feed {
+    next_order_id: u64,
+    pub update_id: u64,
+    pending: Vec<L3Event>,
+}
+
+impl L3Feed {
+    pub fn next_order_id(&mut self) -> u64 {
+        self.next_order_id += 1;
+        self.next_order_id
+    }
+
+    pub fn push(&mut self, event: L3Event) {
+        self.update_id += 1;
+        self.pending.push(event);
+    }
+
+    // Pending events belong to a message that has already been fully processed
+    // and published by the time a snapshot is taken.
+    pub fn snapshot(&self) -> L3Snapshot {
+        L3Snapshot {
+            next_order_id: self.next_order_id,
+            update_id: self.update_id,
+        }
+    }
+
+    pub fn restore(snapshot: L3Snapshot) -> Self {
+        Self {
+            next_order_id: snapshot.next_order_id,
+            update_id: snapshot.update_id,
+            pending: Vec::new(),
+        }
+    }
+
+    // Events recorded since the last call with the first update id among them.
+    pub fn take(&mut self) -> Option<(u64, Vec<L3Event>)> {
+        if self.pending.is_empty() {
+            return None;
+        }
+        let events = std::mem::take(&mut self.pending);
+        Some((self.update_id + 1 - events.len() as u64, events))
+    }
+}
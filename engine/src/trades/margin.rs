@@ -0,0 +1,103 @@
+use engine::types::MarginConfig;
+use rust_decimal::Decimal;
+use std::collections::{HashMap, HashSet};
+use std::time::Instant;
+
+use super::snapshot::MarginSnapshot;
+
+const MICROS_PER_HOUR: u64 = 3_600_000_000;
+// Decimal places interest is charged to. Left unbounded, a debt's scale grows with
+// every accrual until sums of balances start rounding.
+pub const INTEREST_PRECISION: u32 = 8;
+
+// Currency that account values, margin levels and liquidations are measured in
+pub const VALUATION_ASSET: &str = "INR";
+
+// Exchange-side bookkeeping for margin accounts.
+pub struct MarginState {
+    pub config: MarginConfig,
+    pub enabled_users: HashSet<String>,
+    // Interest charged to borrowers so far per asset, owed to the exchange
+    pub interest_charged: HashMap<String, Decimal>,
+    // (account, asset) pairs with borrowed principal, the only balances interest
+    // accrues on. Rebuilt from balances on restore.
+    pub borrowers: HashSet<(String, String)>,
+    // Margin accounts whose level may have moved since they were last checked
+    pub unchecked: HashSet<String>,
+    // Prices accounts were last checked against, per asset
+    pub checked_prices: HashMap<String, Decimal>,
+    last_accrual: Instant,
+}
+
+impl MarginState {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            config: MarginConfig::default(),
+            enabled_users: HashSet::new(),
+            interest_charged: HashMap::new(),
+            borrowers: HashSet::new(),
+            unchecked: HashSet::new(),
+            checked_prices: HashMap::new(),
+            last_accrual: now,
+        }
+    }
+
+    // Every account is checked again on the first message after a restore.
+    pub fn restore(
+        snapshot: MarginSnapshot,
+        borrowers: HashSet<(String, String)>,
+        now: Instant,
+    ) -> Self {
+        let enabled_users: HashSet<String> = snapshot.enabled_users.into_iter().collect();
+        Self {
+            config: snapshot.config,
+            unchecked: enabled_users.clone(),
+            enabled_users,
+            interest_charged: snapshot.interest_charged,
+            borrowers,
+            checked_prices: HashMap::new(),
+            last_accrual: now.checked_sub(snapshot.since_accrual).unwrap_or(now),
+        }
+    }
+
+    pub fn snapshot(&self, now: Instant) -> MarginSnapshot {
+        MarginSnapshot {
+            config: self.config.clone(),
+            enabled_users: self.enabled_users.iter().cloned().collect(),
+            interest_charged: self.interest_charged.clone(),
+            since_accrual: now.saturating_duration_since(self.last_accrual),
+        }
+    }
+
+    // Fraction of each debt to charge as interest for the time since the last call.
+    pub fn take_interest_factor(&mut self, now: Instant) -> Decimal {
+        let elapsed = now.duration_since(self.last_accrual);
+        self.last_accrual = now;
+        let hours = Decimal::from(elapsed.as_micros() as u64) / Decimal::from(MICROS_PER_HOUR);
+        self.config.hourly_interest_rate * hours
+    }
+}
+
+// An account's holdings and debts valued in the valuation currency.
+#[derive(Default)]
+pub struct AccountValue {
+    pub assets: Decimal,
+    pub liabilities: Decimal,
+}
+
+impl AccountValue {
+    pub fn equity(&self) -> Decimal {
+        self.assets - self.liabilities
+    }
+
+    pub fn margin_level(&self) -> Option<Decimal> {
+        (self.liabilities > Decimal::ZERO).then(|| self.assets / self.liabilities)
+    }
+
+    // Borrowing adds the same value to assets and liabilities, so equity stays put
+    // while leverage grows.
+    pub fn can_borrow(&self, value: Decimal, max_leverage: Decimal) -> bool {
+        let equity = self.equity();
+        equity > Decimal::ZERO && self.assets + value <= equity * max_leverage
+    }
+}
@@ -1,5 +1,13 @@
+mod clock;
 pub mod engine;
 mod events;
+mod l3;
+mod margin;
+mod order_queue;
 mod orderbook;
-pub use engine::*;
+mod perpetual;
+mod protection;
+mod risk;
+pub mod snapshot;
+mod ticker;
 pub use orderbook::*;
@@ -0,0 +1,88 @@
+use engine::types::Order;
+use slab::Slab;
+
+// Resting orders live in a single slab owned by the orderbook; every price level
+// threads its orders through that slab as an intrusive doubly-linked list so the
+// queue keeps arrival order and any node can be unlinked in O(1) by its key.
+pub struct OrderNode {
+    pub order: Order,
+    // Anonymous id the order is known by on the L3 feed
+    pub public_id: u64,
+    prev: Option<usize>,
+    next: Option<usize>,
+}
+
+#[derive(Default)]
+pub struct OrderQueue {
+    head: Option<usize>,
+    tail: Option<usize>,
+}
+
+impl OrderQueue {
+    pub fn front(&self) -> Option<usize> {
+        self.head
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.head.is_none()
+    }
+
+    pub fn push_back(
+        &mut self,
+        nodes: &mut Slab<OrderNode>,
+        order: Order,
+        public_id: u64,
+    ) -> usize {
+        let key = nodes.insert(OrderNode {
+            order,
+            public_id,
+            prev: self.tail,
+            next: None,
+        });
+
+        match self.tail {
+            Some(tail) => nodes[tail].next = Some(key),
+            None => self.head = Some(key),
+        }
+        self.tail = Some(key);
+
+        key
+    }
+
+    pub fn remove(&mut self, nodes: &mut Slab<OrderNode>, key: usize) -> Order {
+        let node = nodes.remove(key);
+
+        match node.prev {
+            Some(prev) => nodes[prev].next = node.next,
+            None => self.head = node.next,
+        }
+        match node.next {
+            Some(next) => nodes[next].prev = node.prev,
+            None => self.tail = node.prev,
+        }
+
+        node.order
+    }
+
+    pub fn iter<'a>(&self, nodes: &'a Slab<OrderNode>) -> OrderQueueIter<'a> {
+        OrderQueueIter {
+            nodes,
+            cursor: self.head,
+        }
+    }
+}
+
+pub struct OrderQueueIter<'a> {
+    nodes: &'a Slab<OrderNode>,
+    cursor: Option<usize>,
+}
+
+impl<'a> Iterator for OrderQueueIter<'a> {
+    type Item = &'a OrderNode;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let node = &self.nodes[self.cursor?];
+        self.cursor = node.next;
+        Some(node)
+    }
+}
@@ -1,14 +1,41 @@
 use std::cmp::min;
 use std::collections::{BTreeMap, HashMap};
+use std::time::Instant;
 
-use engine::types::{DepthPayload, InternalFill, Order, Side};
+use engine::types::{
+    DepthPayload, InternalFill, L3Event, L3Order, L3SnapshotPayload, MarketStatus, Order, Side,
+};
+use rust_decimal::Decimal;
+use slab::Slab;
 
-use super::BASE_CURRENCY;
+use super::l3::L3Feed;
+use super::order_queue::{OrderNode, OrderQueue};
+use super::perpetual::{PerpetualConfig, PerpetualState};
+use super::protection::PriceProtection;
+use super::snapshot::{MarketSnapshot, RestingOrder};
+use super::ticker::TickerStats;
+
+// Price levels per side covered by the depth checksum
+pub const CHECKSUM_LEVELS: usize = 25;
+// Bucket sizes, in ticks, of the aggregated `depth@<market>@<precision>` streams
+pub const DEPTH_STREAM_PRECISIONS: [u64; 2] = [10, 100];
 
 pub struct OrderbookFill {
     pub fill: InternalFill,
     pub other_user_id: String,
-    pub marker_order_id: String,
+    pub maker_order_id: String,
+    // Quantity of the maker order filled so far, this fill included
+    pub maker_filled: u64,
+}
+
+// A match made while uncrossing an auction; both sides were resting, so the buyer
+// may have locked funds at a limit above the clearing price.
+pub struct AuctionFill {
+    pub fill: InternalFill,
+    pub buyer_user_id: String,
+    pub seller_user_id: String,
+    pub buy_order: Order,
+    pub sell_order: Order,
 }
 
 pub struct OrderCreated {
@@ -17,16 +44,28 @@ pub struct OrderCreated {
 }
 
 pub struct Orderbook {
-    pub bids: BTreeMap<u64, Vec<Order>>, // Price -> Orders at that price
-    pub asks: BTreeMap<u64, Vec<Order>>, // Price -> Orders at that price
+    pub bids: BTreeMap<u64, OrderQueue>, // Price -> FIFO queue of orders at that price
+    pub asks: BTreeMap<u64, OrderQueue>, // Price -> FIFO queue of orders at that price
+    orders: Slab<OrderNode>,             // Backing storage for every resting order
     pub base_asset: String,
     pub quote_asset: String,
+    pub status: MarketStatus,
+    pub protection: PriceProtection,
+    // Set for perpetual futures markets, where fills change positions instead of
+    // swapping the base asset
+    pub perpetual: Option<PerpetualState>,
+    pub ticker_stats: TickerStats,
+    // Levels published on the depth stream so far
+    pub depth_update_id: u64,
+    pub l3: L3Feed,
     pub last_trade_id: u64,
     pub current_price: u64,
     // Sorted depth cache using BTreeMap
     pub bids_depth: BTreeMap<u64, u64>,
     pub asks_depth: BTreeMap<u64, u64>,
-    pub order_id_to_price: HashMap<String, (u64, Side)>, // order_id -> (price, side)
+    pub order_id_to_price: HashMap<String, (u64, Side, usize)>, // order_id -> (price, side, slab key)
+    // user_id -> that user's resting orders by public id, which is their placement order
+    pub user_orders: HashMap<String, BTreeMap<u64, String>>,
 }
 
 //add self trade protection
@@ -34,21 +73,31 @@ impl Orderbook {
     //remember to pass last_trade_id and current_price as Option<u64>
     pub fn new(
         base_asset: String,
+        quote_asset: String,
         bids: Vec<Order>,
         asks: Vec<Order>,
         last_trade_id: Option<u64>,
         current_price: Option<u64>,
+        now: Instant,
     ) -> Self {
         let mut orderbook = Orderbook {
             bids: BTreeMap::new(),
             asks: BTreeMap::new(),
+            orders: Slab::new(),
             base_asset,
-            quote_asset: BASE_CURRENCY.to_string(),
+            quote_asset,
+            status: MarketStatus::Trading,
+            protection: PriceProtection::default(),
+            perpetual: None,
+            ticker_stats: TickerStats::new(now),
+            depth_update_id: 0,
+            l3: L3Feed::default(),
             last_trade_id: last_trade_id.unwrap_or(0),
             current_price: current_price.unwrap_or(0),
             bids_depth: BTreeMap::new(),
             asks_depth: BTreeMap::new(),
             order_id_to_price: HashMap::new(),
+            user_orders: HashMap::new(),
         };
 
         for bid in bids {
@@ -62,25 +111,60 @@ impl Orderbook {
     }
 
     fn add_bid_to_level(&mut self, order: Order) {
-        let price = order.price;
-        let quantity = order.quantity - order.filled;
-
-        *self.bids_depth.entry(price).or_insert(0) += quantity;
-        self.order_id_to_price
-            .insert(order.order_id.clone(), (price, Side::Buy));
-
-        self.bids.entry(price).or_insert_with(Vec::new).push(order);
+        let public_id = self.l3.next_order_id();
+        self.l3.push(L3Event::Add {
+            id: public_id,
+            side: Side::Buy,
+            price: order.price,
+            quantity: order.quantity - order.filled,
+        });
+        self.rest_order(Side::Buy, order, public_id);
     }
 
     fn add_ask_to_level(&mut self, order: Order) {
+        let public_id = self.l3.next_order_id();
+        self.l3.push(L3Event::Add {
+            id: public_id,
+            side: Side::Sell,
+            price: order.price,
+            quantity: order.quantity - order.filled,
+        });
+        self.rest_order(Side::Sell, order, public_id);
+    }
+
+    // Queues an order at the back of its price level and indexes it.
+    fn rest_order(&mut self, side: Side, order: Order, public_id: u64) {
         let price = order.price;
         let quantity = order.quantity - order.filled;
+        let order_id = order.order_id.clone();
 
-        *self.asks_depth.entry(price).or_insert(0) += quantity;
-        self.order_id_to_price
-            .insert(order.order_id.clone(), (price, Side::Sell));
+        self.user_orders
+            .entry(order.user_id.clone())
+            .or_default()
+            .insert(public_id, order_id.clone());
+        let (levels, depth) = match side {
+            Side::Buy => (&mut self.bids, &mut self.bids_depth),
+            Side::Sell => (&mut self.asks, &mut self.asks_depth),
+        };
+        *depth.entry(price).or_insert(0) += quantity;
+        let key = levels
+            .entry(price)
+            .or_default()
+            .push_back(&mut self.orders, order, public_id);
+        self.order_id_to_price.insert(order_id, (price, side, key));
+    }
 
-        self.asks.entry(price).or_insert_with(Vec::new).push(order);
+    fn untrack_user_order(
+        user_orders: &mut HashMap<String, BTreeMap<u64, String>>,
+        user_id: &str,
+        public_id: u64,
+    ) {
+        if let Some(order_ids) = user_orders.get_mut(user_id) {
+            order_ids.remove(&public_id);
+            if order_ids.is_empty() {
+                user_orders.remove(user_id);
+            }
+        }
     }
 
     fn remove_from_bids_depth(&mut self, price: u64, quantity: u64) {
@@ -88,9 +172,11 @@ impl Orderbook {
             *total_qty = total_qty.saturating_sub(quantity);
             if *total_qty == 0 {
                 self.bids_depth.remove(&price);
-                self.bids.remove(&price);
             }
         }
+        if self.bids.get(&price).is_some_and(OrderQueue::is_empty) {
+            self.bids.remove(&price);
+        }
     }
 
     fn remove_from_asks_depth(&mut self, price: u64, quantity: u64) {
@@ -98,16 +184,126 @@ impl Orderbook {
             *total_qty = total_qty.saturating_sub(quantity);
             if *total_qty == 0 {
                 self.asks_depth.remove(&price);
-                self.asks.remove(&price);
             }
         }
+        if self.asks.get(&price).is_some_and(OrderQueue::is_empty) {
+            self.asks.remove(&price);
+        }
+    }
+
+    pub fn snapshot(&self, now: Instant) -> MarketSnapshot {
+        let orders = |levels: &mut dyn Iterator<Item = &OrderQueue>| {
+            levels
+                .flat_map(|queue| queue.iter(&self.orders))
+                .map(|node| RestingOrder {
+                    order: node.order.clone(),
+                    public_id: node.public_id,
+                })
+                .collect()
+        };
+
+        MarketSnapshot {
+            base_asset: self.base_asset.clone(),
+            quote_asset: self.quote_asset.clone(),
+            status: self.status,
+            protection: self.protection.snapshot(now),
+            perpetual: self
+                .perpetual
+                .as_ref()
+                .map(|perpetual| perpetual.snapshot(now)),
+            ticker: self.ticker_stats.snapshot(now),
+            depth_update_id: self.depth_update_id,
+            l3: self.l3.snapshot(),
+            last_trade_id: self.last_trade_id,
+            current_price: self.current_price,
+            bids: orders(&mut self.bids.values().rev()),
+            asks: orders(&mut self.asks.values()),
+        }
+    }
+
+    // Orders keep their public ids so L3 consumers can carry on from the
+    // snapshot's update id.
+    pub fn restore(snapshot: MarketSnapshot, now: Instant) -> Self {
+        let mut orderbook = Self::new(
+            snapshot.base_asset,
+            snapshot.quote_asset,
+            vec![],
+            vec![],
+            Some(snapshot.last_trade_id),
+            Some(snapshot.current_price),
+            now,
+        );
+        orderbook.status = snapshot.status;
+        orderbook.protection = PriceProtection::restore(snapshot.protection, now);
+        orderbook.perpetual = snapshot
+            .perpetual
+            .map(|perpetual| PerpetualState::restore(perpetual, now));
+        orderbook.ticker_stats = TickerStats::restore(snapshot.ticker, now);
+        orderbook.depth_update_id = snapshot.depth_update_id;
+        orderbook.l3 = L3Feed::restore(snapshot.l3);
+        for resting in snapshot.bids {
+            orderbook.rest_order(Side::Buy, resting.order, resting.public_id);
+        }
+        for resting in snapshot.asks {
+            orderbook.rest_order(Side::Sell, resting.order, resting.public_id);
+        }
+        orderbook
+    }
+
+    pub fn new_perpetual(
+        base_asset: String,
+        quote_asset: String,
+        index_market: String,
+        now: Instant,
+    ) -> Self {
+        let mut orderbook = Self::new(base_asset, quote_asset, vec![], vec![], None, None, now);
+        orderbook.perpetual = Some(PerpetualState::new(
+            index_market,
+            PerpetualConfig::default(),
+            now,
+        ));
+        orderbook
+    }
+
+    // The asset and amount an order has to lock for the given quantity.
+    pub fn lock_for(&self, side: &Side, price: u64, quantity: u64) -> (&str, Decimal) {
+        let price = Decimal::from(price);
+        let quantity = Decimal::from(quantity);
+        match (&self.perpetual, side) {
+            (Some(perpetual), _) => (&self.quote_asset, perpetual.collateral(price, quantity)),
+            (None, Side::Buy) => (&self.quote_asset, price * quantity),
+            (None, Side::Sell) => (&self.base_asset, quantity),
+        }
     }
 
     pub fn ticker(&self) -> String {
         format!("{}_{}", self.base_asset, self.quote_asset)
     }
 
+    // True if an order at this price would match against the opposite side.
+    pub fn would_cross(&self, side: &Side, price: u64) -> bool {
+        match side {
+            Side::Buy => self.asks.keys().next().is_some_and(|&ask| ask <= price),
+            Side::Sell => self
+                .bids
+                .keys()
+                .next_back()
+                .is_some_and(|&bid| bid >= price),
+        }
+    }
+
     pub fn add_order(&mut self, order: &mut Order) -> OrderCreated {
+        if self.status == MarketStatus::Auction {
+            match order.side {
+                Side::Buy => self.add_bid_to_level(order.clone()),
+                Side::Sell => self.add_ask_to_level(order.clone()),
+            }
+            return OrderCreated {
+                executed_quantity: 0,
+                fills: Vec::new(),
+            };
+        }
+
         match order.side {
             Side::Buy => {
                 let ongoing_order = self.match_asks(order);
@@ -116,7 +312,7 @@ impl Orderbook {
                     return ongoing_order;
                 }
                 self.add_bid_to_level(order.clone());
-                return ongoing_order;
+                ongoing_order
             }
             Side::Sell => {
                 let ongoing_order = self.match_bids(order);
@@ -125,7 +321,7 @@ impl Orderbook {
                     return ongoing_order;
                 }
                 self.add_ask_to_level(order.clone());
-                return ongoing_order;
+                ongoing_order
             }
         }
     }
@@ -134,24 +330,33 @@ impl Orderbook {
         let mut fills: Vec<OrderbookFill> = Vec::with_capacity(4);
         let mut executed_quantity: u64 = 0;
 
-        let mut prices_to_remove = Vec::new();
-
-        for (&ask_price, ask_orders) in self.asks.iter_mut() {
-            if ask_price > order.price || executed_quantity >= order.quantity {
+        while executed_quantity < order.quantity {
+            let Some((&ask_price, ask_orders)) = self.asks.iter_mut().next() else {
+                break;
+            };
+            if ask_price > order.price {
                 break;
             }
 
-            ask_orders.retain_mut(|ask| {
-                if executed_quantity >= order.quantity {
-                    return true;
-                }
+            while executed_quantity < order.quantity {
+                let Some(key) = ask_orders.front() else {
+                    break;
+                };
+                let node = &mut self.orders[key];
+                let public_id = node.public_id;
+                let ask = &mut node.order;
 
                 let remaining_ask_qty = ask.quantity - ask.filled;
                 let filled_qty = min(remaining_ask_qty, order.quantity - executed_quantity);
 
                 executed_quantity += filled_qty;
                 ask.filled += filled_qty;
+                self.l3.push(L3Event::Execute {
+                    id: public_id,
+                    quantity: filled_qty,
+                });
                 self.last_trade_id += 1;
+                self.current_price = ask_price;
 
                 if let Some(depth_qty) = self.asks_depth.get_mut(&ask_price) {
                     *depth_qty = depth_qty.saturating_sub(filled_qty);
@@ -160,27 +365,23 @@ impl Orderbook {
                 fills.push(OrderbookFill {
                     fill: InternalFill::new(ask.price, filled_qty, self.last_trade_id),
                     other_user_id: ask.user_id.clone(),
-                    marker_order_id: order.order_id.clone(),
+                    maker_order_id: ask.order_id.clone(),
+                    maker_filled: ask.filled,
                 });
 
-                let should_remove = ask.filled >= ask.quantity;
-                if should_remove {
-                    self.order_id_to_price.remove(&ask.order_id);
+                if ask.filled >= ask.quantity {
+                    let filled_ask = ask_orders.remove(&mut self.orders, key);
+                    self.order_id_to_price.remove(&filled_ask.order_id);
+                    Self::untrack_user_order(&mut self.user_orders, &filled_ask.user_id, public_id);
                 }
-
-                !should_remove
-            });
+            }
 
             if ask_orders.is_empty() {
-                prices_to_remove.push(ask_price);
+                self.asks.remove(&ask_price);
+                self.asks_depth.remove(&ask_price);
             }
         }
 
-        for price in prices_to_remove {
-            self.asks.remove(&price);
-            self.asks_depth.remove(&price);
-        }
-
         OrderCreated {
             fills,
             executed_quantity,
@@ -191,24 +392,33 @@ impl Orderbook {
         let mut fills: Vec<OrderbookFill> = Vec::with_capacity(4);
         let mut executed_qty: u64 = 0;
 
-        let mut prices_to_remove = Vec::new();
-
-        for (&bid_price, bid_orders) in self.bids.iter_mut().rev() {
-            if bid_price < order.price || executed_qty >= order.quantity {
+        while executed_qty < order.quantity {
+            let Some((&bid_price, bid_orders)) = self.bids.iter_mut().next_back() else {
+                break;
+            };
+            if bid_price < order.price {
                 break;
             }
 
-            bid_orders.retain_mut(|bid| {
-                if executed_qty >= order.quantity {
-                    return true;
-                }
+            while executed_qty < order.quantity {
+                let Some(key) = bid_orders.front() else {
+                    break;
+                };
+                let node = &mut self.orders[key];
+                let public_id = node.public_id;
+                let bid = &mut node.order;
 
                 let remaining_bid_qty = bid.quantity - bid.filled;
                 let amount_remaining = min(remaining_bid_qty, order.quantity - executed_qty);
 
                 executed_qty += amount_remaining;
                 bid.filled += amount_remaining;
+                self.l3.push(L3Event::Execute {
+                    id: public_id,
+                    quantity: amount_remaining,
+                });
                 self.last_trade_id += 1;
+                self.current_price = bid_price;
 
                 if let Some(depth_qty) = self.bids_depth.get_mut(&bid_price) {
                     *depth_qty = depth_qty.saturating_sub(amount_remaining);
@@ -217,108 +427,705 @@ impl Orderbook {
                 fills.push(OrderbookFill {
                     fill: InternalFill::new(bid.price, amount_remaining, self.last_trade_id),
                     other_user_id: bid.user_id.clone(),
-                    marker_order_id: bid.order_id.clone(),
+                    maker_order_id: bid.order_id.clone(),
+                    maker_filled: bid.filled,
                 });
 
-                let should_remove = bid.filled >= bid.quantity;
-                if should_remove {
-                    self.order_id_to_price.remove(&bid.order_id);
+                if bid.filled >= bid.quantity {
+                    let filled_bid = bid_orders.remove(&mut self.orders, key);
+                    self.order_id_to_price.remove(&filled_bid.order_id);
+                    Self::untrack_user_order(&mut self.user_orders, &filled_bid.user_id, public_id);
                 }
-
-                !should_remove
-            });
+            }
 
             if bid_orders.is_empty() {
-                prices_to_remove.push(bid_price);
+                self.bids.remove(&bid_price);
+                self.bids_depth.remove(&bid_price);
             }
         }
 
-        for price in prices_to_remove {
-            self.bids.remove(&price);
-            self.bids_depth.remove(&price);
-        }
-
         OrderCreated {
             fills,
             executed_quantity: executed_qty,
         }
     }
 
-    //uses cachded depth
-    pub fn get_depth(&self) -> DepthPayload {
-      
-
-        let bids: Vec<[String; 2]> = self
+    // The auction clearing price is the one that executes the most volume. Ties go to
+    // the smallest surplus left on either side, then to the price nearest the last
+    // trade, then to the lower price.
+    pub fn indicative_uncross(&self) -> Option<(u64, u64)> {
+        let mut candidates: Vec<u64> = self
             .bids_depth
-            .iter()
-            .rev()
-            .filter(|(_, &qty)| qty > 0)
-            .map(|(&price, &qty)| [price.to_string(), qty.to_string()])
+            .keys()
+            .chain(self.asks_depth.keys())
+            .copied()
             .collect();
+        candidates.sort_unstable();
+        candidates.dedup();
+
+        let mut best: Option<(u64, u64, u64)> = None; // (price, volume, imbalance)
+        for price in candidates {
+            let buy_volume: u64 = self.bids_depth.range(price..).map(|(_, q)| q).sum();
+            let sell_volume: u64 = self.asks_depth.range(..=price).map(|(_, q)| q).sum();
+            let volume = min(buy_volume, sell_volume);
+            if volume == 0 {
+                continue;
+            }
+            let imbalance = buy_volume.abs_diff(sell_volume);
+
+            let better = match best {
+                None => true,
+                Some((best_price, best_volume, best_imbalance)) => {
+                    (volume, std::cmp::Reverse(imbalance))
+                        .cmp(&(best_volume, std::cmp::Reverse(best_imbalance)))
+                        .then_with(|| {
+                            best_price
+                                .abs_diff(self.current_price)
+                                .cmp(&price.abs_diff(self.current_price))
+                        })
+                        .is_gt()
+                }
+            };
+            if better {
+                best = Some((price, volume, imbalance));
+            }
+        }
+
+        best.map(|(price, volume, _)| (price, volume))
+    }
 
-        let asks: Vec<[String; 2]> = self
-            .asks_depth
-            .iter()
+    // Fills every crossing order at the single clearing price, in price-time priority
+    // on both sides, leaving an uncrossed book behind.
+    pub fn uncross(&mut self) -> Vec<AuctionFill> {
+        let mut fills = Vec::new();
+        let Some((clearing_price, _)) = self.indicative_uncross() else {
+            return fills;
+        };
+
+        while let (Some((&bid_price, bid_orders)), Some((&ask_price, ask_orders))) =
+            (self.bids.iter().next_back(), self.asks.iter().next())
+        {
+            if bid_price < clearing_price || ask_price > clearing_price {
+                break;
+            }
+            let (Some(bid_key), Some(ask_key)) = (bid_orders.front(), ask_orders.front()) else {
+                break;
+            };
+
+            let bid = &self.orders[bid_key].order;
+            let ask = &self.orders[ask_key].order;
+            let quantity = min(bid.quantity - bid.filled, ask.quantity - ask.filled);
+
+            self.orders[bid_key].order.filled += quantity;
+            self.orders[ask_key].order.filled += quantity;
+            for key in [bid_key, ask_key] {
+                self.l3.push(L3Event::Execute {
+                    id: self.orders[key].public_id,
+                    quantity,
+                });
+            }
+            self.last_trade_id += 1;
+            if let Some(depth_qty) = self.bids_depth.get_mut(&bid_price) {
+                *depth_qty = depth_qty.saturating_sub(quantity);
+            }
+            if let Some(depth_qty) = self.asks_depth.get_mut(&ask_price) {
+                *depth_qty = depth_qty.saturating_sub(quantity);
+            }
+
+            let buy_order = self.orders[bid_key].order.clone();
+            let sell_order = self.orders[ask_key].order.clone();
+            if buy_order.filled >= buy_order.quantity {
+                self.remove_filled(bid_price, Side::Buy, bid_key);
+            }
+            if sell_order.filled >= sell_order.quantity {
+                self.remove_filled(ask_price, Side::Sell, ask_key);
+            }
+
+            fills.push(AuctionFill {
+                fill: InternalFill::new(clearing_price, quantity, self.last_trade_id),
+                buyer_user_id: buy_order.user_id.clone(),
+                seller_user_id: sell_order.user_id.clone(),
+                buy_order,
+                sell_order,
+            });
+        }
+
+        self.current_price = clearing_price;
+        fills
+    }
+
+    fn remove_filled(&mut self, price: u64, side: Side, key: usize) {
+        let (levels, depth) = match side {
+            Side::Buy => (&mut self.bids, &mut self.bids_depth),
+            Side::Sell => (&mut self.asks, &mut self.asks_depth),
+        };
+        let Some(orders) = levels.get_mut(&price) else {
+            return;
+        };
+        let public_id = self.orders[key].public_id;
+        let filled = orders.remove(&mut self.orders, key);
+        if orders.is_empty() {
+            levels.remove(&price);
+            depth.remove(&price);
+        }
+        self.order_id_to_price.remove(&filled.order_id);
+        Self::untrack_user_order(&mut self.user_orders, &filled.user_id, public_id);
+    }
+
+    //uses cachded depth
+    // The top `limit` levels per side with prices grouped into buckets of
+    // `precision` ticks.
+    pub fn aggregated_depth(&self, limit: Option<usize>, precision: u64) -> DepthPayload {
+        let levels = |side: &Side| {
+            self.depth_levels(side, precision)
+                .take(limit.unwrap_or(usize::MAX))
+                .map(|(price, qty)| [price.to_string(), qty.to_string()])
+                .collect()
+        };
+
+        DepthPayload {
+            bids: levels(&Side::Buy),
+            asks: levels(&Side::Sell),
+            last_update_id: self.depth_update_id,
+            checksum: self.checksum(precision),
+        }
+    }
+
+    // Bids round down and asks round up, so a bucket never shows a better price
+    // than the orders in it.
+    pub fn bucket(side: &Side, price: u64, precision: u64) -> u64 {
+        match side {
+            Side::Buy => price / precision * precision,
+            Side::Sell => price.div_ceil(precision) * precision,
+        }
+    }
+
+    // Total quantity resting in one bucket.
+    pub fn aggregated_level(&self, side: &Side, bucket: u64, precision: u64) -> u64 {
+        match side {
+            Side::Buy => self
+                .bids_depth
+                .range(bucket..=bucket.saturating_add(precision - 1))
+                .map(|(_, qty)| qty)
+                .sum(),
+            Side::Sell => self
+                .asks_depth
+                .range(bucket.saturating_sub(precision - 1)..=bucket)
+                .map(|(_, qty)| qty)
+                .sum(),
+        }
+    }
+
+    // Non-empty buckets of one side, best first.
+    fn depth_levels(&self, side: &Side, precision: u64) -> impl Iterator<Item = (u64, u64)> + '_ {
+        let depth: Box<dyn Iterator<Item = (&u64, &u64)>> = match side {
+            Side::Buy => Box::new(self.bids_depth.iter().rev()),
+            Side::Sell => Box::new(self.asks_depth.iter()),
+        };
+        let side = side.clone();
+        let mut depth = depth
             .filter(|(_, &qty)| qty > 0)
-            .map(|(&price, &qty)| [price.to_string(), qty.to_string()])
-            .collect();
+            .map(move |(&price, &qty)| (Self::bucket(&side, price, precision), qty))
+            .peekable();
+        std::iter::from_fn(move || {
+            let (bucket, mut qty) = depth.next()?;
+            while let Some((_, more)) = depth.next_if(|(next, _)| *next == bucket) {
+                qty += more;
+            }
+            Some((bucket, qty))
+        })
+    }
+
+    // CRC32 of the top levels as `price:quantity` pairs, best bid then best ask then
+    // the next bid and so on, all joined with `:`.
+    pub fn checksum(&self, precision: u64) -> u32 {
+        let mut bids = self.depth_levels(&Side::Buy, precision);
+        let mut asks = self.depth_levels(&Side::Sell, precision);
+        let mut entries = Vec::with_capacity(CHECKSUM_LEVELS * 2);
+        for _ in 0..CHECKSUM_LEVELS {
+            for (price, qty) in [bids.next(), asks.next()].into_iter().flatten() {
+                entries.push(format!("{}:{}", price, qty));
+            }
+        }
+        crc32fast::hash(entries.join(":").as_bytes())
+    }
+
+    // Every resting order in queue order, best price first on each side.
+    pub fn l3_snapshot(&self) -> L3SnapshotPayload {
+        let orders = |levels: &mut dyn Iterator<Item = &OrderQueue>| {
+            levels
+                .flat_map(|queue| queue.iter(&self.orders))
+                .map(|node| L3Order {
+                    id: node.public_id,
+                    price: node.order.price,
+                    quantity: node.order.quantity - node.order.filled,
+                })
+                .collect()
+        };
 
-        DepthPayload { bids, asks }
+        L3SnapshotPayload {
+            market: self.ticker(),
+            bids: orders(&mut self.bids.values().rev()),
+            asks: orders(&mut self.asks.values()),
+            last_update_id: self.l3.update_id,
+        }
+    }
+
+    // Resting orders of the best `levels` prices on each side, in queue order
+    pub fn top_orders(&self, levels: usize) -> (Vec<Order>, Vec<Order>) {
+        let orders = |queues: &mut dyn Iterator<Item = &OrderQueue>| {
+            queues
+                .take(levels)
+                .flat_map(|queue| queue.iter(&self.orders))
+                .map(|node| node.order.clone())
+                .collect()
+        };
+        (
+            orders(&mut self.bids.values().rev()),
+            orders(&mut self.asks.values()),
+        )
+    }
+
+    pub fn resting_orders(&self) -> impl Iterator<Item = &Order> {
+        self.orders.iter().map(|(_, node)| &node.order)
+    }
+
+    pub fn get_order(&self, order_id: &str) -> Option<&Order> {
+        self.order_id_to_price
+            .get(order_id)
+            .map(|&(_, _, key)| &self.orders[key].order)
     }
 
     pub fn cancel_bid(&mut self, order: &Order) -> Option<u64> {
-        if let Some(&(price, Side::Buy)) = self.order_id_to_price.get(&order.order_id) {
+        if let Some(&(price, Side::Buy, key)) = self.order_id_to_price.get(&order.order_id) {
             if let Some(orders) = self.bids.get_mut(&price) {
-                if let Some(index) = orders.iter().position(|bid| bid.order_id == order.order_id) {
-                    let removed_order = orders.swap_remove(index);
-                    self.order_id_to_price.remove(&order.order_id);
-                    self.remove_from_bids_depth(
-                        price,
-                        removed_order.quantity - removed_order.filled,
-                    );
-                    return Some(price);
-                }
+                let public_id = self.orders[key].public_id;
+                self.l3.push(L3Event::Cancel { id: public_id });
+                let removed_order = orders.remove(&mut self.orders, key);
+                self.order_id_to_price.remove(&order.order_id);
+                Self::untrack_user_order(&mut self.user_orders, &removed_order.user_id, public_id);
+                self.remove_from_bids_depth(price, removed_order.quantity - removed_order.filled);
+                return Some(price);
             }
         }
         None
     }
 
     pub fn cancel_ask(&mut self, order: &Order) -> Option<u64> {
-        if let Some(&(price, Side::Sell)) = self.order_id_to_price.get(&order.order_id) {
+        if let Some(&(price, Side::Sell, key)) = self.order_id_to_price.get(&order.order_id) {
             if let Some(orders) = self.asks.get_mut(&price) {
-                if let Some(index) = orders.iter().position(|ask| ask.order_id == order.order_id) {
-                    let removed_order = orders.swap_remove(index);
-                    self.order_id_to_price.remove(&order.order_id);
-                    self.remove_from_asks_depth(
-                        price,
-                        removed_order.quantity - removed_order.filled,
-                    );
-                    return Some(price);
-                }
+                let public_id = self.orders[key].public_id;
+                self.l3.push(L3Event::Cancel { id: public_id });
+                let removed_order = orders.remove(&mut self.orders, key);
+                self.order_id_to_price.remove(&order.order_id);
+                Self::untrack_user_order(&mut self.user_orders, &removed_order.user_id, public_id);
+                self.remove_from_asks_depth(price, removed_order.quantity - removed_order.filled);
+                return Some(price);
             }
         }
         None
     }
 
+    pub fn open_order_count(&self, user_id: &str) -> u64 {
+        self.user_orders
+            .get(user_id)
+            .map_or(0, |order_ids| order_ids.len() as u64)
+    }
+
+    // Quote value of the unfilled part of a user's resting orders at their limit prices.
+    pub fn open_notional(&self, user_id: &str) -> Decimal {
+        self.user_orders
+            .get(user_id)
+            .into_iter()
+            .flat_map(|order_ids| order_ids.values())
+            .filter_map(|order_id| self.get_order(order_id))
+            .map(|order| Decimal::from(order.price) * Decimal::from(order.quantity - order.filled))
+            .sum()
+    }
+
     pub fn get_open_orders(&self, user_id: String) -> Vec<Order> {
-        let mut open_orders = Vec::new();
+        self.user_orders
+            .get(&user_id)
+            .map(|order_ids| {
+                order_ids
+                    .values()
+                    .filter_map(|order_id| self.get_order(order_id))
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    #[derive(Debug, Clone)]
+    enum Op {
+        Place(bool, u64, u64),
+        Cancel(usize),
+    }
+
+    struct ModelOrder {
+        order_id: String,
+        side: Side,
+        price: u64,
+        remaining: u64,
+    }
+
+    fn op_strategy() -> impl Strategy<Value = Op> {
+        prop_oneof![
+            3 => (any::<bool>(), 1u64..=8, 1u64..=20).prop_map(|(b, p, q)| Op::Place(b, p, q)),
+            1 => any::<usize>().prop_map(Op::Cancel),
+        ]
+    }
+
+    fn new_order(order_id: String, side: Side, price: u64, quantity: u64) -> Order {
+        Order {
+            price,
+            quantity,
+            order_id,
+            filled: 0,
+            side,
+            user_id: "1".to_string(),
+        }
+    }
+
+    // Reference matcher: resting orders are kept in arrival order and the taker walks
+    // them best price first, oldest first.
+    fn model_match(resting: &mut Vec<ModelOrder>, taker: &Order) -> Vec<(String, u64, u64)> {
+        let crosses = |o: &ModelOrder| match taker.side {
+            Side::Buy => matches!(o.side, Side::Sell) && o.price <= taker.price,
+            Side::Sell => matches!(o.side, Side::Buy) && o.price >= taker.price,
+        };
+        let mut candidates: Vec<usize> = (0..resting.len())
+            .filter(|&i| crosses(&resting[i]))
+            .collect();
+        candidates.sort_by_key(|&i| match taker.side {
+            Side::Buy => (resting[i].price, i),
+            Side::Sell => (u64::MAX - resting[i].price, i),
+        });
 
-        for orders in self.bids.values() {
-            for order in orders {
-                if order.user_id == user_id {
-                    open_orders.push(order.clone());
+        let mut left = taker.quantity;
+        let mut fills = Vec::new();
+        for i in candidates {
+            if left == 0 {
+                break;
+            }
+            let qty = min(left, resting[i].remaining);
+            resting[i].remaining -= qty;
+            left -= qty;
+            fills.push((resting[i].order_id.clone(), resting[i].price, qty));
+        }
+        resting.retain(|o| o.remaining > 0);
+        if left > 0 {
+            resting.push(ModelOrder {
+                order_id: taker.order_id.clone(),
+                side: taker.side.clone(),
+                price: taker.price,
+                remaining: left,
+            });
+        }
+        fills
+    }
+
+    fn model_depth(resting: &[ModelOrder], side: Side) -> BTreeMap<u64, u64> {
+        let mut depth = BTreeMap::new();
+        for o in resting.iter().filter(|o| o.side.as_str() == side.as_str()) {
+            *depth.entry(o.price).or_insert(0) += o.remaining;
+        }
+        depth
+    }
+
+    #[test]
+    fn open_orders_come_back_in_placement_order() {
+        let mut book = Orderbook::new(
+            "TATA".to_string(),
+            "INR".to_string(),
+            vec![],
+            vec![],
+            None,
+            None,
+            Instant::now(),
+        );
+        let placed = [
+            ("a", Side::Sell, 105),
+            ("b", Side::Buy, 90),
+            ("c", Side::Sell, 101),
+            ("d", Side::Buy, 95),
+            ("e", Side::Sell, 103),
+        ];
+        for (order_id, side, price) in placed {
+            book.add_order(&mut new_order(order_id.to_string(), side, price, 5));
+        }
+        let c = book.get_order("c").cloned().unwrap();
+        book.cancel_ask(&c);
+
+        let open_ids = |book: &Orderbook| -> Vec<String> {
+            book.get_open_orders("1".to_string())
+                .into_iter()
+                .map(|order| order.order_id)
+                .collect()
+        };
+        assert_eq!(open_ids(&book), ["a", "b", "d", "e"]);
+        let restored = Orderbook::restore(book.snapshot(Instant::now()), Instant::now());
+        assert_eq!(open_ids(&restored), ["a", "b", "d", "e"]);
+    }
+
+    #[test]
+    fn checksum_joins_the_best_levels_alternating_sides() {
+        let mut book = Orderbook::new(
+            "TATA".to_string(),
+            "INR".to_string(),
+            vec![],
+            vec![],
+            None,
+            None,
+            Instant::now(),
+        );
+        for (order_id, side, price, quantity) in [
+            ("a", Side::Buy, 5, 2),
+            ("b", Side::Buy, 4, 1),
+            ("c", Side::Buy, 4, 3),
+            ("d", Side::Sell, 7, 3),
+        ] {
+            book.add_order(&mut new_order(order_id.to_string(), side, price, quantity));
+        }
+        assert_eq!(book.checksum(1), crc32fast::hash(b"5:2:7:3:4:4"));
+        assert_eq!(book.checksum(10), crc32fast::hash(b"0:6:10:3"));
+    }
+
+    #[test]
+    fn checksum_covers_only_the_top_levels() {
+        let mut book = Orderbook::new(
+            "TATA".to_string(),
+            "INR".to_string(),
+            vec![],
+            vec![],
+            None,
+            None,
+            Instant::now(),
+        );
+        let deepest = 100;
+        let best = deepest + CHECKSUM_LEVELS as u64;
+        for price in deepest..=best {
+            book.add_order(&mut new_order(price.to_string(), Side::Buy, price, 1));
+        }
+        let checksum = book.checksum(1);
+
+        book.add_order(&mut new_order("deep".to_string(), Side::Buy, deepest, 1));
+        assert_eq!(book.checksum(1), checksum);
+        book.add_order(&mut new_order("top".to_string(), Side::Buy, best, 1));
+        assert_ne!(book.checksum(1), checksum);
+    }
+
+    #[test]
+    fn buckets_round_bids_down_and_asks_up() {
+        let buckets = |side: Side| -> Vec<u64> {
+            [0, 1, 9, 10, 11, 20]
+                .into_iter()
+                .map(|price| Orderbook::bucket(&side, price, 10))
+                .collect()
+        };
+        assert_eq!(buckets(Side::Buy), [0, 0, 0, 10, 10, 20]);
+        assert_eq!(buckets(Side::Sell), [0, 10, 10, 10, 20, 20]);
+        assert_eq!(Orderbook::bucket(&Side::Sell, 11, 1), 11);
+
+        let mut book = Orderbook::new(
+            "TATA".to_string(),
+            "INR".to_string(),
+            vec![],
+            vec![],
+            None,
+            None,
+            Instant::now(),
+        );
+        for (order_id, side, price) in [
+            ("a", Side::Buy, 10),
+            ("b", Side::Buy, 19),
+            ("c", Side::Buy, 20),
+            ("d", Side::Sell, 21),
+            ("e", Side::Sell, 30),
+            ("f", Side::Sell, 31),
+        ] {
+            book.add_order(&mut new_order(order_id.to_string(), side, price, 1));
+        }
+        assert_eq!(book.aggregated_level(&Side::Buy, 10, 10), 2);
+        assert_eq!(book.aggregated_level(&Side::Buy, 20, 10), 1);
+        assert_eq!(book.aggregated_level(&Side::Sell, 30, 10), 2);
+        assert_eq!(book.aggregated_level(&Side::Sell, 40, 10), 1);
+        let depth = book.aggregated_depth(None, 10);
+        let levels = |levels: &[(u64, u64)]| -> Vec<[String; 2]> {
+            levels
+                .iter()
+                .map(|(p, q)| [p.to_string(), q.to_string()])
+                .collect()
+        };
+        assert_eq!(depth.bids, levels(&[(20, 1), (10, 2)]));
+        assert_eq!(depth.asks, levels(&[(30, 2), (40, 1)]));
+    }
+
+    proptest! {
+        #[test]
+        fn matching_preserves_price_time_priority(ops in prop::collection::vec(op_strategy(), 1..200)) {
+            let mut book = Orderbook::new("TATA".to_string(), "INR".to_string(), vec![], vec![], None, None, Instant::now());
+            let mut model: Vec<ModelOrder> = Vec::new();
+            let mut placed: Vec<String> = Vec::new();
+
+            for op in ops {
+                match op {
+                    Op::Place(is_buy, price, quantity) => {
+                        let side = if is_buy { Side::Buy } else { Side::Sell };
+                        let mut order = new_order(placed.len().to_string(), side, price, quantity);
+                        placed.push(order.order_id.clone());
+
+                        let expected = model_match(&mut model, &order);
+                        let created = book.add_order(&mut order);
+                        let actual: Vec<(String, u64, u64)> = created
+                            .fills
+                            .iter()
+                            .map(|f| (f.maker_order_id.clone(), f.fill.price_u64, f.fill.qty))
+                            .collect();
+                        prop_assert_eq!(actual, expected);
+                    }
+                    Op::Cancel(index) => {
+                        if placed.is_empty() {
+                            continue;
+                        }
+                        let order_id = &placed[index % placed.len()];
+                        let resting = book.get_order(order_id).cloned();
+                        let in_model = model.iter().position(|o| &o.order_id == order_id);
+                        prop_assert_eq!(resting.is_some(), in_model.is_some());
+                        if let Some(order) = resting {
+                            let cancelled = match order.side {
+                                Side::Buy => book.cancel_bid(&order),
+                                Side::Sell => book.cancel_ask(&order),
+                            };
+                            prop_assert_eq!(cancelled, Some(order.price));
+                            model.remove(in_model.unwrap());
+                        }
+                    }
                 }
+
+                prop_assert_eq!(&book.bids_depth, &model_depth(&model, Side::Buy));
+                prop_assert_eq!(&book.asks_depth, &model_depth(&model, Side::Sell));
+                prop_assert_eq!(book.order_id_to_price.len(), model.len());
+                prop_assert_eq!(book.get_open_orders("1".to_string()).len(), model.len());
+                prop_assert_eq!(book.bids.keys().collect::<Vec<_>>(), book.bids_depth.keys().collect::<Vec<_>>());
+                prop_assert_eq!(book.asks.keys().collect::<Vec<_>>(), book.asks_depth.keys().collect::<Vec<_>>());
             }
         }
 
-        for orders in self.asks.values() {
-            for order in orders {
-                if order.user_id == user_id {
-                    open_orders.push(order.clone());
+        #[test]
+        fn cancel_keeps_queue_order_of_remaining_orders(
+            quantities in prop::collection::vec(1u64..=10, 2..40),
+            cancel_mask in prop::collection::vec(any::<bool>(), 40),
+        ) {
+            let asks: Vec<Order> = quantities
+                .iter()
+                .enumerate()
+                .map(|(i, &q)| new_order(i.to_string(), Side::Sell, 100, q))
+                .collect();
+            let mut book = Orderbook::new("TATA".to_string(), "INR".to_string(), vec![], asks.clone(), None, None, Instant::now());
+
+            for (ask, &cancel) in asks.iter().zip(cancel_mask.iter()) {
+                if cancel {
+                    prop_assert_eq!(book.cancel_ask(ask), Some(100));
                 }
             }
+
+            let expected: Vec<String> = asks
+                .iter()
+                .zip(cancel_mask.iter())
+                .filter(|(_, &cancel)| !cancel)
+                .map(|(ask, _)| ask.order_id.clone())
+                .collect();
+            let queued: Vec<String> = book
+                .asks
+                .get(&100)
+                .map(|queue| queue.iter(&book.orders).map(|node| node.order.order_id.clone()).collect())
+                .unwrap_or_default();
+            prop_assert_eq!(&queued, &expected);
+
+            let total: u64 = quantities.iter().sum();
+            let mut taker = new_order("taker".to_string(), Side::Buy, 100, total);
+            let fills: Vec<String> = book
+                .add_order(&mut taker)
+                .fills
+                .iter()
+                .map(|f| f.maker_order_id.clone())
+                .collect();
+            prop_assert_eq!(fills, expected);
+            prop_assert!(book.asks.is_empty());
+        }
+
+        #[test]
+        fn uncross_executes_indicative_volume_at_one_price(
+            orders in prop::collection::vec((any::<bool>(), 1u64..=8, 1u64..=20), 1..60),
+        ) {
+            let mut book = Orderbook::new("TATA".to_string(), "INR".to_string(), vec![], vec![], None, None, Instant::now());
+            book.status = MarketStatus::Auction;
+            for (i, (is_buy, price, quantity)) in orders.into_iter().enumerate() {
+                let side = if is_buy { Side::Buy } else { Side::Sell };
+                let created = book.add_order(&mut new_order(i.to_string(), side, price, quantity));
+                prop_assert!(created.fills.is_empty());
+            }
+
+            let indicative = book.indicative_uncross();
+            let fills = book.uncross();
+            let volume: u64 = fills.iter().map(|f| f.fill.qty).sum();
+            prop_assert_eq!(volume, indicative.map_or(0, |(_, volume)| volume));
+            if let Some((price, _)) = indicative {
+                prop_assert!(fills.iter().all(|f| f.fill.price_u64 == price));
+                prop_assert_eq!(book.current_price, price);
+            }
+            if let (Some(best_bid), Some(best_ask)) = (book.bids.keys().next_back(), book.asks.keys().next()) {
+                prop_assert!(best_bid < best_ask);
+            }
+            prop_assert_eq!(book.bids.keys().collect::<Vec<_>>(), book.bids_depth.keys().collect::<Vec<_>>());
+            prop_assert_eq!(book.asks.keys().collect::<Vec<_>>(), book.asks_depth.keys().collect::<Vec<_>>());
+            prop_assert_eq!(book.order_id_to_price.len(), book.resting_orders().count());
         }
 
-        open_orders
+        #[test]
+        fn aggregated_depth_groups_levels_into_buckets(
+            bids in prop::collection::vec((1u64..=50, 1u64..=20), 0..30),
+            asks in prop::collection::vec((51u64..=100, 1u64..=20), 0..30),
+            precision in 1u64..=20,
+            limit in 1usize..=10,
+        ) {
+            let mut book = Orderbook::new("TATA".to_string(), "INR".to_string(), vec![], vec![], None, None, Instant::now());
+            let mut expected = [BTreeMap::new(), BTreeMap::new()];
+            for (i, (side, price, quantity)) in bids
+                .iter()
+                .map(|&(p, q)| (Side::Buy, p, q))
+                .chain(asks.iter().map(|&(p, q)| (Side::Sell, p, q)))
+                .enumerate()
+            {
+                let bucket = Orderbook::bucket(&side, price, precision);
+                let index = usize::from(matches!(side, Side::Sell));
+                *expected[index].entry(bucket).or_insert(0u64) += quantity;
+                book.add_order(&mut new_order(i.to_string(), side, price, quantity));
+            }
+
+            let format = |levels: Vec<(&u64, &u64)>| -> Vec<[String; 2]> {
+                levels
+                    .into_iter()
+                    .take(limit)
+                    .map(|(p, q)| [p.to_string(), q.to_string()])
+                    .collect()
+            };
+            let depth = book.aggregated_depth(Some(limit), precision);
+            prop_assert_eq!(depth.bids, format(expected[0].iter().rev().collect()));
+            prop_assert_eq!(depth.asks, format(expected[1].iter().collect()));
+            for (bucket, quantity) in &expected[0] {
+                prop_assert_eq!(book.aggregated_level(&Side::Buy, *bucket, precision), *quantity);
+            }
+            for (bucket, quantity) in &expected[1] {
+                prop_assert_eq!(book.aggregated_level(&Side::Sell, *bucket, precision), *quantity);
+            }
+        }
     }
 }
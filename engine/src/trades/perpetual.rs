@@ -0,0 +1,174 @@
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use super::snapshot::PerpetualSnapshot;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PerpetualConfig {
+    // Share of an order's notional locked as collateral, in the quote asset
+    pub initial_margin_rate: Decimal,
+    pub funding_interval: Duration,
+    // Cap on the funding rate charged in a single interval, either way
+    pub max_funding_rate: Decimal,
+}
+
+impl Default for PerpetualConfig {
+    fn default() -> Self {
+        Self {
+            initial_margin_rate: Decimal::new(1, 1),
+            funding_interval: Duration::from_secs(8 * 60 * 60),
+            max_funding_rate: Decimal::new(75, 4),
+        }
+    }
+}
+
+// Positive size is long, negative is short. Margin is the collateral backing it.
+#[derive(Default, Clone, Serialize, Deserialize)]
+pub struct Position {
+    pub size: i64,
+    pub entry_price: Decimal,
+    pub margin: Decimal,
+}
+
+impl Position {
+    pub fn unrealized_pnl(&self, mark_price: Decimal) -> Decimal {
+        (mark_price - self.entry_price) * Decimal::from(self.size)
+    }
+}
+
+// Decimal places kept for entry prices, margins and funding rates. Bounding the
+// scale keeps later sums exact, so cash moved between users always nets to zero.
+const PRECISION: u32 = 8;
+
+// State of a perpetual market on top of its order book. Realized PnL is paid out of
+// (or into) a settlement pool, which returns to zero once every position is closed
+// since each long is matched by a short.
+pub struct PerpetualState {
+    pub config: PerpetualConfig,
+    // Spot market whose last price serves as the index price
+    pub index_market: String,
+    pub positions: HashMap<String, Position>,
+    pub settlement_pool: Decimal,
+    next_funding: Instant,
+}
+
+impl PerpetualState {
+    pub fn new(index_market: String, config: PerpetualConfig, now: Instant) -> Self {
+        let next_funding = now + config.funding_interval;
+        Self {
+            config,
+            index_market,
+            positions: HashMap::new(),
+            settlement_pool: Decimal::ZERO,
+            next_funding,
+        }
+    }
+
+    pub fn snapshot(&self, now: Instant) -> PerpetualSnapshot {
+        PerpetualSnapshot {
+            config: self.config.clone(),
+            index_market: self.index_market.clone(),
+            positions: self.positions.clone(),
+            settlement_pool: self.settlement_pool,
+            next_funding_in: self.next_funding.saturating_duration_since(now),
+        }
+    }
+
+    pub fn restore(snapshot: PerpetualSnapshot, now: Instant) -> Self {
+        Self {
+            config: snapshot.config,
+            index_market: snapshot.index_market,
+            positions: snapshot.positions,
+            settlement_pool: snapshot.settlement_pool,
+            next_funding: now + snapshot.next_funding_in,
+        }
+    }
+
+    pub fn collateral(&self, price: Decimal, quantity: Decimal) -> Decimal {
+        price * quantity * self.config.initial_margin_rate
+    }
+
+    // Applies one fill to a user's position. `collateral` is what the order locked for
+    // this quantity; the part that opens exposure becomes position margin and the rest
+    // is handed back with any released margin and realized PnL. The returned amount
+    // may be negative when a loss exceeds the margin released.
+    pub fn apply_fill(
+        &mut self,
+        user_id: &str,
+        quantity: i64,
+        price: Decimal,
+        collateral: Decimal,
+    ) -> Decimal {
+        let position = self.positions.entry(user_id.to_string()).or_default();
+        let mut credit = Decimal::ZERO;
+        let mut collateral_left = collateral;
+
+        let closing = if position.size.signum() == -quantity.signum() {
+            position.size.abs().min(quantity.abs())
+        } else {
+            0
+        };
+        if closing > 0 {
+            let closing_decimal = Decimal::from(closing);
+            let released = (position.margin * closing_decimal / Decimal::from(position.size.abs()))
+                .round_dp(PRECISION);
+            let pnl = (price - position.entry_price)
+                * closing_decimal
+                * Decimal::from(position.size.signum());
+            let returned =
+                (collateral * closing_decimal / Decimal::from(quantity.abs())).round_dp(PRECISION);
+
+            position.margin -= released;
+            position.size += quantity.signum() * closing;
+            collateral_left -= returned;
+            credit += released + pnl + returned;
+            self.settlement_pool -= pnl;
+        }
+
+        let opening = quantity.abs() - closing;
+        if opening > 0 {
+            let held = Decimal::from(position.size.abs());
+            let added = Decimal::from(opening);
+            position.entry_price = ((position.entry_price * held + price * added) / (held + added))
+                .round_dp(PRECISION);
+            position.size += quantity.signum() * opening;
+            position.margin += collateral_left;
+        }
+
+        if position.size == 0 {
+            // Whatever margin is left over after rounding goes back to the user
+            credit += position.margin;
+            self.positions.remove(user_id);
+        }
+        credit
+    }
+
+    pub fn funding_due(&self, now: Instant) -> bool {
+        now >= self.next_funding
+    }
+
+    // Moves funding between longs and shorts through their position margins. Longs
+    // pay shorts when the mark trades above the index and the reverse below it.
+    // Returns the rate applied, or None when either price is not known yet.
+    pub fn settle_funding(
+        &mut self,
+        now: Instant,
+        mark_price: Decimal,
+        index_price: Decimal,
+    ) -> Option<Decimal> {
+        self.next_funding = now + self.config.funding_interval;
+        if mark_price.is_zero() || index_price.is_zero() {
+            return None;
+        }
+        let premium = ((mark_price - index_price) / index_price).round_dp(PRECISION);
+        let rate = premium
+            .max(-self.config.max_funding_rate)
+            .min(self.config.max_funding_rate);
+        for position in self.positions.values_mut() {
+            position.margin -= Decimal::from(position.size) * mark_price * rate;
+        }
+        Some(rate)
+    }
+}
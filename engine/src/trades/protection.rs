@@ -0,0 +1,121 @@
+use engine::types::{CircuitBreakerConfig, MarketStatus, PriceProtectionPayload};
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use super::snapshot::ProtectionSnapshot;
+
+// Per-market guards against erroneous prices: a band around the reference price that
+// every limit price must fall within, and a circuit breaker that halts the market
+// when trades move too far within a time window.
+#[derive(Default)]
+pub struct PriceProtection {
+    pub band_bps: Option<u64>,
+    pub reference_price: Option<u64>,
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+    recent_trades: VecDeque<(Instant, u64)>,
+    // While the breaker holds the market halted: when it resumes and to which status
+    tripped: Option<(Instant, MarketStatus)>,
+}
+
+impl PriceProtection {
+    pub fn configure(&mut self, config: &PriceProtectionPayload) {
+        self.band_bps = config.band_bps;
+        self.reference_price = config.reference_price;
+        self.circuit_breaker = config.circuit_breaker;
+        self.recent_trades.clear();
+    }
+
+    pub fn config(&self, market: String) -> PriceProtectionPayload {
+        PriceProtectionPayload {
+            market,
+            band_bps: self.band_bps,
+            reference_price: self.reference_price,
+            circuit_breaker: self.circuit_breaker,
+        }
+    }
+
+    // Without a reference price or last trade there is nothing to measure against.
+    pub fn within_band(&self, price: u64, last_price: u64) -> bool {
+        let Some(band_bps) = self.band_bps else {
+            return true;
+        };
+        let reference = self.reference_price.unwrap_or(last_price);
+        if reference == 0 {
+            return true;
+        }
+        price.abs_diff(reference) as u128 * 10_000 <= band_bps as u128 * reference as u128
+    }
+
+    // Records trade prices and reports whether the range traded within the window
+    // now exceeds the allowed move.
+    pub fn record_trades(&mut self, now: Instant, prices: impl IntoIterator<Item = u64>) -> bool {
+        let Some(config) = self.circuit_breaker else {
+            return false;
+        };
+        self.recent_trades
+            .extend(prices.into_iter().map(|price| (now, price)));
+        let window = Duration::from_secs(config.window_secs);
+        while let Some(&(traded_at, _)) = self.recent_trades.front() {
+            if now.duration_since(traded_at) <= window {
+                break;
+            }
+            self.recent_trades.pop_front();
+        }
+
+        let low = self.recent_trades.iter().map(|&(_, p)| p).min();
+        let high = self.recent_trades.iter().map(|&(_, p)| p).max();
+        match (low, high) {
+            (Some(low), Some(high)) if low > 0 => {
+                (high - low) as u128 * 10_000 > config.max_move_bps as u128 * low as u128
+            }
+            _ => false,
+        }
+    }
+
+    pub fn trip(&mut self, now: Instant, resume_status: MarketStatus) {
+        let cooldown = self
+            .circuit_breaker
+            .map_or(0, |config| config.cooldown_secs);
+        self.tripped = Some((now + Duration::from_secs(cooldown), resume_status));
+        self.recent_trades.clear();
+    }
+
+    // A manual status change overrides the breaker.
+    pub fn reset_trip(&mut self) {
+        self.tripped = None;
+    }
+
+    pub fn snapshot(&self, now: Instant) -> ProtectionSnapshot {
+        ProtectionSnapshot {
+            band_bps: self.band_bps,
+            reference_price: self.reference_price,
+            circuit_breaker: self.circuit_breaker,
+            tripped: self
+                .tripped
+                .map(|(resume_at, status)| (resume_at.saturating_duration_since(now), status)),
+        }
+    }
+
+    pub fn restore(snapshot: ProtectionSnapshot, now: Instant) -> Self {
+        Self {
+            band_bps: snapshot.band_bps,
+            reference_price: snapshot.reference_price,
+            circuit_breaker: snapshot.circuit_breaker,
+            recent_trades: VecDeque::new(),
+            tripped: snapshot
+                .tripped
+                .map(|(remaining, status)| (now + remaining, status)),
+        }
+    }
+
+    // Returns the status to resume once the cool-down has elapsed.
+    pub fn take_expired_trip(&mut self, now: Instant) -> Option<MarketStatus> {
+        match self.tripped {
+            Some((resume_at, status)) if resume_at <= now => {
+                self.tripped = None;
+                Some(status)
+            }
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,71 @@
+use engine::types::{RejectReason, RiskLimits};
+use rust_decimal::Decimal;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+// What the engine knows about a user's exposure when a new order arrives.
+pub struct OrderExposure {
+    pub open_orders_in_market: u64,
+    pub order_notional: Decimal,
+    pub open_notional: Decimal,
+}
+
+// Pre-trade limits for one user plus the recent order times the rate limit needs.
+#[derive(Default)]
+pub struct UserRisk {
+    pub limits: RiskLimits,
+    recent_orders: VecDeque<Instant>,
+}
+
+impl UserRisk {
+    pub fn new(limits: RiskLimits) -> Self {
+        Self {
+            limits,
+            recent_orders: VecDeque::new(),
+        }
+    }
+
+    // Orders count against the rate limit once they pass it, even if a later check
+    // rejects them.
+    pub fn check_order(
+        &mut self,
+        now: Instant,
+        exposure: &OrderExposure,
+    ) -> Result<(), RejectReason> {
+        if let Some(max_per_second) = self.limits.max_orders_per_second {
+            while let Some(&placed_at) = self.recent_orders.front() {
+                if now.duration_since(placed_at) < Duration::from_secs(1) {
+                    break;
+                }
+                self.recent_orders.pop_front();
+            }
+            if self.recent_orders.len() as u64 >= max_per_second {
+                return Err(RejectReason::RateLimited);
+            }
+            self.recent_orders.push_back(now);
+        }
+
+        if self
+            .limits
+            .max_open_orders
+            .is_some_and(|max| exposure.open_orders_in_market >= max)
+        {
+            return Err(RejectReason::TooManyOpenOrders);
+        }
+        if self
+            .limits
+            .max_order_notional
+            .is_some_and(|max| exposure.order_notional > max)
+        {
+            return Err(RejectReason::OrderNotionalTooLarge);
+        }
+        if self
+            .limits
+            .max_open_notional
+            .is_some_and(|max| exposure.open_notional + exposure.order_notional > max)
+        {
+            return Err(RejectReason::OpenNotionalTooLarge);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,93 @@
+use std::collections::{BTreeSet, HashMap};
+use std::time::Duration;
+
+use engine::types::{CircuitBreakerConfig, MarginConfig, MarketStatus, Order, RiskLimits};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use super::engine::UserBalance;
+use super::perpetual::{PerpetualConfig, Position};
+use super::ticker::Bucket;
+use crate::journal::JournalId;
+
+// Everything the engine needs to carry on where it stopped. Deadlines are kept as
+// the time left when the snapshot was taken, and short rolling windows (order rate
+// limits, circuit breaker trades) start out empty again.
+#[derive(Serialize, Deserialize)]
+pub struct EngineSnapshot {
+    // Last journal entry reflected in the snapshot; replay resumes after it
+    pub journal_id: Option<JournalId>,
+    // Engine time in Unix milliseconds
+    pub taken_at: i64,
+    pub balances: HashMap<String, UserBalance>,
+    pub asset_supply: HashMap<String, Decimal>,
+    pub risk_limits: HashMap<String, RiskLimits>,
+    pub margin: MarginSnapshot,
+    pub sub_accounts: HashMap<String, BTreeSet<String>>,
+    pub markets: Vec<MarketSnapshot>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct MarginSnapshot {
+    pub config: MarginConfig,
+    pub enabled_users: Vec<String>,
+    pub interest_charged: HashMap<String, Decimal>,
+    // Time since interest was last accrued
+    pub since_accrual: Duration,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct MarketSnapshot {
+    pub base_asset: String,
+    pub quote_asset: String,
+    pub status: MarketStatus,
+    pub protection: ProtectionSnapshot,
+    pub perpetual: Option<PerpetualSnapshot>,
+    pub ticker: TickerSnapshot,
+    pub depth_update_id: u64,
+    pub l3: L3Snapshot,
+    pub last_trade_id: u64,
+    pub current_price: u64,
+    // Resting orders in price-time priority
+    pub bids: Vec<RestingOrder>,
+    pub asks: Vec<RestingOrder>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RestingOrder {
+    pub order: Order,
+    pub public_id: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ProtectionSnapshot {
+    pub band_bps: Option<u64>,
+    pub reference_price: Option<u64>,
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+    // Time left on a tripped breaker and the status it resumes to
+    pub tripped: Option<(Duration, MarketStatus)>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PerpetualSnapshot {
+    pub config: PerpetualConfig,
+    pub index_market: String,
+    pub positions: HashMap<String, Position>,
+    pub settlement_pool: Decimal,
+    pub next_funding_in: Duration,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TickerSnapshot {
+    // Index of the bucket that was current when the snapshot was taken, and how
+    // far into it the snapshot was
+    pub current_bucket: u64,
+    pub into_bucket: Duration,
+    pub buckets: Vec<Bucket>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct L3Snapshot {
+    pub next_order_id: u64,
+    pub update_id: u64,
+}
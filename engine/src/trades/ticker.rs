@@ -0,0 +1,137 @@
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use super::snapshot::TickerSnapshot;
+
+const BUCKET: Duration = Duration::from_secs(60);
+const WINDOW_BUCKETS: u64 = 24 * 60;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Bucket {
+    index: u64,
+    open: u64,
+    high: u64,
+    low: u64,
+    close: u64,
+    volume: Decimal,
+    quote_volume: Decimal,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct TickerSummary {
+    pub open: u64,
+    pub high: u64,
+    pub low: u64,
+    pub close: u64,
+    pub volume: Decimal,
+    pub quote_volume: Decimal,
+}
+
+// Rolling 24h statistics of a market, kept in one-minute buckets so the window
+// slides without storing every trade.
+pub struct TickerStats {
+    started: Instant,
+    // Bucket `started` falls in and how far into it, both non-zero once restored
+    // from a snapshot
+    first_bucket: u64,
+    offset: Duration,
+    buckets: VecDeque<Bucket>,
+}
+
+impl TickerStats {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            started: now,
+            first_bucket: 0,
+            offset: Duration::ZERO,
+            buckets: VecDeque::new(),
+        }
+    }
+
+    // Time since the start of `first_bucket`
+    fn elapsed(&self, now: Instant) -> Duration {
+        now.saturating_duration_since(self.started) + self.offset
+    }
+
+    fn bucket_index(&self, now: Instant) -> u64 {
+        self.first_bucket + self.elapsed(now).as_secs() / BUCKET.as_secs()
+    }
+
+    pub fn snapshot(&self, now: Instant) -> TickerSnapshot {
+        let into_bucket = self.elapsed(now).as_nanos() % BUCKET.as_nanos();
+        TickerSnapshot {
+            current_bucket: self.bucket_index(now),
+            into_bucket: Duration::from_nanos(into_bucket as u64),
+            buckets: self.buckets.iter().cloned().collect(),
+        }
+    }
+
+    // Buckets roll over at the same moments as they would have without the restart.
+    pub fn restore(snapshot: TickerSnapshot, now: Instant) -> Self {
+        Self {
+            started: now,
+            first_bucket: snapshot.current_bucket,
+            offset: snapshot.into_bucket,
+            buckets: snapshot.buckets.into(),
+        }
+    }
+
+    pub fn record_trade(&mut self, now: Instant, price: u64, quantity: u64) {
+        let index = self.bucket_index(now);
+        let volume = Decimal::from(quantity);
+        let quote_volume = Decimal::from(price) * volume;
+        match self.buckets.back_mut() {
+            Some(bucket) if bucket.index == index => {
+                bucket.high = bucket.high.max(price);
+                bucket.low = bucket.low.min(price);
+                bucket.close = price;
+                bucket.volume += volume;
+                bucket.quote_volume += quote_volume;
+            }
+            _ => self.buckets.push_back(Bucket {
+                index,
+                open: price,
+                high: price,
+                low: price,
+                close: price,
+                volume,
+                quote_volume,
+            }),
+        }
+        while self
+            .buckets
+            .front()
+            .is_some_and(|bucket| bucket.index + WINDOW_BUCKETS <= index)
+        {
+            self.buckets.pop_front();
+        }
+    }
+
+    // None when the market has not traded in the last 24 hours.
+    pub fn summary(&self, now: Instant) -> Option<TickerSummary> {
+        let index = self.bucket_index(now);
+        let mut buckets = self
+            .buckets
+            .iter()
+            .filter(|bucket| bucket.index + WINDOW_BUCKETS > index);
+        let first = buckets.next()?;
+        let mut summary = TickerSummary {
+            open: first.open,
+            high: first.high,
+            low: first.low,
+            close: first.close,
+            volume: first.volume,
+            quote_volume: first.quote_volume,
+        };
+        for bucket in buckets {
+            summary.high = summary.high.max(bucket.high);
+            summary.low = summary.low.min(bucket.low);
+            summary.close = bucket.close;
+            summary.volume += bucket.volume;
+            summary.quote_volume += bucket.quote_volume;
+        }
+        Some(summary)
+    }
+}
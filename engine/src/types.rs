@@ -7,6 +7,10 @@ use std::str::FromStr;
 pub enum DbMessageType {
     TradeAdded,
     OrderUpdate,
+    OrderCancelled,
+    RiskLimitsUpdated,
+    TransferCompleted,
+    BalanceAdjusted,
 }
 
 //Message to DB
@@ -21,6 +25,10 @@ pub struct DbMessage {
 pub enum DbMessageData {
     TradeAdd(TradeAdd),
     OrderUpdate(OrderUpdate),
+    OrderCancelled(OrderCancelled),
+    RiskLimits(RiskLimitsPayload),
+    Transfer(TransferRecord),
+    BalanceAdjustment(BalanceAdjustment),
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -44,6 +52,15 @@ pub struct OrderUpdate {
     pub side: Option<Side>,
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+pub struct OrderCancelled {
+    pub order_id: String,
+    pub market: String,
+    pub executed_quantity: u64,
+    pub remaining_quantity: u64,
+    pub timestamp: String,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Order {
     pub price: u64,
@@ -62,13 +79,72 @@ pub enum MessageToApi {
     Depth(DepthPayload),
     OrderPlaced(OrderPlacedPayload),
     OrderCancelled(OrderCancelledPayload),
+    OrdersCancelled(Vec<OrderCancelledPayload>),
     OpenOrders(Vec<Order>),
+    MarketStatus(MarketStatusPayload),
+    Markets(Vec<MarketPayload>),
+    OrderRejected(OrderRejectedPayload),
+    PriceProtection(PriceProtectionPayload),
+    RiskLimits(RiskLimitsPayload),
+    MarginConfig(MarginConfig),
+    MarginAccount(MarginAccountPayload),
+    Positions(Vec<PositionPayload>),
+    Tickers(Vec<TickerUpdateMessage>),
+    L3Snapshot(L3SnapshotPayload),
+    SubAccounts(SubAccountsPayload),
+    TransferCompleted(TransferRecord),
+    TransferRejected(TransferRejectedPayload),
+    UserState(UserStatePayload),
+    BookTop(BookTopPayload),
+    BalanceAdjusted(BalanceAdjustment),
+    AdminRejected(AdminRejectedPayload),
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct DepthPayload {
     pub bids: Vec<[String; 2]>,
     pub asks: Vec<[String; 2]>,
+    // Id of the last depth update already reflected in this snapshot
+    pub last_update_id: u64,
+    pub checksum: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct L3SnapshotPayload {
+    pub market: String,
+    // Resting orders best price first, in queue order within a price
+    pub bids: Vec<L3Order>,
+    pub asks: Vec<L3Order>,
+    // Id of the last L3 event already reflected in this snapshot
+    pub last_update_id: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct L3Order {
+    pub id: u64,
+    pub price: u64,
+    pub quantity: u64,
+}
+
+// Order-level book changes. Ids are anonymous and only valid on the L3 feed; an
+// order whose executions use up its quantity leaves the book.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum L3Event {
+    // Joins the back of the queue at its price
+    Add {
+        id: u64,
+        side: Side,
+        price: u64,
+        quantity: u64,
+    },
+    Execute {
+        id: u64,
+        quantity: u64,
+    },
+    Cancel {
+        id: u64,
+    },
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -85,6 +161,130 @@ pub struct OrderCancelledPayload {
     pub remaining_qty: u64,
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MarketStatusPayload {
+    pub market: String,
+    pub status: MarketStatus,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MarketPayload {
+    pub market: String,
+    pub base_asset: String,
+    pub quote_asset: String,
+    pub kind: MarketKind,
+    pub status: MarketStatus,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum MarketKind {
+    Spot,
+    Perpetual,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PositionPayload {
+    pub market: String,
+    // Positive for longs, negative for shorts
+    pub size: i64,
+    pub entry_price: Decimal,
+    pub margin: Decimal,
+    pub mark_price: Decimal,
+    pub unrealized_pnl: Decimal,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct OrderRejectedPayload {
+    pub reason: RejectReason,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SubAccountsPayload {
+    pub user_id: String,
+    // Full account ids, `<user_id>:<name>`
+    pub sub_accounts: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TransferRecord {
+    pub id: String,
+    pub from_account: String,
+    pub to_account: String,
+    pub asset: String,
+    pub amount: Decimal,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TransferRejectedPayload {
+    pub reason: RejectReason,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UserStatePayload {
+    pub user_id: String,
+    pub balances: Vec<MarginBalance>,
+    pub open_orders: Vec<MarketOrders>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MarketOrders {
+    pub market: String,
+    pub orders: Vec<Order>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BookTopPayload {
+    pub market: String,
+    pub status: MarketStatus,
+    pub last_price: u64,
+    // Resting orders of the best levels, best price first and in queue order
+    pub bids: Vec<Order>,
+    pub asks: Vec<Order>,
+}
+
+// A manual credit (positive amount) or debit (negative amount) made by an operator
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BalanceAdjustment {
+    pub id: String,
+    pub user_id: String,
+    pub asset: String,
+    pub amount: Decimal,
+    pub reason: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AdminRejectedPayload {
+    pub reason: RejectReason,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum RejectReason {
+    MarketNotFound,
+    InsufficientFunds,
+    // The market's status does not accept this kind of request
+    MarketHalted,
+    MarketCancelOnly,
+    MarketDelisted,
+    // A post-only market refuses orders that would take liquidity
+    WouldTakeLiquidity,
+    // The limit price is too far from the market's reference price
+    PriceOutsideBand,
+    // Per-user risk limits
+    TooManyOpenOrders,
+    OrderNotionalTooLarge,
+    OpenNotionalTooLarge,
+    RateLimited,
+    // The sub-account has not been created
+    AccountNotFound,
+    // Transfers out of an account that owes margin debt are not allowed
+    OutstandingDebt,
+    // Admin requests
+    OrderNotFound,
+    AssetNotListed,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Fill {
     pub price: String,
@@ -92,6 +292,66 @@ pub struct Fill {
     pub trade_id: u64,
 }
 
+// Requests on the engine queues are JSON arrays of the reply channel, the message
+// and the correlation id of the request that caused them. Senders may leave the
+// correlation id out, in which case the reply channel stands in for it.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct EngineRequest<T> {
+    pub client_id: String,
+    pub message: T,
+    #[serde(default)]
+    pub correlation_id: Option<String>,
+}
+
+// An engine output together with the correlation id of the request that caused
+// it. The id is written next to the message's own fields.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Traced<T> {
+    #[serde(flatten)]
+    pub message: T,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub correlation_id: Option<String>,
+}
+
+// Operator commands. They are read from the `admin_messages` queue, which only
+// exchange-admin writes to, so the API has no way to send them.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", content = "data")]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum AdminCommand {
+    ListMarkets,
+    GetUser(GetUserPayload),
+    GetBook(GetBookPayload),
+    ForceCancel(CancelOrderPayload),
+    AdjustBalance(AdjustBalancePayload),
+    SetMarketStatus(SetMarketStatusPayload),
+    SetPriceProtection(PriceProtectionPayload),
+    SetRiskLimits(RiskLimitsPayload),
+    SetMarginConfig(MarginConfig),
+    SetMarginEnabled(MarginEnabledPayload),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GetUserPayload {
+    pub user_id: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GetBookPayload {
+    pub market: String,
+    // Price levels per side
+    pub levels: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AdjustBalancePayload {
+    pub user_id: String,
+    pub asset: String,
+    // Signed; negative amounts debit the available balance
+    pub amount: String,
+    pub reason: String,
+}
+
 //Recieve from Api
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(tag = "type", content = "data")]
@@ -99,9 +359,20 @@ pub struct Fill {
 pub enum MessageFromApi {
     CreateOrder(CreateOrderPayload),
     CancelOrder(CancelOrderPayload),
+    CancelAllOrders(CancelAllOrdersPayload),
     GetDepth(GetDepthPayload),
+    GetL3Snapshot(GetL3SnapshotPayload),
     GetOpenOrders(GetOpenOrdersPayload),
     OnRamp(OnRampPayload),
+    GetMarkets,
+    GetTickers,
+    GetRiskLimits(GetRiskLimitsPayload),
+    RepayMargin(RepayMarginPayload),
+    GetMarginAccount(GetMarginAccountPayload),
+    GetPositions(GetPositionsPayload),
+    CreateSubAccount(SubAccountPayload),
+    GetSubAccounts(GetSubAccountsPayload),
+    Transfer(TransferPayload),
 }
 #[derive(Serialize, Deserialize, Debug)]
 pub struct CreateOrderPayload {
@@ -118,9 +389,24 @@ pub struct CancelOrderPayload {
     pub market: String,
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CancelAllOrdersPayload {
+    pub market: String,
+    pub user_id: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GetL3SnapshotPayload {
+    pub market: String,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct GetDepthPayload {
     pub market: String,
+    // Levels per side, the whole book when absent
+    pub limit: Option<usize>,
+    // Bucket size in ticks that levels are grouped into, 1 when absent
+    pub precision: Option<u64>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -131,11 +417,182 @@ pub struct GetOpenOrdersPayload {
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct OnRampPayload {
+    pub asset: String,
     pub amount: String,
     pub user_id: String,
     pub txn_id: String,
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SetMarketStatusPayload {
+    pub market: String,
+    pub status: MarketStatus,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RiskLimitsPayload {
+    pub user_id: String,
+    pub limits: RiskLimits,
+}
+
+// Leaving a field out disables that limit. Notional is measured in the quote asset.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct RiskLimits {
+    // Resting orders per market
+    pub max_open_orders: Option<u64>,
+    pub max_order_notional: Option<Decimal>,
+    // Across every market, including the order being placed
+    pub max_open_notional: Option<Decimal>,
+    pub max_orders_per_second: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GetRiskLimitsPayload {
+    pub user_id: String,
+}
+
+// Exchange-wide margin settings. A leverage of 1 disables borrowing.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MarginConfig {
+    // Largest ratio of account assets to equity a user may reach by borrowing
+    pub max_leverage: Decimal,
+    // Accounts whose assets fall below this multiple of their liabilities are liquidated
+    pub liquidation_margin_level: Decimal,
+    // Charged on borrowed amounts, as a fraction of the debt per hour
+    pub hourly_interest_rate: Decimal,
+}
+
+impl Default for MarginConfig {
+    fn default() -> Self {
+        Self {
+            max_leverage: Decimal::ONE,
+            liquidation_margin_level: Decimal::new(11, 1),
+            hourly_interest_rate: Decimal::ZERO,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MarginEnabledPayload {
+    pub user_id: String,
+    pub enabled: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RepayMarginPayload {
+    pub user_id: String,
+    pub asset: String,
+    pub amount: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GetPositionsPayload {
+    pub user_id: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SubAccountPayload {
+    pub user_id: String,
+    pub name: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GetSubAccountsPayload {
+    pub user_id: String,
+}
+
+// Accounts are either a user id or a sub-account id of the form `<user_id>:<name>`
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TransferPayload {
+    pub from_account: String,
+    pub to_account: String,
+    pub asset: String,
+    pub amount: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GetMarginAccountPayload {
+    pub user_id: String,
+}
+
+// Values are in the valuation currency at last traded prices
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MarginAccountPayload {
+    pub user_id: String,
+    pub enabled: bool,
+    pub assets_value: Decimal,
+    pub liabilities_value: Decimal,
+    // Assets over liabilities; absent while nothing is borrowed
+    pub margin_level: Option<Decimal>,
+    pub balances: Vec<MarginBalance>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MarginBalance {
+    pub asset: String,
+    pub available: Decimal,
+    pub locked: Decimal,
+    pub borrowed: Decimal,
+    pub interest: Decimal,
+}
+
+// Leaving a field out disables that protection
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PriceProtectionPayload {
+    pub market: String,
+    // Maximum distance of a limit price from the reference price, in basis points
+    pub band_bps: Option<u64>,
+    // Fixed reference price for the band; the last traded price is used when absent
+    pub reference_price: Option<u64>,
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct CircuitBreakerConfig {
+    // Largest move between the highest and lowest trade in the window, in basis points
+    pub max_move_bps: u64,
+    pub window_secs: u64,
+    // How long the market stays halted once the breaker trips
+    pub cooldown_secs: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum MarketStatus {
+    // Continuous matching
+    Trading,
+    // Orders rest without matching until the auction is uncrossed
+    Auction,
+    // No new orders and no cancels
+    Halted,
+    // Cancels only
+    CancelOnly,
+    // New orders are accepted only if they rest on the book without matching
+    PostOnly,
+    // All resting orders are cancelled and the market accepts nothing further
+    Delisted,
+}
+
+impl MarketStatus {
+    // Checks whether a new order may enter a market in this status.
+    pub fn accepts_orders(&self) -> Result<(), RejectReason> {
+        match self {
+            MarketStatus::Trading | MarketStatus::Auction | MarketStatus::PostOnly => Ok(()),
+            MarketStatus::Halted => Err(RejectReason::MarketHalted),
+            MarketStatus::CancelOnly => Err(RejectReason::MarketCancelOnly),
+            MarketStatus::Delisted => Err(RejectReason::MarketDelisted),
+        }
+    }
+
+    pub fn accepts_cancels(&self) -> Result<(), RejectReason> {
+        match self {
+            MarketStatus::Halted => Err(RejectReason::MarketHalted),
+            MarketStatus::Delisted => Err(RejectReason::MarketDelisted),
+            _ => Ok(()),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(rename_all = "lowercase")]
 pub enum Side {
@@ -164,10 +621,17 @@ pub enum WsPayload {
     Ticker(TickerUpdateMessage),
     Depth(DepthUpdateMessage),
     Trade(TradeUpdateMessage),
+    Auction(AuctionUpdateMessage),
+    Status(StatusUpdateMessage),
+    Funding(FundingUpdateMessage),
+    L3(L3UpdateMessage),
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[allow(non_snake_case)]
 pub struct TickerUpdateMessage {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub o: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub c: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -184,11 +648,55 @@ pub struct TickerUpdateMessage {
     pub e: String,
 }
 
+// `U` and `u` are the first and last depth update ids covered by the diff and `c`
+// the book checksum once it is applied
 #[derive(Serialize, Deserialize, Clone)]
+#[allow(non_snake_case)]
 pub struct DepthUpdateMessage {
     pub b: Vec<[String; 2]>,
     pub a: Vec<[String; 2]>,
     pub e: String,
+    pub U: u64,
+    pub u: u64,
+    pub c: u32,
+}
+
+// `U` and `u` are the update ids of the first and last event
+#[derive(Serialize, Deserialize, Clone)]
+#[allow(non_snake_case)]
+pub struct L3UpdateMessage {
+    pub e: String,
+    pub s: String,
+    pub U: u64,
+    pub u: u64,
+    pub events: Vec<L3Event>,
+}
+
+// Indicative uncrossing price and volume while a market is in auction
+#[derive(Serialize, Deserialize, Clone)]
+pub struct AuctionUpdateMessage {
+    pub e: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub p: Option<String>,
+    pub q: String,
+    pub s: String,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct StatusUpdateMessage {
+    pub e: String,
+    pub s: String,
+    pub status: MarketStatus,
+}
+
+// Funding rate applied to a perpetual market with the mark and index prices used
+#[derive(Serialize, Deserialize, Clone)]
+pub struct FundingUpdateMessage {
+    pub e: String,
+    pub s: String,
+    pub r: String,
+    pub p: String,
+    pub i: String,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -205,9 +713,29 @@ pub struct TradeUpdateMessage {
 pub enum InternalMessage {
     CreateOrder(InternalCreateOrderPayload),
     CancelOrder(CancelOrderPayload),
+    CancelAllOrders(CancelAllOrdersPayload),
     GetDepth(GetDepthPayload),
+    GetL3Snapshot(GetL3SnapshotPayload),
     GetOpenOrders(GetOpenOrdersPayload),
     OnRamp(InternalOnRampPayload),
+    SetMarketStatus(SetMarketStatusPayload),
+    GetMarkets,
+    GetTickers,
+    SetPriceProtection(PriceProtectionPayload),
+    SetRiskLimits(RiskLimitsPayload),
+    GetRiskLimits(GetRiskLimitsPayload),
+    SetMarginConfig(MarginConfig),
+    SetMarginEnabled(MarginEnabledPayload),
+    RepayMargin(InternalRepayMarginPayload),
+    GetMarginAccount(GetMarginAccountPayload),
+    GetPositions(GetPositionsPayload),
+    CreateSubAccount(SubAccountPayload),
+    GetSubAccounts(GetSubAccountsPayload),
+    Transfer(InternalTransferPayload),
+    GetUserState(GetUserPayload),
+    GetBookTop(GetBookPayload),
+    ForceCancel(CancelOrderPayload),
+    AdjustBalance(InternalAdjustBalancePayload),
 }
 
 #[derive(Debug)]
@@ -221,8 +749,32 @@ pub struct InternalCreateOrderPayload {
     pub user_id: String,
 }
 
+#[derive(Debug)]
+pub struct InternalRepayMarginPayload {
+    pub user_id: String,
+    pub asset: String,
+    pub amount: Decimal,
+}
+
+#[derive(Debug)]
+pub struct InternalTransferPayload {
+    pub from_account: String,
+    pub to_account: String,
+    pub asset: String,
+    pub amount: Decimal,
+}
+
+#[derive(Debug)]
+pub struct InternalAdjustBalancePayload {
+    pub user_id: String,
+    pub asset: String,
+    pub amount: Decimal,
+    pub reason: String,
+}
+
 #[derive(Debug)]
 pub struct InternalOnRampPayload {
+    pub asset: String,
     pub amount: Decimal,
     pub user_id: String,
     pub txn_id: String,
@@ -292,13 +844,59 @@ impl InternalMessage {
                 }))
             }
             MessageFromApi::CancelOrder(payload) => Ok(InternalMessage::CancelOrder(payload)),
+            MessageFromApi::CancelAllOrders(payload) => {
+                Ok(InternalMessage::CancelAllOrders(payload))
+            }
             MessageFromApi::GetDepth(payload) => Ok(InternalMessage::GetDepth(payload)),
+            MessageFromApi::GetL3Snapshot(payload) => Ok(InternalMessage::GetL3Snapshot(payload)),
             MessageFromApi::GetOpenOrders(payload) => Ok(InternalMessage::GetOpenOrders(payload)),
+            MessageFromApi::GetMarkets => Ok(InternalMessage::GetMarkets),
+            MessageFromApi::GetTickers => Ok(InternalMessage::GetTickers),
+            MessageFromApi::GetRiskLimits(payload) => Ok(InternalMessage::GetRiskLimits(payload)),
+            MessageFromApi::RepayMargin(payload) => {
+                let amount = Decimal::from_str(&payload.amount)
+                    .map_err(|_| format!("Invalid amount format: {}", payload.amount))?;
+                if amount <= Decimal::ZERO {
+                    return Err(format!("Repay amount must be positive: {}", payload.amount));
+                }
+
+                Ok(InternalMessage::RepayMargin(InternalRepayMarginPayload {
+                    user_id: payload.user_id,
+                    asset: payload.asset,
+                    amount,
+                }))
+            }
+            MessageFromApi::GetMarginAccount(payload) => {
+                Ok(InternalMessage::GetMarginAccount(payload))
+            }
+            MessageFromApi::GetPositions(payload) => Ok(InternalMessage::GetPositions(payload)),
+            MessageFromApi::CreateSubAccount(payload) => {
+                Ok(InternalMessage::CreateSubAccount(payload))
+            }
+            MessageFromApi::GetSubAccounts(payload) => Ok(InternalMessage::GetSubAccounts(payload)),
+            MessageFromApi::Transfer(payload) => {
+                let amount = Decimal::from_str(&payload.amount)
+                    .map_err(|_| format!("Invalid amount format: {}", payload.amount))?;
+                if amount <= Decimal::ZERO {
+                    return Err(format!(
+                        "Transfer amount must be positive: {}",
+                        payload.amount
+                    ));
+                }
+
+                Ok(InternalMessage::Transfer(InternalTransferPayload {
+                    from_account: payload.from_account,
+                    to_account: payload.to_account,
+                    asset: payload.asset,
+                    amount,
+                }))
+            }
             MessageFromApi::OnRamp(payload) => {
                 let amount = Decimal::from_str(&payload.amount)
                     .map_err(|_| format!("Invalid amount format: {}", payload.amount))?;
 
                 Ok(InternalMessage::OnRamp(InternalOnRampPayload {
+                    asset: payload.asset,
                     amount,
                     user_id: payload.user_id,
                     txn_id: payload.txn_id,
@@ -306,4 +904,72 @@ impl InternalMessage {
             }
         }
     }
+
+    // Label for the message in metrics
+    pub fn kind(&self) -> &'static str {
+        match self {
+            InternalMessage::CreateOrder(_) => "create_order",
+            InternalMessage::CancelOrder(_) => "cancel_order",
+            InternalMessage::CancelAllOrders(_) => "cancel_all_orders",
+            InternalMessage::GetDepth(_) => "get_depth",
+            InternalMessage::GetL3Snapshot(_) => "get_l3_snapshot",
+            InternalMessage::GetOpenOrders(_) => "get_open_orders",
+            InternalMessage::OnRamp(_) => "on_ramp",
+            InternalMessage::SetMarketStatus(_) => "set_market_status",
+            InternalMessage::GetMarkets => "get_markets",
+            InternalMessage::GetTickers => "get_tickers",
+            InternalMessage::SetPriceProtection(_) => "set_price_protection",
+            InternalMessage::SetRiskLimits(_) => "set_risk_limits",
+            InternalMessage::GetRiskLimits(_) => "get_risk_limits",
+            InternalMessage::SetMarginConfig(_) => "set_margin_config",
+            InternalMessage::SetMarginEnabled(_) => "set_margin_enabled",
+            InternalMessage::RepayMargin(_) => "repay_margin",
+            InternalMessage::GetMarginAccount(_) => "get_margin_account",
+            InternalMessage::GetPositions(_) => "get_positions",
+            InternalMessage::CreateSubAccount(_) => "create_sub_account",
+            InternalMessage::GetSubAccounts(_) => "get_sub_accounts",
+            InternalMessage::Transfer(_) => "transfer",
+            InternalMessage::GetUserState(_) => "get_user_state",
+            InternalMessage::GetBookTop(_) => "get_book_top",
+            InternalMessage::ForceCancel(_) => "force_cancel",
+            InternalMessage::AdjustBalance(_) => "adjust_balance",
+        }
+    }
+
+    pub fn from_admin_command(command: AdminCommand) -> Result<Self, String> {
+        match command {
+            AdminCommand::ListMarkets => Ok(InternalMessage::GetMarkets),
+            AdminCommand::GetUser(payload) => Ok(InternalMessage::GetUserState(payload)),
+            AdminCommand::GetBook(payload) => Ok(InternalMessage::GetBookTop(payload)),
+            AdminCommand::ForceCancel(payload) => Ok(InternalMessage::ForceCancel(payload)),
+            AdminCommand::AdjustBalance(payload) => {
+                let amount = Decimal::from_str(&payload.amount)
+                    .map_err(|_| format!("Invalid amount format: {}", payload.amount))?;
+                if amount.is_zero() {
+                    return Err("Balance adjustment amount must not be zero".to_string());
+                }
+                if payload.reason.trim().is_empty() {
+                    return Err("Balance adjustments need a reason".to_string());
+                }
+
+                Ok(InternalMessage::AdjustBalance(
+                    InternalAdjustBalancePayload {
+                        user_id: payload.user_id,
+                        asset: payload.asset,
+                        amount,
+                        reason: payload.reason,
+                    },
+                ))
+            }
+            AdminCommand::SetMarketStatus(payload) => Ok(InternalMessage::SetMarketStatus(payload)),
+            AdminCommand::SetPriceProtection(payload) => {
+                Ok(InternalMessage::SetPriceProtection(payload))
+            }
+            AdminCommand::SetRiskLimits(payload) => Ok(InternalMessage::SetRiskLimits(payload)),
+            AdminCommand::SetMarginConfig(config) => Ok(InternalMessage::SetMarginConfig(config)),
+            AdminCommand::SetMarginEnabled(payload) => {
+                Ok(InternalMessage::SetMarginEnabled(payload))
+            }
+        }
+    }
 }
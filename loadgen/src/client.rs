@@ -0,0 +1,176 @@
+use engine::streams::{self, API_STREAM};
+use redis::{aio::Connection, Client};
+use std::error::Error;
+use std::time::Duration;
+
+use crate::types::{MessageFromEngine, MessageToEngine};
+
+pub type ClientError = Box<dyn Error + Send + Sync>;
+
+pub enum RequestError {
+    Timeout,
+    Failed(ClientError),
+}
+
+// One client per simulated trader. The Redis variant talks to the engine exactly
+// like the API does, the REST variant goes through the API itself.
+pub enum ExchangeClient {
+    Redis(Box<RedisClient>),
+    Rest(RestClient),
+}
+
+impl ExchangeClient {
+    pub async fn request(
+        &mut self,
+        message: MessageToEngine,
+        timeout: Duration,
+    ) -> Result<MessageFromEngine, RequestError> {
+        match self {
+            ExchangeClient::Redis(client) => match client.send_and_await(message, timeout).await {
+                Ok(Some(response)) => Ok(response),
+                Ok(None) => {
+                    // The engine may still answer the abandoned request, so move to
+                    // a fresh reply stream rather than mistake that answer for the next one.
+                    client.rotate_reply_stream();
+                    Err(RequestError::Timeout)
+                }
+                Err(e) => Err(RequestError::Failed(e)),
+            },
+            ExchangeClient::Rest(client) => tokio::time::timeout(timeout, client.send(message))
+                .await
+                .map_err(|_| RequestError::Timeout)?
+                .map_err(RequestError::Failed),
+        }
+    }
+}
+
+pub struct RedisClient {
+    client_id: String,
+    connection: Connection,
+}
+
+impl RedisClient {
+    pub async fn connect(redis_url: &str) -> Result<Self, ClientError> {
+        let client = Client::open(redis_url)?;
+        let connection = client.get_async_connection().await?;
+
+        // A trader only ever has one request in flight, so a single reply stream
+        // can be reused for its whole lifetime.
+        Ok(Self {
+            client_id: get_random_client_id(),
+            connection,
+        })
+    }
+
+    fn rotate_reply_stream(&mut self) {
+        self.client_id = get_random_client_id();
+    }
+
+    // Queues a message for the engine without waiting for a reply.
+    pub async fn push(&mut self, message: MessageToEngine) -> Result<(), ClientError> {
+        let payload = request_payload(&self.client_id, &message)?;
+        streams::push(&mut self.connection, API_STREAM, &payload).await?;
+        Ok(())
+    }
+
+    // Returns None when no reply arrived within `timeout`. The wait happens in Redis
+    // rather than by abandoning the read, which would leave the connection out of step.
+    async fn send_and_await(
+        &mut self,
+        message: MessageToEngine,
+        timeout: Duration,
+    ) -> Result<Option<MessageFromEngine>, ClientError> {
+        self.push(message).await?;
+
+        let reply = streams::take_reply(&mut self.connection, &self.client_id, timeout).await?;
+        Ok(reply
+            .map(|response| serde_json::from_str(&response))
+            .transpose()?)
+    }
+}
+
+pub struct RestClient {
+    base_url: String,
+    http: reqwest::Client,
+}
+
+impl RestClient {
+    pub fn new(base_url: &str) -> Self {
+        Self {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            http: reqwest::Client::new(),
+        }
+    }
+
+    async fn send(&self, message: MessageToEngine) -> Result<MessageFromEngine, ClientError> {
+        let request = match &message {
+            MessageToEngine::CreateOrder(payload) => self
+                .http
+                .post(format!("{}/order", self.base_url))
+                .json(payload),
+            MessageToEngine::CancelOrder(payload) => self
+                .http
+                .delete(format!("{}/order", self.base_url))
+                .json(payload),
+            MessageToEngine::CancelAllOrders(payload) => self
+                .http
+                .delete(format!("{}/order/all", self.base_url))
+                .json(payload),
+            MessageToEngine::GetOpenOrders(payload) => self
+                .http
+                .get(format!("{}/order/open", self.base_url))
+                .query(&[("market", &payload.market), ("user_id", &payload.user_id)]),
+            MessageToEngine::GetDepth(payload) => self
+                .http
+                .get(format!("{}/depth", self.base_url))
+                .query(&[("symbol", &payload.market)]),
+            _ => return Err("message is not exposed by the REST API".into()),
+        };
+
+        let response = request.send().await?.error_for_status()?;
+        Ok(response.json().await?)
+    }
+}
+
+// Same shape as the API's requests: the reply channel and the message, with the
+// reply channel standing in for the correlation id.
+fn request_payload(client_id: &str, message: &MessageToEngine) -> serde_json::Result<String> {
+    serde_json::to_string(&(client_id, message))
+}
+
+fn get_random_client_id() -> String {
+    use rand::Rng;
+    let mut rng = rand::thread_rng();
+    format!("loadgen-{:x}{:x}", rng.gen::<u64>(), rng.gen::<u64>())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{CancelOrderRequest, MessageToEngine};
+    use engine::types::EngineRequest;
+
+    #[test]
+    fn requests_parse_as_engine_requests() {
+        let message = MessageToEngine::CancelOrder(CancelOrderRequest {
+            order_id: "o1".to_string(),
+            market: "TATA_INR".to_string(),
+        });
+        let payload = request_payload("loadgen-1", &message).unwrap();
+
+        let request: EngineRequest<MessageToEngine> = serde_json::from_str(&payload).unwrap();
+        assert_eq!(request.client_id, "loadgen-1");
+        assert!(request.correlation_id.is_none());
+        let MessageToEngine::CancelOrder(cancel) = request.message else {
+            panic!("expected a cancel, got {:?}", request.message);
+        };
+        assert_eq!(cancel.order_id, "o1");
+    }
+
+    #[test]
+    fn client_ids_are_unique_reply_streams() {
+        let (a, b) = (get_random_client_id(), get_random_client_id());
+        assert_ne!(a, b);
+        assert_ne!(streams::reply_stream(&a), streams::reply_stream(&b));
+    }
+}
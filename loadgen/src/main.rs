@@ -0,0 +1,159 @@
+mod client;
+mod stats;
+mod trader;
+mod types;
+
+use clap::{Parser, ValueEnum};
+use client::{ExchangeClient, RedisClient, RestClient};
+use stats::Stats;
+use std::time::Duration;
+use tokio::time::Instant;
+use trader::{Trader, TraderConfig};
+use types::{MessageToEngine, OnRampRequest, TraderRole};
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Target {
+    // Push straight onto the engine's `messages` queue, bypassing the API
+    Redis,
+    // Go through the REST API
+    Rest,
+}
+
+/// Drives simulated traders against the exchange and reports throughput and
+/// end-to-end latency percentiles.
+#[derive(Parser)]
+struct Args {
+    #[arg(long, value_enum, default_value = "redis")]
+    target: Target,
+    #[arg(long, env = "REDIS_URL", default_value = "redis://127.0.0.1:6379")]
+    redis_url: String,
+    #[arg(long, default_value = "http://127.0.0.1:8000/api/v1")]
+    api_url: String,
+    #[arg(long, default_value = "TATA_INR")]
+    market: String,
+    /// Comma separated user ids the traders are spread across
+    #[arg(long, default_value = "1", value_delimiter = ',')]
+    users: Vec<String>,
+    /// Amount of quote currency credited to every user before the run
+    #[arg(long, default_value_t = 0)]
+    on_ramp: u64,
+    #[arg(long, default_value_t = 2)]
+    market_makers: usize,
+    #[arg(long, default_value_t = 4)]
+    takers: usize,
+    #[arg(long, default_value_t = 1)]
+    cancellers: usize,
+    #[arg(long, default_value_t = 1000)]
+    mid_price: u64,
+    #[arg(long, default_value_t = 5)]
+    spread: u64,
+    #[arg(long, default_value_t = 10)]
+    max_quantity: u64,
+    /// Resting quotes each market maker keeps before cancelling the oldest
+    #[arg(long, default_value_t = 20)]
+    max_open_orders: usize,
+    /// Actions per second per trader, 0 runs every trader flat out
+    #[arg(long, default_value_t = 0.0)]
+    rate: f64,
+    /// Test duration in seconds
+    #[arg(long, default_value_t = 30)]
+    duration: u64,
+    /// Per request timeout in milliseconds
+    #[arg(long, default_value_t = 5000)]
+    timeout_ms: u64,
+}
+
+#[tokio::main]
+async fn main() {
+    dotenv::dotenv().ok();
+    let args = Args::parse();
+
+    let config = TraderConfig {
+        market: args.market.clone(),
+        mid_price: args.mid_price,
+        spread: args.spread.max(1),
+        max_quantity: args.max_quantity.max(1),
+        max_open_orders: args.max_open_orders,
+        actions_per_second: args.rate,
+        timeout: Duration::from_millis(args.timeout_ms),
+    };
+
+    if args.on_ramp > 0 {
+        if let Err(e) = on_ramp_users(&args).await {
+            eprintln!("Failed to on-ramp users: {}", e);
+            return;
+        }
+    }
+
+    let roles = std::iter::repeat_n(TraderRole::MarketMaker, args.market_makers)
+        .chain(std::iter::repeat_n(TraderRole::Taker, args.takers))
+        .chain(std::iter::repeat_n(TraderRole::Canceller, args.cancellers));
+
+    let mut traders = Vec::new();
+    for (index, role) in roles.enumerate() {
+        let client = match connect(&args).await {
+            Ok(client) => client,
+            Err(e) => {
+                eprintln!("Failed to connect trader {}: {}", index, e);
+                return;
+            }
+        };
+        let user_id = args.users[index % args.users.len()].clone();
+        traders.push(Trader::new(role, user_id, client, config.clone()));
+    }
+
+    println!(
+        "Running {} traders ({} makers, {} takers, {} cancellers) against {} for {}s",
+        traders.len(),
+        args.market_makers,
+        args.takers,
+        args.cancellers,
+        args.market,
+        args.duration
+    );
+
+    let started = Instant::now();
+    let deadline = started + Duration::from_secs(args.duration);
+    let handles: Vec<_> = traders
+        .into_iter()
+        .map(|trader| tokio::spawn(trader.run(deadline)))
+        .collect();
+
+    let mut stats = Stats::new();
+    for handle in handles {
+        match handle.await {
+            Ok(trader_stats) => stats.merge(trader_stats),
+            Err(e) => eprintln!("Trader task failed: {}", e),
+        }
+    }
+    stats.report(started.elapsed());
+}
+
+async fn connect(args: &Args) -> Result<ExchangeClient, client::ClientError> {
+    match args.target {
+        Target::Redis => Ok(ExchangeClient::Redis(Box::new(
+            RedisClient::connect(&args.redis_url).await?,
+        ))),
+        Target::Rest => Ok(ExchangeClient::Rest(RestClient::new(&args.api_url))),
+    }
+}
+
+// On-ramps always go straight to the engine since the API does not expose them.
+async fn on_ramp_users(args: &Args) -> Result<(), client::ClientError> {
+    let quote_asset = args
+        .market
+        .split_once('_')
+        .map_or(args.market.as_str(), |(_, quote)| quote);
+    let mut client = RedisClient::connect(&args.redis_url).await?;
+    for (index, user_id) in args.users.iter().enumerate() {
+        let message = MessageToEngine::OnRamp(OnRampRequest {
+            asset: quote_asset.to_string(),
+            amount: args.on_ramp.to_string(),
+            user_id: user_id.clone(),
+            txn_id: format!("loadgen-{}", index),
+        });
+        // The engine does not acknowledge on-ramps
+        client.push(message).await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,142 @@
+use hdrhistogram::Histogram;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use crate::types::{Operation, TraderRole};
+
+// Latencies are recorded in microseconds, up to one minute at 3 significant digits.
+const MAX_LATENCY_MICROS: u64 = 60_000_000;
+
+pub struct Stats {
+    latencies: BTreeMap<(TraderRole, Operation), Histogram<u64>>,
+    pub fills: u64,
+    pub errors: u64,
+    pub timeouts: u64,
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Self {
+            latencies: BTreeMap::new(),
+            fills: 0,
+            errors: 0,
+            timeouts: 0,
+        }
+    }
+
+    pub fn record(&mut self, role: TraderRole, operation: Operation, latency: Duration) {
+        let micros = (latency.as_micros() as u64).clamp(1, MAX_LATENCY_MICROS);
+        self.latencies
+            .entry((role, operation))
+            .or_insert_with(|| Histogram::new_with_bounds(1, MAX_LATENCY_MICROS, 3).unwrap())
+            .record(micros)
+            .expect("latency is clamped to the histogram bounds");
+    }
+
+    pub fn merge(&mut self, other: Stats) {
+        for (key, histogram) in other.latencies {
+            match self.latencies.get_mut(&key) {
+                Some(existing) => existing
+                    .add(&histogram)
+                    .expect("histograms share the same bounds"),
+                None => {
+                    self.latencies.insert(key, histogram);
+                }
+            }
+        }
+        self.fills += other.fills;
+        self.errors += other.errors;
+        self.timeouts += other.timeouts;
+    }
+
+    pub fn report(&self, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        let mut total = Histogram::<u64>::new_with_bounds(1, MAX_LATENCY_MICROS, 3).unwrap();
+        for histogram in self.latencies.values() {
+            total
+                .add(histogram)
+                .expect("histograms share the same bounds");
+        }
+
+        println!();
+        println!(
+            "Duration: {:.2}s  Requests: {}  Throughput: {:.1} req/s  Fills: {} ({:.1}/s)  Errors: {}  Timeouts: {}",
+            seconds,
+            total.len(),
+            total.len() as f64 / seconds,
+            self.fills,
+            self.fills as f64 / seconds,
+            self.errors,
+            self.timeouts
+        );
+        println!();
+        println!(
+            "{:<14} {:<14} {:>9} {:>10} {:>10} {:>10} {:>10} {:>10} {:>10}",
+            "role",
+            "operation",
+            "count",
+            "req/s",
+            "p50 µs",
+            "p90 µs",
+            "p99 µs",
+            "p99.9 µs",
+            "max µs"
+        );
+        for ((role, operation), histogram) in &self.latencies {
+            print_row(role.as_str(), operation.as_str(), histogram, seconds);
+        }
+        print_row("all", "all", &total, seconds);
+    }
+}
+
+fn print_row(role: &str, operation: &str, histogram: &Histogram<u64>, seconds: f64) {
+    println!(
+        "{:<14} {:<14} {:>9} {:>10.1} {:>10} {:>10} {:>10} {:>10} {:>10}",
+        role,
+        operation,
+        histogram.len(),
+        histogram.len() as f64 / seconds,
+        histogram.value_at_quantile(0.50),
+        histogram.value_at_quantile(0.90),
+        histogram.value_at_quantile(0.99),
+        histogram.value_at_quantile(0.999),
+        histogram.max()
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merge_combines_latencies_per_role_and_operation() {
+        let mut a = Stats::new();
+        a.record(
+            TraderRole::Taker,
+            Operation::PlaceOrder,
+            Duration::from_micros(100),
+        );
+        a.fills = 2;
+        let mut b = Stats::new();
+        b.record(
+            TraderRole::Taker,
+            Operation::PlaceOrder,
+            Duration::from_micros(300),
+        );
+        b.record(
+            TraderRole::Canceller,
+            Operation::CancelOrder,
+            Duration::ZERO,
+        );
+        b.timeouts = 1;
+
+        a.merge(b);
+        let taker = &a.latencies[&(TraderRole::Taker, Operation::PlaceOrder)];
+        assert_eq!(taker.len(), 2);
+        assert!(taker.equivalent(taker.max(), 300));
+        // Latencies below the histogram's range are counted as its lowest value
+        let canceller = &a.latencies[&(TraderRole::Canceller, Operation::CancelOrder)];
+        assert_eq!(canceller.min(), 1);
+        assert_eq!((a.fills, a.timeouts, a.errors), (2, 1, 0));
+    }
+}
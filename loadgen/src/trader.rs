@@ -0,0 +1,177 @@
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::VecDeque;
+use std::time::Duration;
+use tokio::time::{Instant, MissedTickBehavior};
+
+use crate::client::{ExchangeClient, RequestError};
+use crate::stats::Stats;
+use crate::types::{
+    CancelOrderRequest, MessageFromEngine, MessageToEngine, Operation, PlaceOrderRequest, Side,
+    TraderRole,
+};
+
+#[derive(Clone)]
+pub struct TraderConfig {
+    pub market: String,
+    pub mid_price: u64,
+    pub spread: u64,
+    pub max_quantity: u64,
+    pub max_open_orders: usize,
+    pub actions_per_second: f64,
+    pub timeout: Duration,
+}
+
+pub struct Trader {
+    role: TraderRole,
+    user_id: String,
+    client: ExchangeClient,
+    config: TraderConfig,
+    rng: StdRng,
+    open_orders: VecDeque<String>,
+    stats: Stats,
+}
+
+impl Trader {
+    pub fn new(
+        role: TraderRole,
+        user_id: String,
+        client: ExchangeClient,
+        config: TraderConfig,
+    ) -> Self {
+        Self {
+            role,
+            user_id,
+            client,
+            config,
+            rng: StdRng::from_entropy(),
+            open_orders: VecDeque::new(),
+            stats: Stats::new(),
+        }
+    }
+
+    pub async fn run(mut self, deadline: Instant) -> Stats {
+        let mut ticker = (self.config.actions_per_second > 0.0).then(|| {
+            let mut interval = tokio::time::interval(Duration::from_secs_f64(
+                1.0 / self.config.actions_per_second,
+            ));
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            interval
+        });
+
+        while Instant::now() < deadline {
+            if let Some(interval) = ticker.as_mut() {
+                interval.tick().await;
+            }
+            match self.role {
+                TraderRole::MarketMaker => self.quote().await,
+                TraderRole::Taker => self.take().await,
+                TraderRole::Canceller => self.place_and_cancel().await,
+            }
+        }
+
+        self.stats
+    }
+
+    // Rests one order on each side of the mid and retires the oldest quotes so the
+    // maker never holds more than `max_open_orders` at a time.
+    async fn quote(&mut self) {
+        let bid_offset = self.rng.gen_range(1..=self.config.spread);
+        let ask_offset = self.rng.gen_range(1..=self.config.spread);
+        let bid_price = self.config.mid_price.saturating_sub(bid_offset).max(1);
+        let ask_price = self.config.mid_price + ask_offset;
+
+        for (side, price) in [(Side::Buy, bid_price), (Side::Sell, ask_price)] {
+            if let Some(order_id) = self.place_order(side, price).await {
+                self.open_orders.push_back(order_id);
+            }
+        }
+        while self.open_orders.len() > self.config.max_open_orders {
+            let order_id = self.open_orders.pop_front().unwrap();
+            self.cancel_order(order_id).await;
+        }
+    }
+
+    // Crosses the spread so every order has a chance to match resting maker quotes.
+    async fn take(&mut self) {
+        let (side, price) = if self.rng.gen_bool(0.5) {
+            (Side::Buy, self.config.mid_price + self.config.spread)
+        } else {
+            (
+                Side::Sell,
+                self.config
+                    .mid_price
+                    .saturating_sub(self.config.spread)
+                    .max(1),
+            )
+        };
+        self.place_order(side, price).await;
+    }
+
+    // Rests an order well away from the mid and cancels it straight away.
+    async fn place_and_cancel(&mut self) {
+        let offset = self.config.spread + self.rng.gen_range(1..=self.config.spread);
+        let price = self.config.mid_price.saturating_sub(offset).max(1);
+        if let Some(order_id) = self.place_order(Side::Buy, price).await {
+            self.cancel_order(order_id).await;
+        }
+    }
+
+    // Returns the order id when part of the order is left resting on the book.
+    async fn place_order(&mut self, side: Side, price: u64) -> Option<String> {
+        let quantity = self.rng.gen_range(1..=self.config.max_quantity);
+        let message = MessageToEngine::CreateOrder(PlaceOrderRequest {
+            market: self.config.market.clone(),
+            price: price.to_string(),
+            quantity: quantity.to_string(),
+            side,
+            user_id: self.user_id.clone(),
+        });
+
+        match self.request(Operation::PlaceOrder, message).await? {
+            MessageFromEngine::OrderPlaced(placed) => {
+                self.stats.fills += placed.fills.len() as u64;
+                (placed.executed_qty < quantity).then_some(placed.order_id)
+            }
+            _ => {
+                self.stats.errors += 1;
+                None
+            }
+        }
+    }
+
+    async fn cancel_order(&mut self, order_id: String) {
+        let message = MessageToEngine::CancelOrder(CancelOrderRequest {
+            order_id,
+            market: self.config.market.clone(),
+        });
+        if let Some(response) = self.request(Operation::CancelOrder, message).await {
+            if !matches!(response, MessageFromEngine::OrderCancelled(_)) {
+                self.stats.errors += 1;
+            }
+        }
+    }
+
+    async fn request(
+        &mut self,
+        operation: Operation,
+        message: MessageToEngine,
+    ) -> Option<MessageFromEngine> {
+        let started = Instant::now();
+        match self.client.request(message, self.config.timeout).await {
+            Ok(response) => {
+                self.stats.record(self.role, operation, started.elapsed());
+                Some(response)
+            }
+            Err(RequestError::Timeout) => {
+                self.stats.timeouts += 1;
+                None
+            }
+            Err(RequestError::Failed(e)) => {
+                eprintln!("{} request failed: {}", operation.as_str(), e);
+                self.stats.errors += 1;
+                None
+            }
+        }
+    }
+}
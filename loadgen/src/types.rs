@@ -0,0 +1,37 @@
+pub use engine::types::{
+    CancelOrderPayload as CancelOrderRequest, CreateOrderPayload as PlaceOrderRequest,
+    MessageFromApi as MessageToEngine, MessageToApi as MessageFromEngine,
+    OnRampPayload as OnRampRequest, Side,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum TraderRole {
+    MarketMaker,
+    Taker,
+    Canceller,
+}
+
+impl TraderRole {
+    pub fn as_str(&self) -> &str {
+        match self {
+            TraderRole::MarketMaker => "market_maker",
+            TraderRole::Taker => "taker",
+            TraderRole::Canceller => "canceller",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Operation {
+    PlaceOrder,
+    CancelOrder,
+}
+
+impl Operation {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Operation::PlaceOrder => "place_order",
+            Operation::CancelOrder => "cancel_order",
+        }
+    }
+}
@@ -5,15 +5,37 @@ mod user_manager;
 
 use tokio::net::{TcpListener, TcpStream};
 use tokio_tungstenite::accept_async;
+use tracing::{error, info, warn};
 use user_manager::UserManager;
 
 #[tokio::main]
 async fn main() {
+    engine::telemetry::init_tracing();
+    engine::telemetry::serve_metrics("0.0.0.0:9102");
     let listener = TcpListener::bind("127.0.0.1:9001").await.unwrap();
-    println!("Ws Server Listening");
-    while let Ok((stream, _)) = listener.accept().await {
-        tokio::spawn(handle_connection(stream));
+    info!("Ws Server Listening");
+    let shutdown = engine::shutdown::signal();
+    tokio::pin!(shutdown);
+    loop {
+        tokio::select! {
+            _ = &mut shutdown => break,
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => {
+                    tokio::spawn(handle_connection(stream));
+                }
+                Err(e) => {
+                    error!("Error accepting TCP connection: {}", e);
+                    break;
+                }
+            },
+        }
     }
+
+    let user_manager = UserManager::get_instance().await;
+    let manager_guard = user_manager.lock().await;
+    info!("Closing {} connections", manager_guard.users.len());
+    manager_guard.close_all().await;
+    info!("Ws Server stopped");
 }
 
 async fn handle_connection(stream: TcpStream) {
@@ -24,7 +46,7 @@ async fn handle_connection(stream: TcpStream) {
             manager_guard.add_user(ws_stream).await;
         }
         Err(e) => {
-            println!("Error accepting connection: {}", e);
+            warn!("Error accepting connection: {}", e);
         }
     }
 }
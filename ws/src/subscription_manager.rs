@@ -1,4 +1,5 @@
 use futures_util::StreamExt;
+use metrics::{counter, gauge};
 use redis::{aio::PubSub, Client, RedisError};
 use std::collections::HashMap;
 use std::sync::Arc;
@@ -6,6 +7,8 @@ use tokio::sync::{Mutex, OnceCell};
 
 use crate::types::OutgoingMessage;
 use crate::user_manager::UserManager;
+use engine::types::Traced;
+use tracing::{debug, error, info, warn};
 
 static SUBSCRIPTION_MANAGER: OnceCell<Arc<Mutex<SubscriptionManager>>> = OnceCell::const_new();
 
@@ -48,7 +51,7 @@ impl SubscriptionManager {
                     let payload: String = match payload_result {
                         Ok(p) => p,
                         Err(e) => {
-                            eprintln!("Error getting payload from Redis message on channel '{}': {}. Skipping.", channel, e);
+                            warn!("Error getting payload from Redis message on channel '{}': {}. Skipping.", channel, e);
                             continue;
                         }
                     };
@@ -57,7 +60,7 @@ impl SubscriptionManager {
                     guard.redis_callback_handler(channel, payload).await;
                 }
                 None => {
-                    eprintln!("Redis PubSub stream ended. Attempting to re-establish stream in the next iteration.");
+                    error!("Redis PubSub stream ended. Attempting to re-establish stream in the next iteration.");
                 }
             }
         }
@@ -101,8 +104,10 @@ impl SubscriptionManager {
             .or_default()
             .push(user_id.to_string());
 
+        gauge!("ws_subscriptions").increment(1);
         if self.reverse_subscriptions.get(&subcsription).unwrap().len() == 1 {
             self.pubsub.subscribe(subcsription).await?;
+            gauge!("ws_redis_channels").set(self.reverse_subscriptions.len() as f64);
         }
         Ok(())
     }
@@ -112,13 +117,16 @@ impl SubscriptionManager {
         subcsription: String,
     ) -> Result<(), RedisError> {
         if let Some(subscriptions) = self.subscriptions.get_mut(&user_id) {
+            let before = subscriptions.len();
             subscriptions.retain(|s| s != &subcsription);
+            gauge!("ws_subscriptions").decrement((before - subscriptions.len()) as f64);
         }
 
         if let Some(subscribers) = self.reverse_subscriptions.get_mut(&subcsription) {
             subscribers.retain(|s| s != &user_id);
             if subscribers.is_empty() {
                 self.reverse_subscriptions.remove(&subcsription);
+                gauge!("ws_redis_channels").set(self.reverse_subscriptions.len() as f64);
                 self.pubsub.unsubscribe(subcsription).await?;
             }
         }
@@ -133,7 +141,7 @@ impl SubscriptionManager {
     }
 
     pub async fn user_left(&mut self, user_id: String) -> Result<(), RedisError> {
-        println!("User {} left", user_id);
+        info!("User {} left", user_id);
         let subscriptions = self.get_subscribers(&user_id);
         for subscription in subscriptions {
             self.unsubscribe(user_id.clone(), subscription).await?;
@@ -142,18 +150,27 @@ impl SubscriptionManager {
         Ok(())
     }
     async fn redis_callback_handler(&mut self, channel: String, msg: String) {
-        match serde_json::from_str::<OutgoingMessage>(&msg) {
-            Ok(parsed_msg) => {
+        match serde_json::from_str::<Traced<OutgoingMessage>>(&msg) {
+            Ok(traced) => {
+                // The correlation id is only for our logs, clients get the bare message
+                let parsed_msg = traced.message;
                 if let Some(subscribers) = self.reverse_subscriptions.get(&channel) {
+                    debug!(
+                        correlation_id = traced.correlation_id.as_deref(),
+                        subscribers = subscribers.len(),
+                        "Forwarding {}",
+                        channel
+                    );
                     for subscriber_id in subscribers {
                         let user_manager = UserManager::get_instance().await;
                         let manager_guard = user_manager.lock().await;
                         if let Some(user_arc) = manager_guard.get_user(subscriber_id).await {
                             let user_guard = user_arc.lock().await;
                             if let Err(e) = user_guard.emit(parsed_msg.clone()).await {
-                                eprintln!(
-                                    "Error emitting message to user {}: {}",
-                                    subscriber_id, e
+                                counter!("ws_emit_failures_total").increment(1);
+                                warn!(
+                                    correlation_id = traced.correlation_id.as_deref(),
+                                    "Error emitting message to user {}: {}", subscriber_id, e
                                 );
                             }
                         }
@@ -161,7 +178,8 @@ impl SubscriptionManager {
                 }
             }
             Err(e) => {
-                eprintln!(
+                counter!("ws_unparsable_messages_total").increment(1);
+                warn!(
                     "Error parsing OutgoingMessage from Redis on channel {}: {}. Message: {}",
                     channel, e, msg
                 );
@@ -1,6 +1,6 @@
 use serde::{Deserialize, Serialize};
 
-use engine::types::{WsMessage as EngineWsMessage, WsPayload as EngineWsPayload};
+use engine::types::WsMessage as EngineWsMessage;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Method {
@@ -17,5 +17,3 @@ pub struct IncomingMessage {
 }
 
 pub type OutgoingMessage = EngineWsMessage;
-
-pub type WsPayload = EngineWsPayload;
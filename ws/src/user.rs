@@ -5,16 +5,19 @@ use crate::types::{IncomingMessage, Method, OutgoingMessage};
 use crate::user_manager::UserManager;
 use futures_util::stream::{SplitSink, SplitStream};
 use futures_util::{SinkExt, StreamExt};
+use metrics::{counter, gauge};
 use tokio::net::TcpStream;
 use tokio::sync::Mutex;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
 use tokio_tungstenite::tungstenite::Message;
 use tokio_tungstenite::WebSocketStream;
+use tracing::{debug, info, warn};
 
 pub struct User {
     id: String,
     sender: Arc<Mutex<SplitSink<WebSocketStream<TcpStream>, Message>>>,
     stream: Option<SplitStream<WebSocketStream<TcpStream>>>,
-    subscriptions: Arc<Mutex<Vec<String>>>,
 }
 
 impl User {
@@ -27,28 +30,28 @@ impl User {
             id,
             sender,
             stream: Some(stream),
-            subscriptions: Arc::new(Mutex::new(Vec::new())),
         };
         user.add_listeners();
         user
     }
 
-    pub async fn subscribe(&self, subscription: String) {
-        let mut subs = self.subscriptions.lock().await;
-        subs.push(subscription);
-    }
-
-    pub async fn unsubscribe(&self, subscription: &str) {
-        let mut subs = self.subscriptions.lock().await;
-        subs.retain(|s| s != subscription);
-    }
-
     pub async fn emit(&self, message: OutgoingMessage) -> Result<(), Box<dyn std::error::Error>> {
         let json = serde_json::to_string(&message)?;
-        println!("Emitting message: {}", json);
         let mut ws = self.sender.lock().await;
         ws.send(Message::Text(json)).await?;
-        println!("Message sent to user {}", self.id);
+        counter!("ws_messages_sent_total").increment(1);
+        debug!("Message sent to user {}", self.id);
+        Ok(())
+    }
+
+    // Going away tells clients to reconnect rather than treat it as an error.
+    pub async fn close(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let mut ws = self.sender.lock().await;
+        ws.send(Message::Close(Some(CloseFrame {
+            code: CloseCode::Away,
+            reason: "Server shutting down".into(),
+        })))
+        .await?;
         Ok(())
     }
 
@@ -69,7 +72,7 @@ impl User {
                                         let res =
                                             sub_guard.subscribe(id.clone(), s.to_string()).await;
                                         if let Err(e) = res {
-                                            eprintln!("Error subscribing to {}: {}", s, e);
+                                            warn!("Error subscribing to {}: {}", s, e);
                                         }
                                     }
                                 }
@@ -82,7 +85,7 @@ impl User {
                                             )
                                             .await;
                                         if let Err(e) = res {
-                                            eprintln!("Error unsubscribing from {}: {}", s, e);
+                                            warn!("Error unsubscribing from {}: {}", s, e);
                                         }
                                     }
                                 }
@@ -91,23 +94,24 @@ impl User {
                     }
                     Ok(_) => {} // Ignore other message types
                     Err(e) => {
-                        eprintln!("Error processing message for user {}: {}", id, e);
+                        warn!("Error processing message for user {}: {}", id, e);
                         break;
                     }
                 }
             }
 
             // Connection closed
-            println!("Connection closed for user {}. Cleaning up.", id);
+            info!("Connection closed for user {}. Cleaning up.", id);
             let user_manager = UserManager::get_instance().await;
             let mut manager_guard = user_manager.lock().await;
             manager_guard.users.remove(&id);
-            println!("User {} removed from UserManager.", id);
+            gauge!("ws_active_connections").set(manager_guard.users.len() as f64);
+            debug!("User {} removed from UserManager.", id);
             let sub_manager = SubscriptionManager::get_instance().await;
             let mut sub_guard = sub_manager.lock().await;
             let res = sub_guard.user_left(id.clone()).await;
             if let Err(e) = res {
-                eprintln!("Error removing user from subscription manager: {}", e);
+                warn!("Error removing user from subscription manager: {}", e);
             }
         });
     }
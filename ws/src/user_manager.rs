@@ -1,8 +1,10 @@
 use futures_util::StreamExt;
+use metrics::gauge;
 use std::{collections::HashMap, sync::Arc};
 use tokio::net::TcpStream;
 use tokio::sync::{Mutex, OnceCell};
 use tokio_tungstenite::WebSocketStream;
+use tracing::warn;
 
 use crate::user::User;
 
@@ -31,6 +33,16 @@ impl UserManager {
         let user = User::new(id.clone(), user_sender.clone(), stream);
 
         self.users.insert(id, Arc::new(Mutex::new(user)));
+        gauge!("ws_active_connections").set(self.users.len() as f64);
+    }
+
+    pub async fn close_all(&self) {
+        for (id, user) in &self.users {
+            let user_guard = user.lock().await;
+            if let Err(e) = user_guard.close().await {
+                warn!("Error closing connection of user {}: {}", id, e);
+            }
+        }
     }
 
     pub async fn get_user(&self, id: &str) -> Option<Arc<Mutex<User>>> {